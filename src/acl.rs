@@ -0,0 +1,344 @@
+use crate::error::FsError;
+use crate::inode::{Inode, MODE_SETGID};
+use km_command::fs::FileMode;
+
+/// Extended attribute name of the access ACL.
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+
+/// Extended attribute name of the default ACL.
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+/// Version of the ACL extended attribute format.
+const ACL_XATTR_VERSION: u32 = 2;
+
+/// Id of ACL entries which are not `ACL_USER` or `ACL_GROUP`.
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// Execute or search permission.
+pub const MAY_EXEC: u16 = 0o1;
+
+/// Write permission.
+pub const MAY_WRITE: u16 = 0o2;
+
+/// Read permission.
+pub const MAY_READ: u16 = 0o4;
+
+/// ACL entry tag. The order of variants is the order of entries in a valid ACL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AclTag {
+    /// `ACL_USER_OBJ`, the owner.
+    UserObj,
+    /// `ACL_USER`, a named user.
+    User(u32),
+    /// `ACL_GROUP_OBJ`, the owning group.
+    GroupObj,
+    /// `ACL_GROUP`, a named group.
+    Group(u32),
+    /// `ACL_MASK`, the upper bound of group class permissions.
+    Mask,
+    /// `ACL_OTHER`, everyone else.
+    Other,
+}
+
+impl AclTag {
+    /// Encode the tag as (e_tag, e_id) of the extended attribute format.
+    fn encode(&self) -> (u16, u32) {
+        match *self {
+            Self::UserObj => (0x01, ACL_UNDEFINED_ID),
+            Self::User(uid) => (0x02, uid),
+            Self::GroupObj => (0x04, ACL_UNDEFINED_ID),
+            Self::Group(gid) => (0x08, gid),
+            Self::Mask => (0x10, ACL_UNDEFINED_ID),
+            Self::Other => (0x20, ACL_UNDEFINED_ID),
+        }
+    }
+
+    /// Decode the tag from (e_tag, e_id) of the extended attribute format.
+    fn decode(tag: u16, id: u32) -> Result<Self, FsError> {
+        match tag {
+            0x01 => Ok(Self::UserObj),
+            0x02 => Ok(Self::User(id)),
+            0x04 => Ok(Self::GroupObj),
+            0x08 => Ok(Self::Group(id)),
+            0x10 => Ok(Self::Mask),
+            0x20 => Ok(Self::Other),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+/// ACL entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    /// Entry tag.
+    pub tag: AclTag,
+    /// Permission bits, `MAY_READ | MAY_WRITE | MAY_EXEC`.
+    pub perm: u16,
+}
+
+/// POSIX access control list, entries sorted by tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl(Vec<AclEntry>);
+
+impl Acl {
+    /// Create an ACL, checking it is valid.
+    ///
+    /// Entries must be sorted by tag without duplicates, `ACL_USER_OBJ`,
+    /// `ACL_GROUP_OBJ` and `ACL_OTHER` are required, and `ACL_MASK` is required
+    /// if there are named entries.
+    pub fn new(entries: Vec<AclEntry>) -> Result<Self, FsError> {
+        if entries.windows(2).any(|w| w[0].tag >= w[1].tag) {
+            return Err(FsError::InvalidArgument);
+        }
+        if entries.iter().any(|e| e.perm & !0o7 != 0) {
+            return Err(FsError::InvalidArgument);
+        }
+        let has = |f: fn(&AclTag) -> bool| entries.iter().any(|e| f(&e.tag));
+        if !has(|t| *t == AclTag::UserObj)
+            || !has(|t| *t == AclTag::GroupObj)
+            || !has(|t| *t == AclTag::Other)
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let named = has(|t| matches!(t, AclTag::User(_) | AclTag::Group(_)));
+        if named && !has(|t| *t == AclTag::Mask) {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(Self(entries))
+    }
+
+    /// Parse an ACL from its extended attribute value. An empty ACL is `None`.
+    pub fn from_xattr(value: &[u8]) -> Result<Option<Self>, FsError> {
+        if value.is_empty() {
+            return Ok(None);
+        }
+        if value.len() < 4 || (value.len() - 4) % 8 != 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        if u32::from_le_bytes(value[0..4].try_into().unwrap()) != ACL_XATTR_VERSION {
+            return Err(FsError::NotSupported);
+        }
+        let entries = value[4..]
+            .chunks(8)
+            .map(|e| {
+                let tag = u16::from_le_bytes(e[0..2].try_into().unwrap());
+                let perm = u16::from_le_bytes(e[2..4].try_into().unwrap());
+                let id = u32::from_le_bytes(e[4..8].try_into().unwrap());
+                Ok(AclEntry {
+                    tag: AclTag::decode(tag, id)?,
+                    perm,
+                })
+            })
+            .collect::<Result<Vec<_>, FsError>>()?;
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        Self::new(entries).map(Some)
+    }
+
+    /// Encode the ACL as an extended attribute value.
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for entry in &self.0 {
+            let (tag, id) = entry.tag.encode();
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&entry.perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    /// Check if the ACL is equivalent to permission bits, i.e. has only the 3 base entries.
+    pub fn is_minimal(&self) -> bool {
+        self.0.len() == 3
+    }
+
+    /// Get the permission of entry `tag`.
+    fn perm(&self, tag: AclTag) -> Option<u16> {
+        self.0.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+
+    /// Get the mutable permission of entry `tag`.
+    fn perm_mut(&mut self, tag: AclTag) -> Option<&mut u16> {
+        self.0
+            .iter_mut()
+            .find(|e| e.tag == tag)
+            .map(|e| &mut e.perm)
+    }
+
+    /// The ACL check algorithm, check if `uid` and `gid` are granted `want` on `inode`.
+    pub fn permits(&self, inode: &Inode, uid: u32, gid: u32, want: u16) -> bool {
+        let mask = self.perm(AclTag::Mask).unwrap_or(0o7);
+        let mut group_found = false;
+        for entry in &self.0 {
+            match entry.tag {
+                AclTag::UserObj if uid == inode.uid => return entry.perm & want == want,
+                AclTag::User(id) if id == uid => return entry.perm & mask & want == want,
+                AclTag::GroupObj if gid == inode.gid => {
+                    group_found = true;
+                    if entry.perm & mask & want == want {
+                        return true;
+                    }
+                }
+                AclTag::Group(id) if id == gid => {
+                    group_found = true;
+                    if entry.perm & mask & want == want {
+                        return true;
+                    }
+                }
+                AclTag::Other => return !group_found && entry.perm & want == want,
+                _ => (),
+            }
+        }
+        false
+    }
+
+    /// Update the ACL after `chmod`. The group class bits go to `ACL_MASK` if
+    /// present, otherwise to `ACL_GROUP_OBJ`.
+    pub fn chmod(&mut self, mode: FileMode) {
+        let bits = mode.bits() as u32;
+        *self.perm_mut(AclTag::UserObj).unwrap() = (bits >> 6 & 0o7) as u16;
+        let group_tag = if self.perm(AclTag::Mask).is_some() {
+            AclTag::Mask
+        } else {
+            AclTag::GroupObj
+        };
+        *self.perm_mut(group_tag).unwrap() = (bits >> 3 & 0o7) as u16;
+        *self.perm_mut(AclTag::Other).unwrap() = (bits & 0o7) as u16;
+    }
+
+    /// Get `mode` with its permission bits replaced by the ACL.
+    pub fn apply_to_mode(&self, mode: FileMode) -> FileMode {
+        let group = self
+            .perm(AclTag::Mask)
+            .or(self.perm(AclTag::GroupObj))
+            .unwrap();
+        let perm = (self.perm(AclTag::UserObj).unwrap() as u32) << 6
+            | (group as u32) << 3
+            | self.perm(AclTag::Other).unwrap() as u32;
+        FileMode::from_bits_retain(((mode.bits() as u32) & !0o777 | perm) as _)
+    }
+
+    /// Mask the ACL inherited by a new inode with the requested `mode`, and mask
+    /// `mode` with the ACL.
+    fn create_masq(&mut self, mode: FileMode) -> FileMode {
+        let bits = mode.bits() as u32;
+        let group_tag = if self.perm(AclTag::Mask).is_some() {
+            AclTag::Mask
+        } else {
+            AclTag::GroupObj
+        };
+        for (tag, shift) in [(AclTag::UserObj, 6), (group_tag, 3), (AclTag::Other, 0)] {
+            *self.perm_mut(tag).unwrap() &= (bits >> shift & 0o7) as u16;
+        }
+        self.apply_to_mode(mode)
+    }
+}
+
+impl Inode {
+    /// Get the access ACL.
+    pub fn access_acl(&self) -> Option<Acl> {
+        let value = self.xattrs.get(ACL_ACCESS)?;
+        Acl::from_xattr(value).ok().flatten()
+    }
+
+    /// Get the default ACL, which is inherited by new entries of a directory.
+    pub fn default_acl(&self) -> Option<Acl> {
+        let value = self.xattrs.get(ACL_DEFAULT)?;
+        Acl::from_xattr(value).ok().flatten()
+    }
+
+    /// Check if `uid` and `gid` are granted `want` on the inode.
+    ///
+    /// The access ACL is used if present, otherwise the permission bits. Root may
+    /// read and write anything, and execute if any execute bit is set.
+    pub fn permission(&self, uid: u32, gid: u32, want: u16) -> Result<(), FsError> {
+        let bits = self.mode.bits() as u32;
+        let granted = if uid == 0 {
+            want & MAY_EXEC == 0 || self.is_dir() || bits & 0o111 != 0
+        } else if let Some(acl) = self.access_acl() {
+            acl.permits(self, uid, gid, want)
+        } else {
+            let shift = if uid == self.uid {
+                6
+            } else if gid == self.gid {
+                3
+            } else {
+                0
+            };
+            (bits >> shift) as u16 & want == want
+        };
+        if granted {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied)
+        }
+    }
+
+    /// Change the file mode, keeping the access ACL in sync.
+    pub fn chmod(&mut self, mode: FileMode) {
+        self.mode = FileMode::from_bits_retain(((mode.bits() as u32) & 0o7777) as _);
+        if let Some(mut acl) = self.access_acl() {
+            acl.chmod(self.mode);
+            self.xattrs.insert(ACL_ACCESS.to_owned(), acl.to_xattr());
+        }
+    }
+
+    /// Inherit the `default` ACL of the parent directory. The inode mode is masked
+    /// by the ACL instead of the umask.
+    pub fn inherit_acl(&mut self, default: &Acl) {
+        if self.is_dir() {
+            self.xattrs
+                .insert(ACL_DEFAULT.to_owned(), default.to_xattr());
+        }
+        let mut acl = default.clone();
+        self.mode = acl.create_masq(self.mode);
+        if !acl.is_minimal() {
+            self.xattrs.insert(ACL_ACCESS.to_owned(), acl.to_xattr());
+        }
+    }
+
+    /// Set ACL extended attribute `name`, only the owner or root may do it.
+    ///
+    /// Setting the access ACL updates the permission bits, and an ACL equivalent
+    /// to the permission bits is not stored. An empty value removes the ACL.
+    /// Like Linux, `XATTR_CREATE` and `XATTR_REPLACE` are ignored for ACLs.
+    pub(crate) fn set_acl(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        name: &str,
+        value: &[u8],
+    ) -> Result<(), FsError> {
+        if uid != 0 && uid != self.uid {
+            return Err(FsError::NotPermitted);
+        }
+        let acl = Acl::from_xattr(value)?;
+        if name == ACL_DEFAULT && !self.is_dir() {
+            return match acl {
+                Some(_) => Err(FsError::PermissionDenied),
+                None => Ok(()),
+            };
+        }
+        match acl {
+            Some(acl) if name == ACL_ACCESS => {
+                self.mode = acl.apply_to_mode(self.mode);
+                if uid != 0 && gid != self.gid {
+                    self.mode.remove(MODE_SETGID);
+                }
+                if acl.is_minimal() {
+                    self.xattrs.remove(name);
+                } else {
+                    self.xattrs.insert(name.to_owned(), acl.to_xattr());
+                }
+            }
+            Some(acl) => {
+                self.xattrs.insert(name.to_owned(), acl.to_xattr());
+            }
+            None => {
+                self.xattrs.remove(name);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{Checkpoint, FileSystem, SyncedPort};
+use km_checker::{CheckLevel, Checker, Command, Commander, Error, StdoutPrinter, TestPort};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Builder of a recorded command, giving a new copy of it each time.
+pub type CommandBuilder = Rc<dyn Fn() -> Box<dyn Command<FileSystem>>>;
+
+/// Commands generated since the last checkpoint, in order.
+///
+/// The trace is shared between the commander recording it and the code replaying
+/// it, clones refer to the same trace.
+#[derive(Clone, Default)]
+pub struct Trace(Rc<RefCell<Vec<CommandBuilder>>>);
+
+impl Trace {
+    /// Create an empty trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of recorded commands.
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    /// Check if no command is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    /// Forget the recorded commands, e.g. when a checkpoint is taken.
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    /// Record a command.
+    pub(crate) fn push(&self, builder: CommandBuilder) {
+        self.0.borrow_mut().push(builder);
+    }
+}
+
+/// Commander giving the commands of a trace again.
+struct Replayer {
+    commands: Vec<CommandBuilder>,
+    next: usize,
+}
+
+impl Commander<FileSystem> for Replayer {
+    fn command(&mut self, _state: &FileSystem) -> Result<Box<dyn Command<FileSystem>>, Error> {
+        let builder = self.commands.get(self.next).ok_or(Error::Io)?;
+        self.next += 1;
+        Ok(builder())
+    }
+}
+
+/// First step where the model and the target diverged.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Step number, counted from the start of the run as in `Checkpoint::steps`.
+    pub step: u64,
+    /// Failure of the check after the step.
+    pub error: Error,
+}
+
+/// Find the first divergent step after a mismatch, when the full state is only
+/// checked every few steps.
+///
+/// The commands recorded in `trace` since `checkpoint` are replayed on `port`,
+/// whose target state was checked to match the checkpoint by `Checkpoint::sync`,
+/// with strict return value and state checks after every step. The model starts
+/// from a copy of the checkpoint state. The replayed commands are printed to
+/// stdout.
+/// Returns `None` if the replay does not diverge, e.g. when the mismatch depends
+/// on more than the commands.
+pub fn bisect<P: TestPort<FileSystem>>(
+    checkpoint: &Checkpoint,
+    trace: &Trace,
+    port: SyncedPort<P>,
+) -> Result<Option<Divergence>, Error> {
+    assert_eq!(
+        port.steps, checkpoint.steps,
+        "port synchronized with another checkpoint"
+    );
+    let port = port.port;
+    let commands = trace.0.borrow().clone();
+    let len = commands.len();
+    let replayer = Replayer { commands, next: 0 };
+    let mut checker = Checker::new(replayer, port, StdoutPrinter, checkpoint.model.clone());
+    for i in 0..len {
+        if let Err(error) = checker.step(CheckLevel::Strict, CheckLevel::Strict) {
+            return Ok(Some(Divergence {
+                step: checkpoint.steps + i as u64 + 1,
+                error,
+            }));
+        }
+    }
+    Ok(None)
+}
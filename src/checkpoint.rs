@@ -0,0 +1,89 @@
+use crate::{FileSystem, FsCommander};
+use km_checker::{AbstractState, Error, StateChannel};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Checkpoint of a checking campaign, to resume it after the host restarts.
+///
+/// A resumed run continues from the model state at the same position of the
+/// seeded command stream, so it generates the commands the original run would
+/// have. The target is re-synchronized by checking that its retrieved state matches
+/// the model state of the checkpoint, see `verify`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Seed of the command generator.
+    pub seed: u64,
+    /// Number of steps done.
+    pub steps: u64,
+    /// Position in the command stream, the number of commands generated. It may be
+    /// less than `steps`, as no command is generated while the state is unknown
+    /// after a crash.
+    pub generated: u64,
+    /// Model state after the steps.
+    pub model: FileSystem,
+}
+
+impl Checkpoint {
+    /// Take a checkpoint after `steps` steps of a run with `seed`, which generated
+    /// `generated` commands, with model state `model`.
+    pub fn new(seed: u64, steps: u64, generated: u64, model: &FileSystem) -> Self {
+        Self {
+            seed,
+            steps,
+            generated,
+            model: model.clone(),
+        }
+    }
+
+    /// Save the checkpoint at `path`. The previous checkpoint there is replaced
+    /// only once the new one is fully written.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Load the checkpoint saved at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Get a commander continuing the run.
+    pub fn commander(&self) -> FsCommander {
+        FsCommander::resume(self.seed, self.generated)
+    }
+
+    /// Retrieve the state of the target through `port` and check that it matches
+    /// the model state of the checkpoint.
+    pub fn verify<P: StateChannel<FileSystem>>(&self, port: &mut P) -> Result<bool, Error> {
+        port.start_state_retrieval()?;
+        while !port.retrieve_state_data()? {}
+        let target = port.finish_state_retrieval()?;
+        Ok(self.model.matches(&target))
+    }
+
+    /// Verify `port` as `verify` does, and give it back as synchronized with the
+    /// checkpoint if the target state matches.
+    pub fn sync<P: StateChannel<FileSystem>>(
+        &self,
+        mut port: P,
+    ) -> Result<Option<SyncedPort<P>>, Error> {
+        if !self.verify(&mut port)? {
+            return Ok(None);
+        }
+        Ok(Some(SyncedPort {
+            port,
+            steps: self.steps,
+        }))
+    }
+}
+
+/// Port whose target state matched a checkpoint, see `Checkpoint::sync`.
+pub struct SyncedPort<P> {
+    /// The port.
+    pub(crate) port: P,
+    /// Steps of the checkpoint.
+    pub(crate) steps: u64,
+}
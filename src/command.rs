@@ -1,16 +1,24 @@
-use crate::fs::{FileDescriptor, FileSystem};
+use crate::fs::{FdRefType, FileDescriptor, FileSystem, FDCWD};
 use km_checker::model_command;
-use km_command::fs::{FileKind, OpenFlags, UnlinkatFlags};
+use km_command::fs::{AtFlags, FileKind, OpenFlags, UnlinkatFlags};
 use std::cell::RefCell;
 use std::rc::Rc;
 
 model_command!(km_command::fs, Chdir, FileSystem, {
-    (|| state!().chdir(get!(path).clone().try_into()?))().map_or_else(|e| e.into(), |_| 0)
+    (|| {
+        let path = state!().model_path(get!(path).clone().try_into()?)?;
+        state!().chdir(path)
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
 });
 
 model_command!(km_command::fs, Openat, FileSystem, {
     (|| {
         let path = state!().parse_path(get!(dirfd), get!(path).clone())?;
+        // Create an unnamed file in directory `path`
+        if get!(flags).contains(OpenFlags::TMPFILE) {
+            return state!().create_tmpfile(path, get!(flags), get!(mode));
+        }
         // Check file exists
         if let Err(e) = state!().lookup(&path) {
             if !get!(flags).contains(OpenFlags::CREAT) {
@@ -19,9 +27,13 @@ model_command!(km_command::fs, Openat, FileSystem, {
                 // Create file
                 state!().create(path.clone(), FileKind::File, get!(mode))?;
             }
+        } else {
+            state!().open(&path, get!(flags))?;
         }
         // Find available file descriptor
+        let id = state!().alloc_ofd_id();
         state!().alloc_fd(Rc::new(RefCell::new(FileDescriptor::new_perm(
+            id,
             path,
             get!(flags),
         ))))
@@ -41,13 +53,34 @@ model_command!(km_command::fs, Mkdirat, FileSystem, {
     .map_or_else(|e| e.into(), |_| 0)
 });
 
+model_command!(km_command::fs, Mknodat, FileSystem, {
+    (|| {
+        let path = state!().parse_path(get!(dirfd), get!(path).clone())?;
+        state!().mknod(path, get!(kind), get!(mode), get!(dev))
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Fchmodat, FileSystem, {
+    (|| {
+        let fref = state!().resolve(get!(dirfd), get!(path).clone(), AtFlags::empty())?;
+        state!().chmod(&fref, get!(mode))
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
 model_command!(km_command::fs, Linkat, FileSystem, {
     (|| {
-        // Parse paths
-        let old_path = state!().parse_path(get!(olddirfd), get!(oldpath).clone())?;
+        // Parse paths. With `AT_EMPTY_PATH` and an empty old path, the inode
+        // `olddirfd` refers to is linked.
+        let old_ref = if get!(oldpath).0.is_empty() {
+            state!().resolve(get!(olddirfd), get!(oldpath).clone(), get!(flags))?
+        } else {
+            FdRefType::Permanent(state!().parse_path(get!(olddirfd), get!(oldpath).clone())?)
+        };
         let new_path = state!().parse_path(get!(newdirfd), get!(newpath).clone())?;
         // Link file
-        state!().link(&old_path, new_path)
+        state!().link_fref(&old_ref, new_path)
     })()
     .map_or_else(|e| e.into(), |_| 0)
 });
@@ -72,6 +105,251 @@ model_command!(km_command::fs, Dup, FileSystem, {
     .map_or_else(|e| e.into(), |fd| fd)
 });
 
+model_command!(km_command::fs, Umask, FileSystem, {
+    state!().umask(get!(mask)).bits() as isize
+});
+
+model_command!(km_command::fs, Setxattrat, FileSystem, {
+    (|| {
+        let (uid, gid) = (state!().uid(), state!().gid());
+        let fref = state!().resolve(get!(dirfd), get!(path).clone(), get!(at_flags))?;
+        state!().inode_mut(&fref)?.set_xattr(
+            uid,
+            gid,
+            get!(name).as_str(),
+            &get!(value),
+            get!(flags),
+        )
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Fsetxattr, FileSystem, {
+    (|| {
+        let (uid, gid) = (state!().uid(), state!().gid());
+        let fref = state!().fd_ref(get!(fd))?;
+        state!().inode_mut(&fref)?.set_xattr(
+            uid,
+            gid,
+            get!(name).as_str(),
+            &get!(value),
+            get!(flags),
+        )
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Getxattrat, FileSystem, {
+    (|| {
+        let (uid, gid) = (state!().uid(), state!().gid());
+        let fref = state!().resolve(get!(dirfd), get!(path).clone(), get!(at_flags))?;
+        state!()
+            .inode(&fref)?
+            .get_xattr(uid, gid, get!(name).as_str(), get!(size))
+    })()
+    .map_or_else(|e| e.into(), |len| len as isize)
+});
+
+model_command!(km_command::fs, Fgetxattr, FileSystem, {
+    (|| {
+        let (uid, gid) = (state!().uid(), state!().gid());
+        let fref = state!().fd_ref(get!(fd))?;
+        state!()
+            .inode(&fref)?
+            .get_xattr(uid, gid, get!(name).as_str(), get!(size))
+    })()
+    .map_or_else(|e| e.into(), |len| len as isize)
+});
+
+model_command!(km_command::fs, Listxattrat, FileSystem, {
+    (|| {
+        let uid = state!().uid();
+        let fref = state!().resolve(get!(dirfd), get!(path).clone(), get!(at_flags))?;
+        state!().inode(&fref)?.list_xattr(uid, get!(size))
+    })()
+    .map_or_else(|e| e.into(), |len| len as isize)
+});
+
+model_command!(km_command::fs, Flistxattr, FileSystem, {
+    (|| {
+        let uid = state!().uid();
+        let fref = state!().fd_ref(get!(fd))?;
+        state!().inode(&fref)?.list_xattr(uid, get!(size))
+    })()
+    .map_or_else(|e| e.into(), |len| len as isize)
+});
+
+model_command!(km_command::fs, Removexattrat, FileSystem, {
+    (|| {
+        let (uid, gid) = (state!().uid(), state!().gid());
+        let fref = state!().resolve(get!(dirfd), get!(path).clone(), get!(at_flags))?;
+        state!()
+            .inode_mut(&fref)?
+            .remove_xattr(uid, gid, get!(name).as_str())
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Fremovexattr, FileSystem, {
+    (|| {
+        let (uid, gid) = (state!().uid(), state!().gid());
+        let fref = state!().fd_ref(get!(fd))?;
+        state!()
+            .inode_mut(&fref)?
+            .remove_xattr(uid, gid, get!(name).as_str())
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Mount, FileSystem, {
+    (|| {
+        let path = state!().parse_path(FDCWD, get!(path).clone())?;
+        state!().mount(path)
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Flock, FileSystem, {
+    (|| state!().flock(get!(fd), get!(op)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Setlk, FileSystem, {
+    (|| state!().setlk(get!(fd), get!(kind), get!(start), get!(len)))()
+        .map_or_else(|e| e.into(), |_| 0)
+});
+
+// `F_GETLK` reports the conflicting lock through its argument, only the return
+// value is checked. With a single process there is never a conflict to report.
+model_command!(km_command::fs, Getlk, FileSystem, {
+    (|| state!().getlk(get!(fd), get!(kind), get!(start), get!(len)))()
+        .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Pipe2, FileSystem, {
+    (|| state!().pipe(get!(flags)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Read, FileSystem, {
+    (|| state!().read(get!(fd), get!(count)))().map_or_else(|e| e.into(), |n| n as isize)
+});
+
+model_command!(km_command::fs, Write, FileSystem, {
+    (|| state!().write(get!(fd), &get!(buf)))().map_or_else(|e| e.into(), |n| n as isize)
+});
+
+model_command!(km_command::fs, CopyFileRange, FileSystem, {
+    (|| {
+        state!().copy_file_range(
+            get!(fd_in),
+            get!(off_in),
+            get!(fd_out),
+            get!(off_out),
+            get!(len),
+            get!(flags),
+        )
+    })()
+    .map_or_else(|e| e.into(), |n| n as isize)
+});
+
+model_command!(km_command::fs, Sendfile, FileSystem, {
+    (|| state!().sendfile(get!(out_fd), get!(in_fd), get!(offset), get!(count)))()
+        .map_or_else(|e| e.into(), |n| n as isize)
+});
+
+// Splice flags are hints, the model never blocks anyway.
+model_command!(km_command::fs, Splice, FileSystem, {
+    (|| {
+        state!().splice(
+            get!(fd_in),
+            get!(off_in),
+            get!(fd_out),
+            get!(off_out),
+            get!(len),
+        )
+    })()
+    .map_or_else(|e| e.into(), |n| n as isize)
+});
+
+model_command!(km_command::fs, Getdents, FileSystem, {
+    (|| state!().getdents(get!(fd), get!(count)))().map_or_else(|e| e.into(), |n| n as isize)
+});
+
+model_command!(km_command::fs, Truncate, FileSystem, {
+    (|| {
+        let path = state!().parse_path(FDCWD, get!(path).clone())?;
+        state!().truncate(&path, get!(length))
+    })()
+    .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Ftruncate, FileSystem, {
+    (|| state!().ftruncate(get!(fd), get!(length)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Fallocate, FileSystem, {
+    (|| state!().fallocate(get!(fd), get!(mode), get!(offset), get!(len)))()
+        .map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Lseek, FileSystem, {
+    (|| state!().lseek(get!(fd), get!(offset), get!(whence)))()
+        .map_or_else(|e| e.into(), |offset| offset as isize)
+});
+
+model_command!(km_command::fs, Mmap, FileSystem, {
+    (|| {
+        state!().mmap(
+            get!(addr),
+            get!(len),
+            get!(prot),
+            get!(flags),
+            get!(fd),
+            get!(offset),
+        )
+    })()
+    .map_or_else(|e| e.into(), |addr| addr as isize)
+});
+
+model_command!(km_command::fs, Munmap, FileSystem, {
+    (|| state!().munmap(get!(addr), get!(len)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Msync, FileSystem, {
+    (|| state!().msync(get!(addr), get!(len), get!(flags)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+// Memory accesses are run by the target, which catches `SIGSEGV` and `SIGBUS`
+// and reports them as errors.
+model_command!(km_command::fs, MemRead, FileSystem, {
+    (|| state!().mem_read(get!(addr)))().map_or_else(|e| e.into(), |value| value as isize)
+});
+
+model_command!(km_command::fs, MemWrite, FileSystem, {
+    (|| state!().mem_write(get!(addr), get!(value)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Fsync, FileSystem, {
+    (|| state!().fsync(get!(fd)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Fdatasync, FileSystem, {
+    (|| state!().fsync(get!(fd)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Syncfs, FileSystem, {
+    (|| state!().syncfs(get!(fd)))().map_or_else(|e| e.into(), |_| 0)
+});
+
+model_command!(km_command::fs, Sync, FileSystem, {
+    state!().sync();
+    0
+});
+
+model_command!(km_command::fs, Reboot, FileSystem, {
+    state!().crash();
+    0
+});
+
 // Constant FS commands.
 //
 // These commands don't change the state of the file system. They
@@ -80,8 +358,10 @@ model_command!(km_command::fs, Dup, FileSystem, {
 
 model_command!(km_command::fs, Fstat, FileSystem, { 0 });
 
-model_command!(km_command::fs, Getdents, FileSystem, { 0 });
+model_command!(km_command::fs, Fstatat, FileSystem, { 0 });
 
 model_command!(km_command::fs, Getcwd, FileSystem, { 0 });
 
+model_command!(km_command::fs, Statfs, FileSystem, { 0 });
+
 model_command!(km_command, Nop, FileSystem, { 0 });
@@ -1,65 +1,322 @@
+use crate::acl::{Acl, AclEntry, AclTag, ACL_ACCESS, ACL_DEFAULT};
+use crate::bisect::Trace;
 use crate::command::{
-    Chdir as ModelChdir, Close as ModelClose, Dup as ModelDup, Linkat as ModelLinkat,
-    Mkdirat as ModelMkdirat, Openat as ModelOpenat, Unlinkat as ModelUnlinkat,
+    Chdir as ModelChdir, Close as ModelClose, CopyFileRange as ModelCopyFileRange, Dup as ModelDup,
+    Fallocate as ModelFallocate, Fchmodat as ModelFchmodat, Fdatasync as ModelFdatasync,
+    Fgetxattr as ModelFgetxattr, Flistxattr as ModelFlistxattr, Flock as ModelFlock,
+    Fremovexattr as ModelFremovexattr, Fsetxattr as ModelFsetxattr, Fsync as ModelFsync,
+    Ftruncate as ModelFtruncate, Getdents as ModelGetdents, Getlk as ModelGetlk,
+    Getxattrat as ModelGetxattrat, Linkat as ModelLinkat, Listxattrat as ModelListxattrat,
+    Lseek as ModelLseek, MemRead as ModelMemRead, MemWrite as ModelMemWrite,
+    Mkdirat as ModelMkdirat, Mknodat as ModelMknodat, Mmap as ModelMmap, Mount as ModelMount,
+    Msync as ModelMsync, Munmap as ModelMunmap, Nop, Openat as ModelOpenat, Pipe2 as ModelPipe2,
+    Read as ModelRead, Reboot as ModelReboot, Removexattrat as ModelRemovexattrat,
+    Sendfile as ModelSendfile, Setlk as ModelSetlk, Setxattrat as ModelSetxattrat,
+    Splice as ModelSplice, Sync as ModelSync, Syncfs as ModelSyncfs, Truncate as ModelTruncate,
+    Umask as ModelUmask, Unlinkat as ModelUnlinkat, Write as ModelWrite,
 };
-use crate::fs::{FileSystem, FDCWD};
+use crate::fs::{FileSystem, BLOCK_SIZE, FDCWD};
+use crate::generator::{
+    Constant, DefaultOr, Generator, RandomFlags, Rng, SwitchConstant, UniformCollection,
+};
+use crate::inode::{MODE_SETGID, MODE_STICKY};
+use crate::mmap::PAGE_SIZE;
+use crate::pipe::PIPE_CAPACITY;
+use crate::xattr::XATTR_SIZE_MAX;
 use km_checker::{Command, Commander, Error};
 use km_command::fs::{
-    Chdir, Close, Dup, FileMode, Linkat, Mkdirat, OpenFlags, Openat, Path, Unlinkat,
+    Chdir, Close, CopyFileRange, Dup, FallocFlags, Fallocate, Fchmodat, Fdatasync, Fgetxattr,
+    FileKind, FileMode, Flistxattr, Flock, FlockFlags, Fremovexattr, Fsetxattr, Fsync, Ftruncate,
+    Getdents, Getlk, Getxattrat, Linkat, Listxattrat, LockKind, Lseek, MapFlags, MemRead, MemWrite,
+    Mkdirat, Mknodat, Mmap, Mount, Msync, MsyncFlags, Munmap, OpenFlags, Openat, Path, Pipe2,
+    ProtFlags, Read, Reboot, Removexattrat, Sendfile, Setlk, Setxattrat, Splice, SpliceFlags, Sync,
+    Syncfs, Truncate, Umask, Unlinkat, Whence, Write,
 };
-use km_gen::{Constant, DefaultOr, Generator, RandomFlags, SwitchConstant, UniformCollection};
+use std::cell::Cell;
+use std::rc::Rc;
 use std::str::FromStr;
 
 /// Command type.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum CommandType {
     Openat,
+    Tmpfile,
     Mkdirat,
+    Mknodat,
     Linkat,
     Unlinkat,
     Dup,
     Close,
     Chdir,
+    Umask,
+    Mount,
+    Fchmodat,
+    Setxattrat,
+    Fsetxattr,
+    Getxattrat,
+    Fgetxattr,
+    Listxattrat,
+    Flistxattr,
+    Removexattrat,
+    Fremovexattr,
+    Flock,
+    Setlk,
+    Getlk,
+    Pipe2,
+    Read,
+    Write,
+    Truncate,
+    Ftruncate,
+    Fallocate,
+    Lseek,
+    Getdents,
+    CopyFileRange,
+    Sendfile,
+    Splice,
+    Mmap,
+    Munmap,
+    Msync,
+    MemRead,
+    MemWrite,
+    Fsync,
+    Fdatasync,
+    Syncfs,
+    Sync,
+    Reboot,
 }
 
 /// All available file names.
 const NAMES: [&str; 7] = ["aaa", "bbb", "ccc", "ddd", "eee", "fff", "ggg"];
 // const NAMES: [&str; 7] = ["a", "aa", "aaa", "aaaa", "aaaaa", "aaaaaa", "aaaaaaa"];
 
+/// All available extended attribute names, covering each namespace.
+const XATTR_NAMES: [&str; 8] = [
+    "user.aaa",
+    "user.bbb",
+    "trusted.ccc",
+    "security.ddd",
+    "system.eee",
+    "other.fff",
+    ACL_ACCESS,
+    ACL_DEFAULT,
+];
+
+/// All available data written by `write`.
+const WRITE_DATA: [&[u8]; 3] = [b"", b"x", &[b'y'; 512]];
+
+/// Base address of memory mappings, in an area the target leaves unused.
+const MAP_BASE: usize = 0x4000_0000;
+
+/// Distance between the slots mappings are placed at.
+const MAP_SLOT: usize = 16 * PAGE_SIZE;
+
+/// All available extended attribute values.
+const XATTR_VALUES: [&[u8]; 3] = [b"", b"v", b"value"];
+
+/// Sample ACLs, encoded as extended attribute values.
+fn acl_values() -> Vec<Vec<u8>> {
+    let entry = |tag, perm| AclEntry { tag, perm };
+    [
+        vec![
+            entry(AclTag::UserObj, 0o7),
+            entry(AclTag::GroupObj, 0o5),
+            entry(AclTag::Other, 0o0),
+        ],
+        vec![
+            entry(AclTag::UserObj, 0o6),
+            entry(AclTag::User(1000), 0o7),
+            entry(AclTag::GroupObj, 0o4),
+            entry(AclTag::Mask, 0o5),
+            entry(AclTag::Other, 0o4),
+        ],
+        vec![
+            entry(AclTag::UserObj, 0o7),
+            entry(AclTag::GroupObj, 0o7),
+            entry(AclTag::Group(1000), 0o6),
+            entry(AclTag::Mask, 0o6),
+            entry(AclTag::Other, 0o1),
+        ],
+    ]
+    .into_iter()
+    .map(|entries| Acl::new(entries).unwrap().to_xattr())
+    .collect()
+}
+
 #[cfg(not(feature = "fat"))]
 /// All available commands.
-const COMMANDS: [CommandType; 7] = [
+const COMMANDS: [CommandType; 42] = [
     CommandType::Openat,
+    CommandType::Tmpfile,
     CommandType::Mkdirat,
+    CommandType::Mknodat,
     CommandType::Linkat,
     CommandType::Unlinkat,
     CommandType::Dup,
     CommandType::Close,
     CommandType::Chdir,
+    CommandType::Umask,
+    CommandType::Fchmodat,
+    CommandType::Setxattrat,
+    CommandType::Fsetxattr,
+    CommandType::Getxattrat,
+    CommandType::Fgetxattr,
+    CommandType::Listxattrat,
+    CommandType::Flistxattr,
+    CommandType::Removexattrat,
+    CommandType::Fremovexattr,
+    CommandType::Flock,
+    CommandType::Setlk,
+    CommandType::Getlk,
+    CommandType::Pipe2,
+    CommandType::Read,
+    CommandType::Write,
+    CommandType::Truncate,
+    CommandType::Ftruncate,
+    CommandType::Fallocate,
+    CommandType::Lseek,
+    CommandType::Getdents,
+    CommandType::CopyFileRange,
+    CommandType::Sendfile,
+    CommandType::Splice,
+    CommandType::Mmap,
+    CommandType::Munmap,
+    CommandType::Msync,
+    CommandType::MemRead,
+    CommandType::MemWrite,
+    CommandType::Fsync,
+    CommandType::Fdatasync,
+    CommandType::Syncfs,
+    CommandType::Sync,
 ];
 
 #[cfg(feature = "fat")]
-/// All available commands. FAT filesystem does not support linkat.
-const COMMANDS: [CommandType; 6] = [
+/// All available commands. FAT filesystem does not support linkat, special files,
+/// extended attributes and `O_TMPFILE`.
+const COMMANDS: [CommandType; 31] = [
     CommandType::Openat,
     CommandType::Mkdirat,
     CommandType::Unlinkat,
     CommandType::Dup,
     CommandType::Close,
     CommandType::Chdir,
+    CommandType::Umask,
+    CommandType::Fchmodat,
+    CommandType::Flock,
+    CommandType::Setlk,
+    CommandType::Getlk,
+    CommandType::Pipe2,
+    CommandType::Read,
+    CommandType::Write,
+    CommandType::Truncate,
+    CommandType::Ftruncate,
+    CommandType::Fallocate,
+    CommandType::Lseek,
+    CommandType::Getdents,
+    CommandType::CopyFileRange,
+    CommandType::Sendfile,
+    CommandType::Splice,
+    CommandType::Mmap,
+    CommandType::Munmap,
+    CommandType::Msync,
+    CommandType::MemRead,
+    CommandType::MemWrite,
+    CommandType::Fsync,
+    CommandType::Fdatasync,
+    CommandType::Syncfs,
+    CommandType::Sync,
 ];
 
-pub struct FsCommander;
+/// Command generator.
+///
+/// The command kind and the arguments of each command are drawn from the stream
+/// of its number in a generator seeded with `seed`, see `Rng`. A run resumed from a
+/// checkpoint thus generates the same commands as the original run did from the
+/// same state.
+///
+/// Commands depend on the model state they are drawn from, which a replay
+/// checking the state more often may update differently, e.g. after a crash. Commands
+/// to be replayed are thus recorded in a `Trace` as they are generated, see `record`.
+pub struct FsCommander {
+    /// Seed of the generator.
+    seed: u64,
+    /// Number of commands generated, shared with the handles given by `generated`.
+    generated: Rc<Cell<u64>>,
+    /// Trace recording the generated commands, if any.
+    trace: Option<Trace>,
+}
+
+impl FsCommander {
+    /// Create a commander drawing commands with `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self::resume(seed, 0)
+    }
+
+    /// Create a commander continuing a run with `seed` after `generated` commands.
+    pub fn resume(seed: u64, generated: u64) -> Self {
+        Self {
+            seed,
+            generated: Rc::new(Cell::new(generated)),
+            trace: None,
+        }
+    }
+
+    /// Record the generated commands in `trace`.
+    pub fn record(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    /// Get the seed of the generator.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Get the number of commands generated, as a handle that follows the
+    /// commander once it is moved into a `Checker`.
+    pub fn generated(&self) -> Rc<Cell<u64>> {
+        self.generated.clone()
+    }
+
+    /// Wrap `cmd` in model command `wrap`, recording it if a trace is set.
+    fn build<C, M>(&self, wrap: fn(C) -> M, cmd: C) -> Box<dyn Command<FileSystem>>
+    where
+        C: Clone + 'static,
+        M: Command<FileSystem> + 'static,
+    {
+        if let Some(trace) = &self.trace {
+            let recorded = cmd.clone();
+            trace.push(Rc::new(move || -> Box<dyn Command<FileSystem>> {
+                Box::new(wrap(recorded.clone()))
+            }));
+        }
+        Box::new(wrap(cmd))
+    }
+}
 
 impl Commander<FileSystem> for FsCommander {
     fn command(&mut self, state: &FileSystem) -> Result<Box<dyn Command<FileSystem>>, Error> {
+        if state.recovering() {
+            // The namespace after a crash is unknown until the state is retrieved.
+            return Ok(self.build(Nop, km_command::Nop {}));
+        }
         // Generators
-        let mut cmd_gen = UniformCollection::new(COMMANDS.to_vec());
+        let mut commands = COMMANDS.to_vec();
+        // Crashes need a target which can be rebooted or remounted.
+        if cfg!(feature = "crash") {
+            commands.push(CommandType::Reboot);
+        } else {
+            // Mounts do not survive a reboot.
+            commands.push(CommandType::Mount);
+        }
+        let rng = Rng::new(self.seed, self.generated.get());
+        self.generated.set(self.generated.get() + 1);
+        let mut cmd_gen = UniformCollection::new(&rng, commands);
         let mut fd_gen = DefaultOr::new(
             FDCWD,
             SwitchConstant::new(
+                &rng,
                 Constant::new(FDCWD),
                 UniformCollection::new(
+                    &rng,
                     state
                         .all_fds()
                         .into_iter()
@@ -70,53 +327,492 @@ impl Commander<FileSystem> for FsCommander {
             ),
         );
         let mut abs_path_gen = UniformCollection::new(
+            &rng,
             state
                 .paths()
                 .into_iter()
                 .map(|k| {
-                    Path(heapless::String::from_str(&("/".to_owned() + &k.to_string())).unwrap())
+                    let target = k.under(state.test_root());
+                    Path(
+                        heapless::String::from_str(&("/".to_owned() + &target.to_string()))
+                            .unwrap(),
+                    )
                 })
                 .collect(),
         );
         let mut rel_path_gen = UniformCollection::new(
+            &rng,
             NAMES
                 .iter()
                 .map(|name| Path(heapless::String::from_str(name).unwrap()))
                 .collect(),
         );
-        let mut oflags_gen = RandomFlags::new(0.5);
+        let mut oflags_gen = RandomFlags::new(&rng, 0.5);
         oflags_gen.exclude(OpenFlags::DIRECTORY);
-        let mut fmode_gen = RandomFlags::new(0.4);
+        oflags_gen.exclude(OpenFlags::TMPFILE);
+        // `O_TMPFILE` needs write access, read-only is generated to check `EINVAL`.
+        let mut tmpfile_access_gen = UniformCollection::new(
+            &rng,
+            vec![
+                OpenFlags::RDWR,
+                OpenFlags::WRONLY,
+                OpenFlags::RDWR,
+                OpenFlags::WRONLY,
+                OpenFlags::RDONLY,
+            ],
+        );
+        let mut fmode_gen = RandomFlags::new(&rng, 0.4);
         fmode_gen.include(FileMode::USER_READ);
-        let mut unlinkat_flags_gen = RandomFlags::new(0.3);
+        let mut dir_mode_gen = UniformCollection::new(
+            &rng,
+            vec![
+                FileMode::empty(),
+                FileMode::empty(),
+                MODE_SETGID,
+                MODE_STICKY,
+            ],
+        );
+        let mut special_kind_gen = UniformCollection::new(
+            &rng,
+            vec![
+                FileKind::Fifo,
+                FileKind::CharDevice,
+                FileKind::BlockDevice,
+                FileKind::Socket,
+            ],
+        );
+        // Device numbers with major 0, which have no driver.
+        let mut rdev_gen = UniformCollection::new(&rng, vec![0, 1, 2, 3]);
+        // Paths of `*xattrat`, the empty path refers to dirfd with `AT_EMPTY_PATH`.
+        let mut at_path_gen = UniformCollection::new(
+            &rng,
+            NAMES
+                .iter()
+                .chain([""].iter())
+                .map(|name| Path(heapless::String::from_str(name).unwrap()))
+                .collect(),
+        );
+        let mut at_flags_gen = RandomFlags::new(&rng, 0.5);
+        let mut xattr_name_gen = UniformCollection::new(
+            &rng,
+            XATTR_NAMES
+                .iter()
+                .map(|name| heapless::String::from_str(name).unwrap())
+                .collect(),
+        );
+        let mut xattr_value_gen = UniformCollection::new(
+            &rng,
+            XATTR_VALUES
+                .iter()
+                .map(|value| value.to_vec())
+                .chain(acl_values())
+                .map(|value| heapless::Vec::from_slice(&value).unwrap())
+                .collect(),
+        );
+        let mut xattr_flags_gen = RandomFlags::new(&rng, 0.3);
+        let mut xattr_size_gen = UniformCollection::new(&rng, vec![0, 1, XATTR_SIZE_MAX]);
+        let mut flock_op_gen =
+            UniformCollection::new(&rng, vec![FlockFlags::SH, FlockFlags::EX, FlockFlags::UN]);
+        let mut lock_kind_gen = UniformCollection::new(
+            &rng,
+            vec![LockKind::Read, LockKind::Write, LockKind::Unlock],
+        );
+        // Lock ranges, overlapping each other. A zero length locks to end of file.
+        let mut lock_start_gen = UniformCollection::new(&rng, vec![0, 10, 20]);
+        let mut lock_len_gen = UniformCollection::new(&rng, vec![0, 10, 15]);
+        // A blocking pipe would block the target when empty or full.
+        let mut pipe_flags_gen = RandomFlags::new(&rng, 0.5);
+        pipe_flags_gen.include(OpenFlags::NONBLOCK);
+        let mut read_count_gen = UniformCollection::new(&rng, vec![0, 1, 100, PIPE_CAPACITY + 1]);
+        let mut write_data_gen = UniformCollection::new(
+            &rng,
+            WRITE_DATA
+                .iter()
+                .map(|data| heapless::Vec::from_slice(data).unwrap())
+                .collect(),
+        );
+        // File sizes and ranges, covering partial blocks, whole blocks and holes.
+        let mut file_len_gen = UniformCollection::new(
+            &rng,
+            vec![0, 1, BLOCK_SIZE, 3 * BLOCK_SIZE + 100, 16 * BLOCK_SIZE],
+        );
+        let mut falloc_offset_gen =
+            UniformCollection::new(&rng, vec![0, 100, BLOCK_SIZE, 5 * BLOCK_SIZE]);
+        let mut falloc_mode_gen = UniformCollection::new(
+            &rng,
+            vec![
+                FallocFlags::empty(),
+                FallocFlags::KEEP_SIZE,
+                FallocFlags::PUNCH_HOLE | FallocFlags::KEEP_SIZE,
+                FallocFlags::ZERO_RANGE,
+                FallocFlags::ZERO_RANGE | FallocFlags::KEEP_SIZE,
+            ],
+        );
+        let mut seek_offset_gen = UniformCollection::new(&rng, vec![0, 1, BLOCK_SIZE as i64, -1]);
+        let mut whence_gen = UniformCollection::new(
+            &rng,
+            vec![
+                Whence::Set,
+                Whence::Cur,
+                Whence::End,
+                Whence::Data,
+                Whence::Hole,
+            ],
+        );
+        let mut dirent_count_gen = UniformCollection::new(&rng, vec![1, 2, 64]);
+        // Explicit offsets of copies, `None` uses the file offset.
+        let mut copy_offset_gen =
+            UniformCollection::new(&rng, vec![None, Some(0), Some(100), Some(BLOCK_SIZE)]);
+        let mut copy_len_gen =
+            UniformCollection::new(&rng, vec![0, 1, 100, BLOCK_SIZE, PIPE_CAPACITY + 1]);
+        // No `copy_file_range` flags are defined.
+        let mut copy_flags_gen = UniformCollection::new(&rng, vec![0, 0, 0, 1]);
+        // Mappings are placed in a few slots, overlapping each other within a slot.
+        let mut map_addr_gen = UniformCollection::new(
+            &rng,
+            vec![
+                MAP_BASE,
+                MAP_BASE + PAGE_SIZE,
+                MAP_BASE + MAP_SLOT,
+                MAP_BASE + 2 * MAP_SLOT,
+            ],
+        );
+        let mut map_len_gen =
+            UniformCollection::new(&rng, vec![1, PAGE_SIZE, 4 * PAGE_SIZE, 8 * PAGE_SIZE]);
+        let mut map_offset_gen = UniformCollection::new(&rng, vec![0, PAGE_SIZE, 1]);
+        let mut prot_gen = RandomFlags::new(&rng, 0.5);
+        prot_gen.exclude(ProtFlags::EXEC);
+        let mut map_flags_gen = UniformCollection::new(
+            &rng,
+            vec![
+                MapFlags::SHARED | MapFlags::FIXED,
+                MapFlags::PRIVATE | MapFlags::FIXED,
+                MapFlags::SHARED | MapFlags::PRIVATE | MapFlags::FIXED,
+                MapFlags::FIXED,
+            ],
+        );
+        let mut msync_flags_gen = UniformCollection::new(
+            &rng,
+            vec![
+                MsyncFlags::ASYNC,
+                MsyncFlags::SYNC,
+                MsyncFlags::SYNC | MsyncFlags::INVALIDATE,
+                MsyncFlags::ASYNC | MsyncFlags::SYNC,
+            ],
+        );
+        // Accessed addresses, at the start, inside and past the first page of a slot.
+        let mut mem_addr_gen = UniformCollection::new(
+            &rng,
+            [0, MAP_SLOT, 2 * MAP_SLOT]
+                .into_iter()
+                .flat_map(|slot| {
+                    [0, 1, PAGE_SIZE + 1, 4 * PAGE_SIZE].map(|off| MAP_BASE + slot + off)
+                })
+                .collect(),
+        );
+        let mut mem_value_gen = UniformCollection::new(&rng, vec![0, b'z']);
+        let mut unlinkat_flags_gen = RandomFlags::new(&rng, 0.3);
+        let mut umask_gen = RandomFlags::new(&rng, 0.2);
 
         // Generate
         let cmd: Box<dyn Command<FileSystem>> = match cmd_gen.generate() {
-            CommandType::Openat => Box::new(ModelOpenat(Openat::new(
-                fd_gen.generate(),
-                rel_path_gen.generate(),
-                oflags_gen.generate(),
-                fmode_gen.generate(),
-            ))),
-            CommandType::Close => Box::new(ModelClose(Close::new(fd_gen.generate()))),
-            CommandType::Chdir => Box::new(ModelChdir(Chdir::new(abs_path_gen.generate()))),
-            CommandType::Mkdirat => Box::new(ModelMkdirat(Mkdirat::new(
-                fd_gen.generate(),
-                rel_path_gen.generate(),
-                fmode_gen.generate(),
-            ))),
-            CommandType::Unlinkat => Box::new(ModelUnlinkat(Unlinkat::new(
-                fd_gen.generate(),
-                rel_path_gen.generate(),
-                unlinkat_flags_gen.generate(),
-            ))),
-            CommandType::Linkat => Box::new(ModelLinkat(Linkat::new(
-                fd_gen.generate(),
-                rel_path_gen.generate(),
-                fd_gen.generate(),
-                rel_path_gen.generate(),
-            ))),
-            CommandType::Dup => Box::new(ModelDup(Dup::new(fd_gen.generate()))),
+            CommandType::Openat => {
+                let dirfd = fd_gen.generate();
+                let path = rel_path_gen.generate();
+                let mut flags = oflags_gen.generate();
+                // Opening a FIFO without `O_NONBLOCK` would block the target.
+                let fifo = state
+                    .parse_path(dirfd, path.clone())
+                    .and_then(|path| state.lookup(&path))
+                    .is_ok_and(|inode| inode.kind == FileKind::Fifo);
+                if fifo {
+                    flags |= OpenFlags::NONBLOCK;
+                }
+                self.build(
+                    ModelOpenat,
+                    Openat::new(dirfd, path, flags, fmode_gen.generate()),
+                )
+            }
+            CommandType::Tmpfile => self.build(
+                ModelOpenat,
+                Openat::new(
+                    fd_gen.generate(),
+                    rel_path_gen.generate(),
+                    (oflags_gen.generate()
+                        - OpenFlags::CREAT
+                        - OpenFlags::RDWR
+                        - OpenFlags::WRONLY)
+                        | tmpfile_access_gen.generate()
+                        | OpenFlags::TMPFILE,
+                    fmode_gen.generate(),
+                ),
+            ),
+            CommandType::Close => self.build(ModelClose, Close::new(fd_gen.generate())),
+            CommandType::Chdir => self.build(ModelChdir, Chdir::new(abs_path_gen.generate())),
+            CommandType::Mkdirat => self.build(
+                ModelMkdirat,
+                Mkdirat::new(
+                    fd_gen.generate(),
+                    rel_path_gen.generate(),
+                    fmode_gen.generate() | dir_mode_gen.generate(),
+                ),
+            ),
+            CommandType::Mknodat => self.build(
+                ModelMknodat,
+                Mknodat::new(
+                    fd_gen.generate(),
+                    rel_path_gen.generate(),
+                    special_kind_gen.generate(),
+                    fmode_gen.generate(),
+                    rdev_gen.generate(),
+                ),
+            ),
+            CommandType::Unlinkat => self.build(
+                ModelUnlinkat,
+                Unlinkat::new(
+                    fd_gen.generate(),
+                    rel_path_gen.generate(),
+                    unlinkat_flags_gen.generate(),
+                ),
+            ),
+            CommandType::Linkat => self.build(
+                ModelLinkat,
+                Linkat::new(
+                    fd_gen.generate(),
+                    at_path_gen.generate(),
+                    fd_gen.generate(),
+                    rel_path_gen.generate(),
+                    at_flags_gen.generate(),
+                ),
+            ),
+            CommandType::Dup => self.build(ModelDup, Dup::new(fd_gen.generate())),
+            CommandType::Umask => self.build(ModelUmask, Umask::new(umask_gen.generate())),
+            CommandType::Mount => self.build(ModelMount, Mount::new(rel_path_gen.generate())),
+            CommandType::Fchmodat => self.build(
+                ModelFchmodat,
+                Fchmodat::new(
+                    fd_gen.generate(),
+                    rel_path_gen.generate(),
+                    fmode_gen.generate() | dir_mode_gen.generate(),
+                ),
+            ),
+            CommandType::Setxattrat => self.build(
+                ModelSetxattrat,
+                Setxattrat::new(
+                    fd_gen.generate(),
+                    at_path_gen.generate(),
+                    at_flags_gen.generate(),
+                    xattr_name_gen.generate(),
+                    xattr_value_gen.generate(),
+                    xattr_flags_gen.generate(),
+                ),
+            ),
+            CommandType::Fsetxattr => self.build(
+                ModelFsetxattr,
+                Fsetxattr::new(
+                    fd_gen.generate(),
+                    xattr_name_gen.generate(),
+                    xattr_value_gen.generate(),
+                    xattr_flags_gen.generate(),
+                ),
+            ),
+            CommandType::Getxattrat => self.build(
+                ModelGetxattrat,
+                Getxattrat::new(
+                    fd_gen.generate(),
+                    at_path_gen.generate(),
+                    at_flags_gen.generate(),
+                    xattr_name_gen.generate(),
+                    xattr_size_gen.generate(),
+                ),
+            ),
+            CommandType::Fgetxattr => self.build(
+                ModelFgetxattr,
+                Fgetxattr::new(
+                    fd_gen.generate(),
+                    xattr_name_gen.generate(),
+                    xattr_size_gen.generate(),
+                ),
+            ),
+            CommandType::Listxattrat => self.build(
+                ModelListxattrat,
+                Listxattrat::new(
+                    fd_gen.generate(),
+                    at_path_gen.generate(),
+                    at_flags_gen.generate(),
+                    xattr_size_gen.generate(),
+                ),
+            ),
+            CommandType::Flistxattr => self.build(
+                ModelFlistxattr,
+                Flistxattr::new(fd_gen.generate(), xattr_size_gen.generate()),
+            ),
+            CommandType::Removexattrat => self.build(
+                ModelRemovexattrat,
+                Removexattrat::new(
+                    fd_gen.generate(),
+                    at_path_gen.generate(),
+                    at_flags_gen.generate(),
+                    xattr_name_gen.generate(),
+                ),
+            ),
+            CommandType::Fremovexattr => self.build(
+                ModelFremovexattr,
+                Fremovexattr::new(fd_gen.generate(), xattr_name_gen.generate()),
+            ),
+            // A blocking `flock` would block the target on a conflict.
+            CommandType::Flock => self.build(
+                ModelFlock,
+                Flock::new(fd_gen.generate(), flock_op_gen.generate() | FlockFlags::NB),
+            ),
+            CommandType::Setlk => self.build(
+                ModelSetlk,
+                Setlk::new(
+                    fd_gen.generate(),
+                    lock_kind_gen.generate(),
+                    lock_start_gen.generate(),
+                    lock_len_gen.generate(),
+                ),
+            ),
+            CommandType::Getlk => self.build(
+                ModelGetlk,
+                Getlk::new(
+                    fd_gen.generate(),
+                    lock_kind_gen.generate(),
+                    lock_start_gen.generate(),
+                    lock_len_gen.generate(),
+                ),
+            ),
+            CommandType::Pipe2 => self.build(
+                ModelPipe2,
+                Pipe2::new(pipe_flags_gen.generate() & (OpenFlags::NONBLOCK | OpenFlags::CLOEXEC)),
+            ),
+            CommandType::Read => self.build(
+                ModelRead,
+                Read::new(fd_gen.generate(), read_count_gen.generate()),
+            ),
+            CommandType::Write => self.build(
+                ModelWrite,
+                Write::new(fd_gen.generate(), write_data_gen.generate()),
+            ),
+            CommandType::Truncate => self.build(
+                ModelTruncate,
+                Truncate::new(rel_path_gen.generate(), file_len_gen.generate()),
+            ),
+            CommandType::Ftruncate => self.build(
+                ModelFtruncate,
+                Ftruncate::new(fd_gen.generate(), file_len_gen.generate()),
+            ),
+            CommandType::Fallocate => self.build(
+                ModelFallocate,
+                Fallocate::new(
+                    fd_gen.generate(),
+                    falloc_mode_gen.generate(),
+                    falloc_offset_gen.generate(),
+                    file_len_gen.generate(),
+                ),
+            ),
+            CommandType::Lseek => {
+                let fd = fd_gen.generate();
+                // Directory offsets past the start depend on the file system.
+                let is_dir = state
+                    .fd_ref(fd)
+                    .is_ok_and(|fref| state.inode(&fref).is_ok_and(|inode| inode.is_dir()));
+                let whence = if is_dir {
+                    Whence::Set
+                } else {
+                    whence_gen.generate()
+                };
+                let offset = if is_dir {
+                    0
+                } else {
+                    seek_offset_gen.generate()
+                };
+                self.build(ModelLseek, Lseek::new(fd, offset, whence))
+            }
+            CommandType::Getdents => {
+                let fd = fd_gen.generate();
+                let count = [dirent_count_gen.generate(), 1]
+                    .into_iter()
+                    .find(|&count| state.getdents_determined(fd, count));
+                match count {
+                    Some(count) => self.build(ModelGetdents, Getdents::new(fd, count)),
+                    // The number of entries left is unknown, rewind the stream instead.
+                    None => self.build(ModelLseek, Lseek::new(fd, 0, Whence::Set)),
+                }
+            }
+            CommandType::CopyFileRange => self.build(
+                ModelCopyFileRange,
+                CopyFileRange::new(
+                    fd_gen.generate(),
+                    copy_offset_gen.generate(),
+                    fd_gen.generate(),
+                    copy_offset_gen.generate(),
+                    copy_len_gen.generate(),
+                    copy_flags_gen.generate(),
+                ),
+            ),
+            CommandType::Sendfile => self.build(
+                ModelSendfile,
+                Sendfile::new(
+                    fd_gen.generate(),
+                    fd_gen.generate(),
+                    copy_offset_gen.generate(),
+                    copy_len_gen.generate(),
+                ),
+            ),
+            CommandType::Splice => self.build(
+                ModelSplice,
+                Splice::new(
+                    fd_gen.generate(),
+                    copy_offset_gen.generate(),
+                    fd_gen.generate(),
+                    copy_offset_gen.generate(),
+                    copy_len_gen.generate(),
+                    SpliceFlags::NONBLOCK,
+                ),
+            ),
+            CommandType::Mmap => self.build(
+                ModelMmap,
+                Mmap::new(
+                    map_addr_gen.generate(),
+                    map_len_gen.generate(),
+                    prot_gen.generate(),
+                    map_flags_gen.generate(),
+                    fd_gen.generate(),
+                    map_offset_gen.generate(),
+                ),
+            ),
+            CommandType::Munmap => self.build(
+                ModelMunmap,
+                Munmap::new(map_addr_gen.generate(), map_len_gen.generate()),
+            ),
+            CommandType::Msync => self.build(
+                ModelMsync,
+                Msync::new(
+                    map_addr_gen.generate(),
+                    map_len_gen.generate(),
+                    msync_flags_gen.generate(),
+                ),
+            ),
+            CommandType::MemRead => {
+                let addr = mem_addr_gen.generate();
+                if state.mem_read_determined(addr) {
+                    self.build(ModelMemRead, MemRead::new(addr))
+                } else {
+                    // The byte is unknown to the model, write it instead.
+                    self.build(ModelMemWrite, MemWrite::new(addr, mem_value_gen.generate()))
+                }
+            }
+            CommandType::MemWrite => self.build(
+                ModelMemWrite,
+                MemWrite::new(mem_addr_gen.generate(), mem_value_gen.generate()),
+            ),
+            CommandType::Fsync => self.build(ModelFsync, Fsync::new(fd_gen.generate())),
+            CommandType::Fdatasync => self.build(ModelFdatasync, Fdatasync::new(fd_gen.generate())),
+            CommandType::Syncfs => self.build(ModelSyncfs, Syncfs::new(fd_gen.generate())),
+            CommandType::Sync => self.build(ModelSync, Sync::new()),
+            CommandType::Reboot => self.build(ModelReboot, Reboot::new()),
         };
         Ok(cmd)
     }
@@ -0,0 +1,143 @@
+use crate::fs::BLOCK_SIZE;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Number of 512-byte units in a block, as `st_blocks` counts.
+pub const SECTORS_PER_BLOCK: usize = BLOCK_SIZE / 512;
+
+/// Content of an empty file.
+pub static EMPTY_DATA: FileData = FileData {
+    bytes: Vec::new(),
+    blocks: BTreeSet::new(),
+};
+
+/// Content of a regular file, with sparse holes.
+///
+/// Blocks are either allocated or holes. Holes read as zeros and take no space.
+/// Preallocated blocks read as zeros too, but count as data, as on file systems
+/// reporting unwritten extents as data.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileData {
+    /// Bytes written. Bytes past the end are zeros.
+    bytes: Vec<u8>,
+    /// Indexes of allocated blocks, possibly past the end of file.
+    blocks: BTreeSet<usize>,
+}
+
+impl FileData {
+    /// Get the byte at offset `off`.
+    pub fn byte(&self, off: usize) -> u8 {
+        self.bytes.get(off).copied().unwrap_or(0)
+    }
+
+    /// Write `buf` at offset `off`, allocating the blocks written.
+    pub fn write(&mut self, off: usize, buf: &[u8]) {
+        if buf.is_empty() {
+            return;
+        }
+        let end = off + buf.len();
+        if self.bytes.len() < end {
+            self.bytes.resize(end, 0);
+        }
+        self.bytes[off..end].copy_from_slice(buf);
+        self.allocate(off, buf.len());
+    }
+
+    /// Drop the bytes past `size`, so that the file reads zeros there when it
+    /// is extended. Allocated blocks are kept.
+    pub fn zero_tail(&mut self, size: usize) {
+        self.bytes.truncate(size);
+    }
+
+    /// Truncate the file to `size`, freeing the blocks past the end of file.
+    pub fn truncate(&mut self, size: usize) {
+        self.bytes.truncate(size);
+        let end = size.div_ceil(BLOCK_SIZE);
+        self.blocks.retain(|&b| b < end);
+    }
+
+    /// Allocate the blocks covering [`off`, `off + len`).
+    pub fn allocate(&mut self, off: usize, len: usize) {
+        self.blocks.extend(Self::covering(off, len));
+    }
+
+    /// Zero [`off`, `off + len`), freeing the blocks entirely inside it.
+    pub fn punch_hole(&mut self, off: usize, len: usize) {
+        self.zero(off, len);
+        let first = off.div_ceil(BLOCK_SIZE);
+        let last = (off + len) / BLOCK_SIZE;
+        self.blocks.retain(|&b| b < first || b >= last);
+    }
+
+    /// Zero [`off`, `off + len`), allocating the blocks covering it.
+    pub fn zero_range(&mut self, off: usize, len: usize) {
+        self.zero(off, len);
+        self.allocate(off, len);
+    }
+
+    /// Get the number of allocated blocks.
+    pub fn blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Get the number of blocks to allocate for [`off`, `off + len`).
+    pub fn new_blocks(&self, off: usize, len: usize) -> usize {
+        Self::covering(off, len)
+            .filter(|b| !self.blocks.contains(b))
+            .count()
+    }
+
+    /// Get how many of `len` bytes written at `off` fit, when at most `free`
+    /// blocks can be allocated.
+    pub fn fit(&self, off: usize, len: usize, free: usize) -> usize {
+        let mut free = free;
+        for b in Self::covering(off, len) {
+            if self.blocks.contains(&b) {
+                continue;
+            }
+            if free == 0 {
+                return (b * BLOCK_SIZE).saturating_sub(off);
+            }
+            free -= 1;
+        }
+        len
+    }
+
+    /// Get the first data offset at or after `off`, in a file of `size` bytes.
+    pub fn seek_data(&self, off: usize, size: usize) -> Option<usize> {
+        self.blocks
+            .range(off / BLOCK_SIZE..)
+            .next()
+            .map(|&b| off.max(b * BLOCK_SIZE))
+            .filter(|&data| data < size)
+    }
+
+    /// Get the first hole offset at or after `off`, in a file of `size` bytes.
+    /// The end of file counts as a hole.
+    pub fn seek_hole(&self, off: usize, size: usize) -> usize {
+        let mut b = off / BLOCK_SIZE;
+        while self.blocks.contains(&b) {
+            b += 1;
+        }
+        off.max(b * BLOCK_SIZE).min(size)
+    }
+
+    /// Zero the bytes in [`off`, `off + len`).
+    fn zero(&mut self, off: usize, len: usize) {
+        let end = (off + len).min(self.bytes.len());
+        if off < end {
+            self.bytes[off..end].fill(0);
+        }
+    }
+
+    /// Get the indexes of the blocks covering [`off`, `off + len`).
+    fn covering(off: usize, len: usize) -> impl Iterator<Item = usize> {
+        let first = off / BLOCK_SIZE;
+        let last = if len == 0 {
+            first
+        } else {
+            (off + len).div_ceil(BLOCK_SIZE)
+        };
+        first..last
+    }
+}
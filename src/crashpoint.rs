@@ -0,0 +1,375 @@
+use crate::FileSystem;
+use km_checker::{AbstractState, Error};
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Block device operation recorded in the write log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOp {
+    /// Write `data` at byte offset `offset`.
+    Write { offset: u64, data: Vec<u8> },
+    /// Flush barrier. Writes before it are durable, and never reordered after it.
+    Flush,
+}
+
+/// File-backed block device stand-in, recording the writes of the target file
+/// system in order.
+pub struct RecordingDevice {
+    /// Backing image file.
+    file: File,
+    /// Recorded operations.
+    log: Vec<BlockOp>,
+}
+
+impl RecordingDevice {
+    /// Open image `path` as the device.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self {
+            file,
+            log: Vec::new(),
+        })
+    }
+
+    /// Read `buf.len()` bytes at byte offset `offset`.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    /// Write `data` at byte offset `offset`, and record the write.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.log.push(BlockOp::Write {
+            offset,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Flush the device, and record a barrier.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.log.push(BlockOp::Flush);
+        Ok(())
+    }
+
+    /// Get the number of recorded operations.
+    pub fn log_len(&self) -> usize {
+        self.log.len()
+    }
+
+    /// Take the recorded operations, leaving the log empty.
+    pub fn take_log(&mut self) -> Vec<BlockOp> {
+        std::mem::take(&mut self.log)
+    }
+}
+
+/// Port recovering a crashed file system image, by mounting it or running its
+/// recovery tool, and retrieving the recovered state.
+pub trait RecoveryPort {
+    /// Recover the file system in `image` and retrieve its state.
+    fn recover(&mut self, image: &Path) -> Result<FileSystem, Error>;
+}
+
+/// Model states recorded while the target runs on a `RecordingDevice`.
+///
+/// Start recording right after a `sync`, with a copy of the image taken then as
+/// the base image. After each step, record the model state together with the
+/// length of the device log.
+pub struct CrashRecorder {
+    /// (log length at the end of the step, model state after the step).
+    steps: Vec<(usize, FileSystem)>,
+}
+
+impl CrashRecorder {
+    /// Start recording with the model state `model` at the base image.
+    pub fn new(model: &FileSystem) -> Self {
+        Self {
+            steps: vec![(0, model.clone())],
+        }
+    }
+
+    /// Record the model state after a step, when the device log has `log_len`
+    /// operations.
+    pub fn record_step(&mut self, log_len: usize, model: &FileSystem) {
+        self.steps.push((log_len, model.clone()));
+    }
+
+    /// Check if `recovered` is allowed at a crash with the writes from log index
+    /// `from` to `to` (exclusive) partly persisted, and all writes before `from`.
+    ///
+    /// The writes of a step may persist any state since the last sync before the
+    /// step, up to the state after the step.
+    fn allows(&self, from: usize, to: usize, recovered: &FileSystem) -> bool {
+        let step_of = |writes: usize| {
+            self.steps
+                .iter()
+                .position(|(log_len, _)| *log_len >= writes)
+                .unwrap_or(self.steps.len() - 1)
+        };
+        let first = step_of(from.max(1)).saturating_sub(1);
+        (first..=step_of(to)).any(|i| {
+            let mut model = self.steps[i].1.clone();
+            model.crash();
+            model.matches(recovered)
+        })
+    }
+}
+
+/// A crash point, with all writes before the last flush barrier passed persisted,
+/// and some of the writes after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashPoint {
+    /// Number of flush barriers passed.
+    pub flushes: usize,
+    /// Log indices of the writes after the last barrier which are persisted.
+    pub applied: Vec<usize>,
+}
+
+/// Failure found by crash-point enumeration.
+#[derive(Debug)]
+pub enum CrashError {
+    /// Image files could not be prepared.
+    Io(io::Error),
+    /// The file system could not be recovered at the crash point.
+    Recovery(CrashPoint, Error),
+    /// The recovered state is not allowed by the model.
+    Mismatch(CrashPoint, FileSystem),
+}
+
+impl From<io::Error> for CrashError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Writes between two flush barriers, which the device may persist in any order.
+struct Epoch {
+    /// Log index of the first operation.
+    start: usize,
+    /// Log indices of the writes.
+    writes: Vec<usize>,
+}
+
+/// Split `log` at its flush barriers.
+fn epochs(log: &[BlockOp]) -> Vec<Epoch> {
+    let mut epochs = vec![Epoch {
+        start: 0,
+        writes: Vec::new(),
+    }];
+    for (i, op) in log.iter().enumerate() {
+        match op {
+            BlockOp::Write { .. } => epochs.last_mut().unwrap().writes.push(i),
+            BlockOp::Flush => epochs.push(Epoch {
+                start: i + 1,
+                writes: Vec::new(),
+            }),
+        }
+    }
+    epochs
+}
+
+/// Get the subsets of `n` writes to persist, as sorted positions.
+///
+/// All subsets are enumerated if there are at most `max` of them. Otherwise only
+/// the in-order prefixes, and all writes but one.
+fn subsets(n: usize, max: usize) -> Vec<Vec<usize>> {
+    if n < usize::BITS as usize && 1usize << n <= max {
+        return (0..1usize << n)
+            .map(|mask| (0..n).filter(|i| mask & (1 << i) != 0).collect())
+            .collect();
+    }
+    let prefixes = (0..=n).map(|len| (0..len).collect());
+    let all_but_one = (0..n.saturating_sub(1)).map(|skip| (0..n).filter(|&i| i != skip).collect());
+    prefixes.chain(all_but_one).collect()
+}
+
+/// Enumerate the crash points of a recorded device log, CrashMonkey style.
+///
+/// The log is split into epochs at flush barriers. For each epoch, the writes of
+/// all epochs before it are applied to a copy of `base`, together with each subset
+/// of its own writes, up to `max_subsets` subsets, see `subsets`. Writes are never
+/// moved across a barrier. The image is recovered through `port` and checked
+/// against the model. Return the number of crash points checked.
+pub fn check_crash_points<P: RecoveryPort>(
+    recorder: &CrashRecorder,
+    base: &Path,
+    log: &[BlockOp],
+    max_subsets: usize,
+    work_dir: &Path,
+    port: &mut P,
+) -> Result<usize, CrashError> {
+    // `staged` holds the base image with the epochs before the current one applied,
+    // and is copied to `image` for each crash point.
+    let staged = work_dir.join("staged.img");
+    let image = work_dir.join("crash.img");
+    fs::copy(base, &staged)?;
+    let mut checked = 0;
+    for (flushes, epoch) in epochs(log).into_iter().enumerate() {
+        for subset in subsets(epoch.writes.len(), max_subsets) {
+            // With no writes, the image is the one after all writes of the
+            // previous epoch, which was checked already.
+            if subset.is_empty() && flushes > 0 {
+                continue;
+            }
+            let applied: Vec<usize> = subset.iter().map(|&i| epoch.writes[i]).collect();
+            fs::copy(&staged, &image)?;
+            apply(&image, log, &applied)?;
+            let point = CrashPoint { flushes, applied };
+            let to = point.applied.last().map_or(epoch.start, |&i| i + 1);
+            let recovered = port
+                .recover(&image)
+                .map_err(|e| CrashError::Recovery(point.clone(), e))?;
+            if !recorder.allows(epoch.start, to, &recovered) {
+                return Err(CrashError::Mismatch(point, recovered));
+            }
+            checked += 1;
+        }
+        apply(&staged, log, &epoch.writes)?;
+    }
+    Ok(checked)
+}
+
+/// Apply the writes at log indices `writes` of `log` to `image`, in log order.
+fn apply(image: &Path, log: &[BlockOp], writes: &[usize]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(image)?;
+    for &i in writes {
+        if let BlockOp::Write { offset, data } = &log[i] {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(data)?;
+        }
+    }
+    Ok(())
+}
+
+/// A crash-point session, recording the model states of the steps run while the
+/// target writes through `device`.
+///
+/// A session may start at any step. The base image holds all writes before it, and
+/// each recorded model state allows any state since the last sync.
+pub struct CrashSession {
+    /// Device the target file system writes to.
+    device: Rc<RefCell<RecordingDevice>>,
+    /// Copy of the image at the start of the session.
+    base: PathBuf,
+    /// Model states of the steps.
+    recorder: CrashRecorder,
+}
+
+impl CrashSession {
+    /// Start a session with model state `model`, copying device image `image` to
+    /// `work_dir` as the base image.
+    pub fn start(
+        device: Rc<RefCell<RecordingDevice>>,
+        image: &Path,
+        work_dir: &Path,
+        model: &FileSystem,
+    ) -> io::Result<Self> {
+        let base = work_dir.join("base.img");
+        fs::copy(image, &base)?;
+        device.borrow_mut().take_log();
+        Ok(Self {
+            device,
+            base,
+            recorder: CrashRecorder::new(model),
+        })
+    }
+
+    /// Record the model state after a step.
+    pub fn record_step(&mut self, model: &FileSystem) {
+        let log_len = self.device.borrow().log_len();
+        self.recorder.record_step(log_len, model);
+    }
+
+    /// Enumerate the crash points of the steps recorded, see `check_crash_points`.
+    pub fn finish<P: RecoveryPort>(
+        self,
+        max_subsets: usize,
+        work_dir: &Path,
+        port: &mut P,
+    ) -> Result<usize, CrashError> {
+        let log = self.device.borrow_mut().take_log();
+        check_crash_points(
+            &self.recorder,
+            &self.base,
+            &log,
+            max_subsets,
+            work_dir,
+            port,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path::AbsPath;
+    use km_command::fs::{FileKind, FileMode};
+
+    /// Recovery of a toy image, where byte `i` being set means file `f{i}` exists.
+    struct ByteRecovery;
+
+    impl RecoveryPort for ByteRecovery {
+        fn recover(&mut self, image: &Path) -> Result<FileSystem, Error> {
+            let bytes = fs::read(image).map_err(|_| Error::Io)?;
+            let mut state = FileSystem::new_root(0, 0);
+            for (i, _) in bytes.iter().enumerate().filter(|(_, b)| **b != 0) {
+                create_file(&mut state, i);
+            }
+            Ok(state)
+        }
+    }
+
+    fn create_file(state: &mut FileSystem, i: usize) {
+        let path = AbsPath::new(&format!("f{}", i)).unwrap();
+        state.create(path, FileKind::File, FileMode::all()).unwrap();
+    }
+
+    /// Run steps creating `f0` then `f1`, each by one device write, with a flush
+    /// after the first write if `flush`.
+    fn check(name: &str, flush: bool) -> Result<usize, CrashError> {
+        let dir = std::env::temp_dir().join(format!("model-fs-crash-{}", name));
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("device.img");
+        fs::write(&image, [0, 0]).unwrap();
+        let device = Rc::new(RefCell::new(RecordingDevice::open(&image).unwrap()));
+        let mut model = FileSystem::new_root(0, 0);
+        let mut session = CrashSession::start(device.clone(), &image, &dir, &model).unwrap();
+        for i in 0..2 {
+            create_file(&mut model, i);
+            device.borrow_mut().write(i as u64, &[1]).unwrap();
+            if flush && i == 0 {
+                device.borrow_mut().flush().unwrap();
+            }
+            session.record_step(&model);
+        }
+        let result = session.finish(16, &dir, &mut ByteRecovery);
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn reordered_writes_are_checked() {
+        // `f1` persisted without `f0` is a state the model never had.
+        match check("reordered", false) {
+            Err(CrashError::Mismatch(point, _)) => assert_eq!(
+                point,
+                CrashPoint {
+                    flushes: 0,
+                    applied: vec![1]
+                }
+            ),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn writes_are_not_reordered_across_flushes() {
+        assert_eq!(check("flushed", true).unwrap(), 3);
+    }
+}
@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Directory stream of an open file description, read by `getdents`.
+///
+/// The order of entries is up to the file system, so the stream only tracks how
+/// many entries were returned since it was opened or rewound, and which names
+/// were created or removed since then.
+///
+/// - Entries which exist for the whole iteration appear exactly once.
+/// - Entries created or removed during the iteration may or may not appear, but
+///   at most once.
+///
+/// The number of entries read is thus known only within bounds, and `read` tells
+/// when it is not determined.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirStream {
+    /// Number of entries returned, "." and ".." included.
+    returned: usize,
+    /// Names created or removed during the iteration.
+    unstable: BTreeSet<String>,
+}
+
+impl DirStream {
+    /// Rewind the stream to the start of the directory.
+    pub fn rewind(&mut self) {
+        self.returned = 0;
+        self.unstable.clear();
+    }
+
+    /// Note that entry `name` is created or removed.
+    pub fn changed(&mut self, name: &str) {
+        self.unstable.insert(name.to_owned());
+    }
+
+    /// Get the bounds of the number of entries left, with `names` the current
+    /// entries, "." and ".." included.
+    pub fn remaining(&self, names: &[String]) -> (usize, usize) {
+        let unstable = names
+            .iter()
+            .filter(|name| self.unstable.contains(*name))
+            .count();
+        let stable = names.len() - unstable;
+        // At most `self.unstable.len()` of the returned entries are unstable ones.
+        let stable_returned_min = self.returned.saturating_sub(self.unstable.len());
+        let min = stable.saturating_sub(self.returned);
+        let max = stable.saturating_sub(stable_returned_min) + unstable;
+        (min, max)
+    }
+
+    /// Check if reading up to `count` entries gives a determined number of entries.
+    pub fn determined(&self, names: &[String], count: usize) -> bool {
+        let (min, max) = self.remaining(names);
+        count <= min || min == max
+    }
+
+    /// Read up to `count` entries, returning the number of entries read.
+    ///
+    /// If the number is not determined, unstable entries are taken as not appearing.
+    pub fn read(&mut self, names: &[String], count: usize) -> usize {
+        let n = count.min(self.remaining(names).0);
+        self.returned += n;
+        n
+    }
+}
@@ -0,0 +1,221 @@
+use crate::inode::Inode;
+use crate::path::AbsPath;
+use multi_key_map::MultiKeyMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+/// Namespace snapshot, the same structure as `FileSystem::inodes`.
+pub type Snapshot = MultiKeyMap<AbsPath, Inode>;
+
+/// CrashOrdering guarantees of the file system under test, deciding which states may
+/// be recovered after a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CrashOrdering {
+    /// Operations persist in order, and `fsync` commits all operations before it,
+    /// as journaling file systems do. The recovered state must be one of the
+    /// states since the last sync.
+    Ordered,
+    /// Each path persists independently. `fsync` of a file only persists the
+    /// file, `fsync` of a directory only persists its entries.
+    Relaxed,
+}
+
+/// Durability tracking of the namespace.
+///
+/// Only the persisted state, as of the last sync point, is kept whole. The states
+/// since are kept as the values each changed path took, so that a change costs no
+/// more than the paths it touches. State 0 is the persisted state. The paths
+/// touched by the latest change are pending until the next change, and are taken
+/// from the current state when checking.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Durability {
+    /// CrashOrdering guarantees.
+    ordering: CrashOrdering,
+    /// State at the last sync point.
+    #[serde(with = "crate::snapshot::inode_map")]
+    persisted: Snapshot,
+    /// Number of states since the last sync point, the persisted one included.
+    states: usize,
+    /// Values taken by changed paths, as (index of the state, inode), the inode
+    /// `None` if the path is missing.
+    changes: BTreeMap<AbsPath, Vec<(usize, Option<Inode>)>>,
+    /// Paths touched since the last recorded state.
+    pending: BTreeSet<AbsPath>,
+    /// Files and directories whose inode was persisted by `fsync`, mapped to the
+    /// index of the state it was persisted in.
+    pins: BTreeMap<AbsPath, usize>,
+    /// Directories whose entries were persisted by `fsync`, mapped to the index
+    /// of the state they were persisted in.
+    dir_pins: BTreeMap<AbsPath, usize>,
+}
+
+impl Durability {
+    /// Create durability tracking, with `persisted` as the persisted state.
+    pub fn new(ordering: CrashOrdering, persisted: Snapshot) -> Self {
+        Self {
+            ordering,
+            persisted,
+            states: 1,
+            changes: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            pins: BTreeMap::new(),
+            dir_pins: BTreeMap::new(),
+        }
+    }
+
+    /// Note that `paths` of `current` are about to change, recording `current` as
+    /// a state that may be persisted first.
+    pub fn touch<'a>(&mut self, current: &Snapshot, paths: impl IntoIterator<Item = &'a AbsPath>) {
+        self.record(current);
+        self.pending.extend(paths.into_iter().cloned());
+    }
+
+    /// Record `current` as a state that may be persisted, if a pending path changed.
+    fn record(&mut self, current: &Snapshot) {
+        let last = self.states - 1;
+        let changed: Vec<_> = std::mem::take(&mut self.pending)
+            .into_iter()
+            .filter(|path| self.value_at(path, last) != current.get(path))
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+        for path in changed {
+            let value = current.get(&path).cloned();
+            self.changes
+                .entry(path)
+                .or_default()
+                .push((self.states, value));
+        }
+        self.states += 1;
+    }
+
+    /// Persist `current` as a whole (`sync`, `syncfs`).
+    pub fn sync(&mut self, current: &Snapshot) {
+        self.persisted = current.clone();
+        self.states = 1;
+        self.changes.clear();
+        self.pending.clear();
+        self.pins.clear();
+        self.dir_pins.clear();
+    }
+
+    /// Persist the inode at `path` (`fsync`, `fdatasync`), and also the entries of
+    /// `path` if it is a directory.
+    pub fn fsync(&mut self, current: &Snapshot, path: &AbsPath) {
+        if self.ordering == CrashOrdering::Ordered {
+            // Committing the journal persists everything before.
+            self.sync(current);
+            return;
+        }
+        self.record(current);
+        let idx = self.states - 1;
+        self.pins.insert(path.clone(), idx);
+        if current.get(path).is_some_and(|inode| inode.is_dir()) {
+            self.dir_pins.insert(path.clone(), idx);
+        }
+    }
+
+    /// Check if `recovered` is a state allowed after a crash, with `current`
+    /// being the state right before the crash.
+    pub fn allows(&self, current: &Snapshot, recovered: &Snapshot) -> bool {
+        match self.ordering {
+            CrashOrdering::Ordered => self.allows_ordered(current, recovered),
+            CrashOrdering::Relaxed => self.allows_relaxed(current, recovered),
+        }
+    }
+
+    /// Get the inode at `path` in state `idx`.
+    fn value_at(&self, path: &AbsPath, idx: usize) -> Option<&Inode> {
+        match self.changes.get(path) {
+            Some(values) => match values.iter().rev().find(|(i, _)| *i <= idx) {
+                Some((_, value)) => value.as_ref(),
+                None => self.persisted.get(path),
+            },
+            None => self.persisted.get(path),
+        }
+    }
+
+    /// Get the inode at `path` in state `idx`, where state `self.states` is `current`,
+    /// the last state with the pending paths changed.
+    fn value_in<'a>(
+        &'a self,
+        current: &'a Snapshot,
+        path: &AbsPath,
+        idx: usize,
+    ) -> Option<&'a Inode> {
+        if idx == self.states && self.pending.contains(path) {
+            current.get(path)
+        } else {
+            self.value_at(path, idx.min(self.states - 1))
+        }
+    }
+
+    /// Paths changed since the last sync point, recorded or pending.
+    fn changed(&self) -> impl Iterator<Item = &AbsPath> {
+        self.changes.keys().chain(
+            self.pending
+                .iter()
+                .filter(|path| !self.changes.contains_key(*path)),
+        )
+    }
+
+    /// Check if `recovered` equals one of the states.
+    fn allows_ordered(&self, current: &Snapshot, recovered: &Snapshot) -> bool {
+        // Paths never changed must be as persisted.
+        let unchanged = self
+            .persisted
+            .keys()
+            .chain(recovered.keys())
+            .filter(|path| !self.changes.contains_key(*path) && !self.pending.contains(*path))
+            .all(|path| self.persisted.get(path) == recovered.get(path));
+        unchanged
+            && (0..=self.states).any(|idx| {
+                self.changed()
+                    .all(|path| self.value_in(current, path, idx) == recovered.get(path))
+            })
+    }
+
+    /// Check `recovered` path by path.
+    ///
+    /// - A path in `recovered` must exist in some state after the fsync of its
+    ///   parent, and have the same inode as in some state after its own fsync.
+    /// - A path missing in `recovered` must be missing in some state after the
+    ///   fsync of its parent.
+    fn allows_relaxed(&self, current: &Snapshot, recovered: &Snapshot) -> bool {
+        let since = |pins: &BTreeMap<AbsPath, usize>, path: &AbsPath| {
+            pins.get(path).copied().unwrap_or(0)..=self.states
+        };
+        let entry_since = |path: &AbsPath| match path.parent() {
+            Some(parent) => since(&self.dir_pins, &parent),
+            None => 0..=self.states,
+        };
+        let value = |path: &AbsPath, idx| self.value_in(current, path, idx);
+        for path in recovered.keys() {
+            let inode = recovered.get(path);
+            if !since(&self.pins, path).any(|idx| value(path, idx) == inode) {
+                return false;
+            }
+            if !entry_since(path).any(|idx| value(path, idx).is_some()) {
+                return false;
+            }
+        }
+        let mut paths: Vec<&AbsPath> = self.persisted.keys().chain(self.changed()).collect();
+        paths.sort();
+        paths.dedup();
+        paths
+            .into_iter()
+            .filter(|path| !recovered.contains_key(path))
+            .all(|path| entry_since(path).any(|idx| value(path, idx).is_none()))
+    }
+}
+
+impl Debug for Durability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{:?}, {} states since sync, pins: {:?}, directory pins: {:?}",
+            self.ordering, self.states, self.pins, self.dir_pins
+        ))
+    }
+}
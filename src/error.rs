@@ -23,6 +23,42 @@ pub enum FsError {
     InvalidPath,
     /// Directory is not empty.
     DirectoryNotEmpty,
+    /// Operation not permitted.
+    NotPermitted,
+    /// No such device or address.
+    NoSuchDevice,
+    /// Device or resource busy.
+    Busy,
+    /// Cross-device link.
+    CrossDevice,
+    /// No space left on device.
+    NoSpace,
+    /// Invalid argument.
+    InvalidArgument,
+    /// No data available, e.g. a missing extended attribute.
+    NoData,
+    /// Result out of range, e.g. a too small buffer.
+    OutOfRange,
+    /// Operation not supported.
+    NotSupported,
+    /// Argument too big.
+    TooBig,
+    /// Operation would block, e.g. a conflicting lock.
+    WouldBlock,
+    /// File not opened with the access mode the operation needs.
+    WrongAccessMode,
+    /// Writing to a pipe without readers.
+    BrokenPipe,
+    /// No such device, e.g. mapping a file that cannot be mapped.
+    NoDevice,
+    /// Out of memory, e.g. syncing an unmapped range.
+    OutOfMemory,
+    /// Bad address. A `SIGSEGV` on a memory access is reported as this error.
+    Fault,
+    /// A `SIGBUS` on a memory access, reported as `EIO`.
+    BusError,
+    /// Seeking a pipe or FIFO.
+    IllegalSeek,
 }
 
 impl Into<isize> for FsError {
@@ -38,6 +74,24 @@ impl Into<isize> for FsError {
             FsError::NoAvailableFd => linux_err!(EMFILE),
             FsError::InvalidPath => linux_err!(EINVAL),
             FsError::DirectoryNotEmpty => linux_err!(ENOTEMPTY),
+            FsError::NotPermitted => linux_err!(EPERM),
+            FsError::NoSuchDevice => linux_err!(ENXIO),
+            FsError::Busy => linux_err!(EBUSY),
+            FsError::CrossDevice => linux_err!(EXDEV),
+            FsError::NoSpace => linux_err!(ENOSPC),
+            FsError::InvalidArgument => linux_err!(EINVAL),
+            FsError::NoData => linux_err!(ENODATA),
+            FsError::OutOfRange => linux_err!(ERANGE),
+            FsError::NotSupported => linux_err!(EOPNOTSUPP),
+            FsError::TooBig => linux_err!(E2BIG),
+            FsError::WouldBlock => linux_err!(EAGAIN),
+            FsError::WrongAccessMode => linux_err!(EBADF),
+            FsError::BrokenPipe => linux_err!(EPIPE),
+            FsError::NoDevice => linux_err!(ENODEV),
+            FsError::OutOfMemory => linux_err!(ENOMEM),
+            FsError::Fault => linux_err!(EFAULT),
+            FsError::BusError => linux_err!(EIO),
+            FsError::IllegalSeek => linux_err!(ESPIPE),
         }
     }
 }
@@ -0,0 +1,175 @@
+use crate::path::AbsPath;
+use km_command::fs::FileKind;
+use std::fmt::Display;
+
+/// How the target directory graph is corrupt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    /// The directory is already found at `first`, e.g. it contains itself.
+    Cycle { first: AbsPath },
+    /// The directory is nested deeper than the limit.
+    TooDeep(usize),
+    /// The directory lists more entries than the limit.
+    TooManyEntries(usize),
+    /// The walk takes more steps than the limit.
+    Steps(usize),
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Corruption::Cycle { first } => write!(f, "directory already found at {:?}", first),
+            Corruption::TooDeep(depth) => write!(f, "nested deeper than {} directories", depth),
+            Corruption::TooManyEntries(n) => write!(f, "more than {} entries listed", n),
+            Corruption::Steps(n) => write!(f, "walk takes more than {} steps", n),
+        }
+    }
+}
+
+/// Inconsistency of the target found while retrieving its state.
+///
+/// Findings do not depend on the model. Any finding fails the retrieval, whatever
+/// the check level, see `FsTestPort::findings`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// "." of directory `dir` does not have the directory's inode number.
+    BadDot {
+        dir: AbsPath,
+        expected: usize,
+        found: usize,
+    },
+    /// ".." of directory `dir` does not have the parent's inode number. The ".."
+    /// of root, or of the root of a mounted file system, is the directory itself.
+    BadDotDot {
+        dir: AbsPath,
+        expected: usize,
+        found: usize,
+    },
+    /// Entry `path` is listed with a `d_type` other than the kind `fstatat` reports.
+    TypeMismatch {
+        path: AbsPath,
+        listed: FileKind,
+        stat: FileKind,
+    },
+    /// Entry `path` is listed with a `d_ino` other than `st_ino`.
+    InoMismatch {
+        path: AbsPath,
+        listed: usize,
+        stat: usize,
+    },
+    /// Directory `dir` lists `name` more than once in a pass.
+    DuplicateEntry { dir: AbsPath, name: String },
+    /// Directory `dir` lists `name` in only one of two passes over it.
+    OmittedEntry { dir: AbsPath, name: String },
+    /// Entry `path` is listed, but `fstatat` or `openat` on it gives `errno`.
+    Unopenable { path: AbsPath, errno: isize },
+    /// Directory `dir` lists `name`, which is empty or contains '/' or NUL.
+    InvalidName { dir: AbsPath, name: String },
+    /// Listing the extended attributes of `path` gives `errno`.
+    UnlistableXattrs { path: AbsPath, errno: isize },
+    /// Reading extended attribute `name` of `path` gives `errno`.
+    UnreadableXattr {
+        path: AbsPath,
+        name: String,
+        errno: isize,
+    },
+    /// Inode at `path` reports `nlink`, but `found` links to it are found.
+    NlinkMismatch {
+        path: AbsPath,
+        nlink: usize,
+        found: usize,
+    },
+    /// Entry `path` of kind `kind` has inode number `ino`, already found at
+    /// `first` with kind `first_kind`.
+    InoReused {
+        path: AbsPath,
+        first: AbsPath,
+        ino: usize,
+        kind: FileKind,
+        first_kind: FileKind,
+    },
+    /// The target directory graph is corrupt at `path`, the walk does not go
+    /// on there.
+    CorruptGraph {
+        path: AbsPath,
+        corruption: Corruption,
+    },
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Finding::BadDot {
+                dir,
+                expected,
+                found,
+            } => write!(
+                f,
+                "\".\" of {:?} has ino {}, expected {}",
+                dir, found, expected
+            ),
+            Finding::BadDotDot {
+                dir,
+                expected,
+                found,
+            } => write!(
+                f,
+                "\"..\" of {:?} has ino {}, expected {}",
+                dir, found, expected
+            ),
+            Finding::TypeMismatch { path, listed, stat } => write!(
+                f,
+                "{:?} listed as {:?}, but stat reports {:?}",
+                path, listed, stat
+            ),
+            Finding::InoMismatch { path, listed, stat } => write!(
+                f,
+                "{:?} listed with ino {}, but stat reports {}",
+                path, listed, stat
+            ),
+            Finding::DuplicateEntry { dir, name } => {
+                write!(f, "duplicate entry {} in {:?}", name, dir)
+            }
+            Finding::OmittedEntry { dir, name } => {
+                write!(
+                    f,
+                    "entry {} in {:?} listed in only one of two passes",
+                    name, dir
+                )
+            }
+            Finding::Unopenable { path, errno } => {
+                write!(f, "listed but unopenable: {:?} ({})", path, errno)
+            }
+            Finding::InvalidName { dir, name } => {
+                write!(f, "listed name {:?} in {:?} contains '/' or NUL", name, dir)
+            }
+            Finding::UnlistableXattrs { path, errno } => {
+                write!(f, "cannot list xattrs of {:?} ({})", path, errno)
+            }
+            Finding::UnreadableXattr { path, name, errno } => {
+                write!(f, "cannot read xattr {} of {:?} ({})", name, path, errno)
+            }
+            Finding::NlinkMismatch { path, nlink, found } => write!(
+                f,
+                "{:?} has nlink {}, but {} links found",
+                path, nlink, found
+            ),
+            Finding::InoReused {
+                path,
+                first,
+                ino,
+                kind,
+                first_kind,
+            } => write!(
+                f,
+                "{:?} is a {:?} with ino {}, already found at {:?} as a {:?}",
+                path, kind, ino, first, first_kind
+            ),
+            Finding::CorruptGraph { path, corruption } => write!(
+                f,
+                "target directory graph is corrupt at {:?}: {}",
+                path, corruption
+            ),
+        }
+    }
+}
@@ -1,46 +1,109 @@
+use crate::acl::{MAY_EXEC, MAY_READ, MAY_WRITE};
+use crate::content::{FileData, EMPTY_DATA, SECTORS_PER_BLOCK};
+use crate::dirstream::DirStream;
+use crate::durability::CrashOrdering;
 use crate::error::FsError;
-use crate::inode::Inode;
+use crate::image::{Entry, Image};
+use crate::inode::{Inode, MODE_SETGID};
+use crate::inode_table::{dir_blocks, InodeTable};
+use crate::lock::LockTable;
+use crate::mmap::{Mapping, MappingTable, PAGE_SIZE};
 use crate::path::AbsPath;
+use crate::pipe::PipeBuffer;
+use crate::scope::Scope;
+use crate::snapshot::{SavedState, SNAPSHOT_VERSION};
 use km_checker::AbstractState;
-use km_command::fs::{FileKind, FileMode, OpenFlags, Path};
+use km_command::fs::{
+    AtFlags, FallocFlags, FileKind, FileMode, FlockFlags, LockKind, MapFlags, MsyncFlags,
+    OpenFlags, Path, ProtFlags, Whence,
+};
 use multi_key_map::MultiKeyMap;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
+use std::ops::Range;
 use std::rc::Rc;
 use std::usize;
 
 /// File descriptor reference file type.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FdRefType {
     /// An existing file, noted by an absolute path.
     Permanent(AbsPath),
     /// A temporary file, noted by an index in the temporary inode list.
     Temporary(usize),
+    /// An end of an anonymous pipe, noted by the pipe inode number.
+    Pipe(usize),
+    /// Standard input, output or error, which are not modeled.
+    Stdio,
 }
 
 /// File descriptor table entry.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileDescriptor {
+    /// Id of the open file description, owning its `flock` locks. Unlike the
+    /// address, it is kept across serialization and never reused.
+    id: usize,
     fref: FdRefType,
+    #[serde(with = "crate::snapshot::open_flags")]
     flags: OpenFlags,
+    /// File offset, for regular files.
+    offset: usize,
+    /// Directory stream, for directories.
+    dir: DirStream,
 }
 
 impl FileDescriptor {
     /// Create a file descriptor, which refers to a permanent file.
-    pub fn new_perm(path: AbsPath, flags: OpenFlags) -> Self {
+    pub fn new_perm(id: usize, path: AbsPath, flags: OpenFlags) -> Self {
         Self {
+            id,
             fref: FdRefType::Permanent(path),
             flags,
+            offset: 0,
+            dir: DirStream::default(),
         }
     }
     /// Create a file descriptor, which refers to a temporary file.
-    pub fn new_tmp(idx: usize, flags: OpenFlags) -> Self {
+    pub fn new_tmp(id: usize, idx: usize, flags: OpenFlags) -> Self {
         Self {
+            id,
             fref: FdRefType::Temporary(idx),
             flags,
+            offset: 0,
+            dir: DirStream::default(),
         }
     }
+    /// Create a file descriptor, which refers to an end of pipe `ino`.
+    pub fn new_pipe(id: usize, ino: usize, flags: OpenFlags) -> Self {
+        Self {
+            id,
+            fref: FdRefType::Pipe(ino),
+            flags,
+            offset: 0,
+            dir: DirStream::default(),
+        }
+    }
+    /// Create a file descriptor for standard input, output or error.
+    pub fn new_stdio(id: usize) -> Self {
+        Self {
+            id,
+            fref: FdRefType::Stdio,
+            flags: OpenFlags::RDWR,
+            offset: 0,
+            dir: DirStream::default(),
+        }
+    }
+    /// Check if the file is opened for reading.
+    pub fn readable(&self) -> bool {
+        !self.flags.contains(OpenFlags::WRONLY)
+    }
+    /// Check if the file is opened for writing.
+    pub fn writable(&self) -> bool {
+        self.flags.intersects(OpenFlags::WRONLY | OpenFlags::RDWR)
+            && !self.flags.contains(OpenFlags::WRONLY | OpenFlags::RDWR)
+    }
 }
 
 /// File descriptor table size.
@@ -49,16 +112,62 @@ pub const FD_TABLE_SIZE: usize = 256;
 /// Special file descriptor representing the current working directory.
 pub const FDCWD: isize = -100;
 
+/// Default file mode creation mask, the same as Linux gives to `init` (022).
+pub const DEFAULT_UMASK: FileMode = FileMode::GROUP_WRITE.union(FileMode::OTHER_WRITE);
+
+/// Number of directory entries a directory block holds, "." and ".." included.
+///
+/// A rough estimate, real file systems pack entries by name length. Only exact
+/// enough to run out of blocks, see `FileSystem::check_free_blocks`.
+pub const DIRENTS_PER_BLOCK: usize = 64;
+
+/// Size of a data block of regular files.
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Capacity limits of the root file system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capacity {
+    /// Maximum number of inodes.
+    pub inodes: usize,
+    /// Maximum number of blocks.
+    pub blocks: usize,
+}
+
+/// Free space of the root file system, as reported by `statfs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FreeSpace {
+    /// Free inodes.
+    pub inodes: usize,
+    /// Free blocks.
+    pub blocks: usize,
+}
+
+/// File descriptors and memory mappings referring to an inode.
+struct InodeUsers {
+    /// File descriptors.
+    fds: Vec<isize>,
+    /// Start addresses of memory mappings.
+    mappings: Vec<usize>,
+}
+
+impl InodeUsers {
+    /// Check if nothing refers to the inode.
+    fn is_empty(&self) -> bool {
+        self.fds.is_empty() && self.mappings.is_empty()
+    }
+}
+
 /// Abstract state of the file system.
-#[derive(Clone)]
 pub struct FileSystem {
     /// User ID.
     uid: u32,
     /// Group ID.
     gid: u32,
+    /// File mode creation mask.
+    umask: FileMode,
     /// Inodes. An inode may have multiple absolutes paths (hard links).
     /// Each key is corresponding to an absolute path.
-    inodes: MultiKeyMap<AbsPath, Inode>,
+    inodes: InodeTable,
     /// Current working directory.
     cwd: AbsPath,
     /// File descriptor table.
@@ -68,20 +177,139 @@ pub struct FileSystem {
     tmp_inodes: HashMap<usize, Inode>,
     /// Next temporary inode index.
     tmp_idx: usize,
+    /// Next open file description id.
+    ofd_idx: usize,
+    /// Temporary inodes created by `O_TMPFILE` without `O_EXCL`, which may be
+    /// given a name by `linkat` with `AT_EMPTY_PATH`.
+    linkable: HashSet<usize>,
+    /// Inodes of anonymous pipes, by inode number.
+    pipes: HashMap<usize, Inode>,
+    /// Buffers of anonymous pipes and opened FIFOs, by inode number.
+    pipe_bufs: HashMap<usize, PipeBuffer>,
+    /// Mount table, mapping mount points to the device number of the mounted
+    /// file system. Inodes of different file systems are told apart by `Inode::dev`.
+    mounts: BTreeMap<AbsPath, u64>,
+    /// Mount points found on the target. Only set for retrieved states.
+    found_mounts: BTreeMap<AbsPath, u64>,
+    /// Capacity limits. `None` means infinite space.
+    capacity: Option<Capacity>,
+    /// Free space reported by the target. Only set for retrieved states.
+    reported_space: Option<FreeSpace>,
+    /// Allowed difference of free blocks of the root file system, `None` if not
+    /// checked.
+    free_blocks_tolerance: Option<usize>,
+    /// Allowed difference of `st_blocks` of regular files, `None` if not checked.
+    blocks_tolerance: Option<usize>,
+    /// Advisory locks, `flock` and POSIX record locks.
+    locks: LockTable,
+    /// Contents of regular files, by inode number. The content may extend past
+    /// the file size within the last page, by writes through shared mappings.
+    contents: HashMap<usize, FileData>,
+    /// Regular files of unknown content, by inode number, e.g. after a crash. Bytes
+    /// read from them are not checked.
+    unknown_contents: HashSet<usize>,
+    /// File-backed memory mappings.
+    mappings: MappingTable,
+    /// Whether the target has crashed and the recovered state is not yet known.
+    recovering: bool,
+    /// Part of the target the model runs in. Absolute paths of commands are target
+    /// paths below its root.
+    scope: Scope,
 }
 
 impl AbstractState for FileSystem {
     fn matches(&self, other: &Self) -> bool {
+        let inodes_match = if self.recovering {
+            self.inodes.allows(&other.inodes)
+        } else if other.recovering {
+            other.inodes.allows(&self.inodes)
+        } else {
+            self.inodes == other.inodes
+        };
         self.cwd == other.cwd
             && self.uid == other.uid
             && self.gid == other.gid
-            && self.inodes == other.inodes
+            && inodes_match
+            && self.mounts_match(other)
+            && self.blocks_match(other)
+            && match (self.free_space(), other.free_space()) {
+                // Space usage after a crash is unknown.
+                _ if self.recovering || other.recovering => true,
+                (Some(a), Some(b)) => {
+                    a.inodes == b.inodes
+                        && match self.free_blocks_tolerance.or(other.free_blocks_tolerance) {
+                            Some(tolerance) => a.blocks.abs_diff(b.blocks) <= tolerance,
+                            None => true,
+                        }
+                }
+                _ => true,
+            }
     }
     fn update(&mut self, other: &Self) {
+        let old_inodes = self.inodes.update(&other.inodes);
         self.cwd = other.cwd.clone();
         self.uid = other.uid;
         self.gid = other.gid;
-        self.inodes = other.inodes.clone();
+        // Inodes now carry the device numbers of the target, renumber the mount table
+        // to match. Mount points themselves are never taken from the target.
+        for (path, dev) in self.mounts.iter_mut() {
+            if let Some(found) = other.found_mounts.get(path) {
+                *dev = *found;
+            }
+        }
+        self.keep_inos(&old_inodes);
+        self.drop_stale_contents();
+        if self.recovering {
+            // The recovered state is persisted.
+            self.inodes.sync();
+            self.recovering = false;
+        }
+    }
+}
+
+/// A deep copy: open file descriptions are copied rather than shared with the
+/// original, still shared between the fds of the copy.
+impl Clone for FileSystem {
+    fn clone(&self) -> Self {
+        const NONE_FD: Option<Rc<RefCell<FileDescriptor>>> = None;
+        let mut fd_table = [NONE_FD; FD_TABLE_SIZE];
+        // Copies of the open file descriptions, by address of the original.
+        let mut copies: HashMap<*const RefCell<FileDescriptor>, Rc<RefCell<FileDescriptor>>> =
+            HashMap::new();
+        for (slot, desc) in fd_table.iter_mut().zip(&self.fd_table) {
+            if let Some(desc) = desc {
+                let copy = copies
+                    .entry(Rc::as_ptr(desc))
+                    .or_insert_with(|| Rc::new(RefCell::new(desc.borrow().clone())));
+                *slot = Some(copy.clone());
+            }
+        }
+        Self {
+            uid: self.uid,
+            gid: self.gid,
+            umask: self.umask,
+            inodes: self.inodes.clone(),
+            cwd: self.cwd.clone(),
+            fd_table,
+            tmp_inodes: self.tmp_inodes.clone(),
+            tmp_idx: self.tmp_idx,
+            ofd_idx: self.ofd_idx,
+            linkable: self.linkable.clone(),
+            pipes: self.pipes.clone(),
+            pipe_bufs: self.pipe_bufs.clone(),
+            mounts: self.mounts.clone(),
+            found_mounts: self.found_mounts.clone(),
+            capacity: self.capacity,
+            reported_space: self.reported_space,
+            free_blocks_tolerance: self.free_blocks_tolerance,
+            blocks_tolerance: self.blocks_tolerance,
+            locks: self.locks.clone(),
+            contents: self.contents.clone(),
+            unknown_contents: self.unknown_contents.clone(),
+            mappings: self.mappings.clone(),
+            recovering: self.recovering,
+            scope: self.scope.clone(),
+        }
     }
 }
 
@@ -91,6 +319,18 @@ impl Debug for FileSystem {
         f.write_fmt(format_args!("  cwd: {:?}\n", self.cwd))?;
         f.write_fmt(format_args!("  uid: {}\n", self.uid))?;
         f.write_fmt(format_args!("  gid: {}\n", self.gid))?;
+        f.write_fmt(format_args!("  <Not Checked> umask: {:?}\n", self.umask))?;
+        f.write_fmt(format_args!(
+            "  mount points: {:?}\n",
+            self.mounts.keys().collect::<Vec<_>>()
+        ))?;
+        if !self.found_mounts.is_empty() {
+            f.write_fmt(format_args!(
+                "  found mount points: {:?}\n",
+                self.found_mounts.keys().collect::<Vec<_>>()
+            ))?;
+        }
+        f.write_fmt(format_args!("  free space: {:?}\n", self.free_space()))?;
         f.write_str("Directory structure:\n")?;
         let mut paths: Vec<_> = self.inodes.keys().collect();
         paths.sort();
@@ -111,6 +351,29 @@ impl Debug for FileSystem {
         for (i, inode) in self.tmp_inodes.iter() {
             f.write_fmt(format_args!("[{}]\t {:?}\n", i, inode))?
         }
+        f.write_str("<Not Checked> Pipe buffers:\n")?;
+        for (ino, buf) in self.pipe_bufs.iter() {
+            f.write_fmt(format_args!("[{}]\t {} bytes\n", ino, buf.len()))?
+        }
+        f.write_str("<Not Checked> Memory mappings:\n")?;
+        for mapping in self.mappings.iter() {
+            f.write_fmt(format_args!(
+                "[{:#x}, {:#x})\t {:?} offset {:#x} {}{} {}\n",
+                mapping.start,
+                mapping.end(),
+                mapping.fref,
+                mapping.offset,
+                if mapping.readable { "r" } else { "-" },
+                if mapping.writable { "w" } else { "-" },
+                if mapping.shared { "shared" } else { "private" },
+            ))?
+        }
+        f.write_fmt(format_args!("<Not Checked> Locks: {:?}\n", self.locks))?;
+        f.write_fmt(format_args!(
+            "<Not Checked> Recovering: {}, durability: {:?}\n",
+            self.recovering,
+            self.inodes.durability()
+        ))?;
         Ok(())
     }
 }
@@ -122,11 +385,28 @@ impl FileSystem {
         Self {
             uid,
             gid,
-            inodes,
+            umask: DEFAULT_UMASK,
+            inodes: InodeTable::new(inodes),
             cwd,
             fd_table: [NONE_FD; FD_TABLE_SIZE],
             tmp_inodes: HashMap::new(),
             tmp_idx: 0,
+            ofd_idx: 0,
+            linkable: HashSet::new(),
+            pipes: HashMap::new(),
+            pipe_bufs: HashMap::new(),
+            mounts: BTreeMap::new(),
+            found_mounts: BTreeMap::new(),
+            capacity: None,
+            reported_space: None,
+            free_blocks_tolerance: None,
+            blocks_tolerance: None,
+            locks: LockTable::default(),
+            contents: HashMap::new(),
+            unknown_contents: HashSet::new(),
+            mappings: MappingTable::default(),
+            recovering: false,
+            scope: Scope::default(),
         }
     }
 
@@ -141,31 +421,470 @@ impl FileSystem {
         let mut fs = Self {
             uid,
             gid,
-            inodes: MultiKeyMap::new(),
+            umask: DEFAULT_UMASK,
+            inodes: InodeTable::new(MultiKeyMap::new()),
             cwd,
             fd_table,
             tmp_inodes: HashMap::new(),
             tmp_idx: 0,
+            ofd_idx: 0,
+            linkable: HashSet::new(),
+            pipes: HashMap::new(),
+            pipe_bufs: HashMap::new(),
+            mounts: BTreeMap::new(),
+            found_mounts: BTreeMap::new(),
+            capacity: None,
+            reported_space: None,
+            free_blocks_tolerance: None,
+            blocks_tolerance: None,
+            locks: LockTable::default(),
+            contents: HashMap::new(),
+            unknown_contents: HashSet::new(),
+            mappings: MappingTable::default(),
+            recovering: false,
+            scope: Scope::default(),
         };
         // Initialize root directory. The `nlink` of the root directory is 2
         // ("." and ".."), which also matches the initialization of the inode.
-        fs.inodes.insert(
-            AbsPath::root(),
-            Inode::new(FileMode::all(), uid, gid, FileKind::Directory),
-        );
+        let mut root = Inode::new(FileMode::all(), uid, gid, FileKind::Directory);
+        root.ino = 1;
+        fs.inodes.insert(AbsPath::root(), root);
+        fs.inodes.sync();
         fs
     }
 
+    /// Create a file system holding the inodes and links of `image`.
+    ///
+    /// The root takes the mode and owner of the root entry of the image, if any.
+    /// Each entry needs its parent directory before it, and each link its target.
+    /// Regular files of an image of unknown contents are marked as such, and bytes
+    /// read from them are not checked.
+    pub fn from_image(image: &Image, uid: u32, gid: u32) -> Result<Self, FsError> {
+        let mut fs = Self::new_root(uid, gid);
+        for entry in image.entries() {
+            match entry {
+                Entry::Inode { path, inode, data } if path.is_root() => {
+                    let root = fs.inodes.get_mut(path).unwrap();
+                    root.mode = inode.mode;
+                    root.uid = inode.uid;
+                    root.gid = inode.gid;
+                    root.xattrs = inode.xattrs.clone();
+                    debug_assert!(data.is_empty());
+                }
+                Entry::Inode { path, inode, data } => {
+                    let parent = path.parent().unwrap();
+                    if !fs.is_dir(&parent) {
+                        return Err(FsError::NotFound);
+                    }
+                    if fs.exists(path) {
+                        return Err(FsError::AlreadyExists);
+                    }
+                    let is_file = inode.is_file();
+                    let size = inode.size.max(data.len());
+                    let inode = Inode {
+                        nlink: if inode.is_dir() { 2 } else { 1 },
+                        dev: 0,
+                        ino: fs.alloc_ino(),
+                        size: if is_file { 0 } else { inode.size },
+                        blocks: 0,
+                        ..inode.clone()
+                    };
+                    let ino = inode.ino;
+                    fs.inodes.insert(path.clone(), inode);
+                    if is_file {
+                        let fref = FdRefType::Permanent(path.clone());
+                        fs.modify_data(&fref, size, |d| d.write(0, data))?;
+                        if image.unknown_contents() && size > 0 {
+                            fs.unknown_contents.insert(ino);
+                        }
+                    }
+                }
+                Entry::Link { path, target } => {
+                    let parent = path.parent().ok_or(FsError::AlreadyExists)?;
+                    if !fs.is_dir(&parent) || !fs.exists(target) {
+                        return Err(FsError::NotFound);
+                    }
+                    if fs.exists(path) {
+                        return Err(FsError::AlreadyExists);
+                    }
+                    fs.inodes.link(target, path.clone());
+                }
+            }
+        }
+        fs.inodes.sync();
+        Ok(fs)
+    }
+
+    /// Serialize the full state as JSON, e.g. to save a checkpoint or attach it to
+    /// a bug report. See `SNAPSHOT_VERSION`.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Load a state serialized by `to_json`.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Get the serialized form of the full state.
+    ///
+    /// Open file descriptions are numbered in the order of their lowest fd.
+    pub(crate) fn save(&self) -> SavedState {
+        let mut descriptions: Vec<&Rc<RefCell<FileDescriptor>>> = Vec::new();
+        let mut fds = Vec::new();
+        for (fd, desc) in self.fd_table.iter().enumerate() {
+            let Some(desc) = desc else {
+                continue;
+            };
+            let idx = match descriptions.iter().position(|&d| Rc::ptr_eq(d, desc)) {
+                Some(idx) => idx,
+                None => {
+                    descriptions.push(desc);
+                    descriptions.len() - 1
+                }
+            };
+            fds.push((fd, idx));
+        }
+        SavedState {
+            version: SNAPSHOT_VERSION,
+            uid: self.uid,
+            gid: self.gid,
+            umask: self.umask,
+            inodes: (*self.inodes).clone(),
+            durability: self.inodes.durability().clone(),
+            cwd: self.cwd.clone(),
+            descriptions: descriptions.iter().map(|d| d.borrow().clone()).collect(),
+            fds,
+            tmp_inodes: self.tmp_inodes.clone().into_iter().collect(),
+            tmp_idx: self.tmp_idx,
+            ofd_idx: self.ofd_idx,
+            linkable: self.linkable.iter().copied().collect(),
+            pipes: self.pipes.clone().into_iter().collect(),
+            pipe_bufs: self.pipe_bufs.clone().into_iter().collect(),
+            mounts: self.mounts.clone(),
+            found_mounts: self.found_mounts.clone(),
+            capacity: self.capacity,
+            reported_space: self.reported_space,
+            free_blocks_tolerance: self.free_blocks_tolerance,
+            blocks_tolerance: self.blocks_tolerance,
+            locks: self.locks.clone(),
+            contents: self.contents.clone().into_iter().collect(),
+            unknown_contents: self.unknown_contents.iter().copied().collect(),
+            mappings: self.mappings.clone(),
+            recovering: self.recovering,
+            scope: self.scope.clone(),
+        }
+    }
+
+    /// Rebuild the state from its serialized form.
+    pub(crate) fn load(saved: SavedState) -> Result<Self, String> {
+        const NONE_FD: Option<Rc<RefCell<FileDescriptor>>> = None;
+        let descriptions: Vec<_> = saved
+            .descriptions
+            .into_iter()
+            .map(|desc| Rc::new(RefCell::new(desc)))
+            .collect();
+        let mut fd_table = [NONE_FD; FD_TABLE_SIZE];
+        for (fd, idx) in saved.fds {
+            let desc = descriptions
+                .get(idx)
+                .ok_or_else(|| format!("fd {} refers to missing description {}", fd, idx))?;
+            let slot = fd_table
+                .get_mut(fd)
+                .ok_or_else(|| format!("fd {} out of range", fd))?;
+            *slot = Some(desc.clone());
+        }
+        Ok(Self {
+            uid: saved.uid,
+            gid: saved.gid,
+            umask: saved.umask,
+            inodes: InodeTable::with_durability(saved.inodes, saved.durability),
+            cwd: saved.cwd,
+            fd_table,
+            tmp_inodes: saved.tmp_inodes.into_iter().collect(),
+            tmp_idx: saved.tmp_idx,
+            ofd_idx: saved.ofd_idx,
+            linkable: saved.linkable.into_iter().collect(),
+            pipes: saved.pipes.into_iter().collect(),
+            pipe_bufs: saved.pipe_bufs.into_iter().collect(),
+            mounts: saved.mounts,
+            found_mounts: saved.found_mounts,
+            capacity: saved.capacity,
+            reported_space: saved.reported_space,
+            free_blocks_tolerance: saved.free_blocks_tolerance,
+            blocks_tolerance: saved.blocks_tolerance,
+            locks: saved.locks,
+            contents: saved.contents.into_iter().collect(),
+            unknown_contents: saved.unknown_contents.into_iter().collect(),
+            mappings: saved.mappings,
+            recovering: saved.recovering,
+            scope: saved.scope,
+        })
+    }
+
+    /// Mount an empty file system on directory `path`.
+    ///
+    /// The mounted file system gets a fresh device number. Mounting is only modelled
+    /// on empty directories, so no entries are hidden by the mount. Only root may
+    /// mount.
+    ///
+    /// Inodes of all file systems share one namespace keyed by path, each tagged with
+    /// the device number of its file system. File descriptors opened on the mount point
+    /// keep referring to the covered directory, which is no longer reachable by path.
+    /// The working directory is kept as a path, so unlike Linux it moves to the root
+    /// of the mounted file system.
+    pub fn mount(&mut self, path: AbsPath) -> Result<(), FsError> {
+        if self.uid != 0 {
+            return Err(FsError::NotPermitted);
+        }
+        if !self.exists(&path) {
+            return Err(FsError::NotFound);
+        }
+        if !self.is_dir(&path) {
+            return Err(FsError::NotDirectory);
+        }
+        if self.mounts.contains_key(&path) || !self.is_empty_dir(&path) {
+            return Err(FsError::Busy);
+        }
+        let dev = self
+            .inodes
+            .keys()
+            .map(|k| self.inodes.get(k).unwrap().dev)
+            .max()
+            .unwrap()
+            + 1;
+        // The root of the mounted file system covers the mount point.
+        let mut root = Inode::new(FileMode::all(), self.uid, self.gid, FileKind::Directory);
+        root.dev = dev;
+        root.ino = self.alloc_ino();
+        let related_fds = self.all_fds_ref_same_inode(&FdRefType::Permanent(path.clone()));
+        let covered = self.inodes.replace(&path, root).unwrap();
+        if !related_fds.is_empty() {
+            for fd in related_fds {
+                self.fd_table[fd as usize]
+                    .as_mut()
+                    .unwrap()
+                    .borrow_mut()
+                    .fref = FdRefType::Temporary(self.tmp_idx);
+            }
+            self.tmp_inodes.insert(self.tmp_idx, covered);
+            self.tmp_idx += 1;
+        }
+        self.mounts.insert(path, dev);
+        Ok(())
+    }
+
+    /// Check the mount table of the model against the mount points found on the
+    /// target `other`.
+    ///
+    /// Both must have the same mount points. Device numbers are assigned independently,
+    /// so each file system of the model must correspond to one distinct device of the
+    /// target, both at the mount points and for every inode.
+    fn mounts_match(&self, other: &Self) -> bool {
+        if !self.mounts.keys().eq(other.found_mounts.keys()) {
+            return false;
+        }
+        let root = AbsPath::root();
+        let mut devs = HashMap::new();
+        if let (Some(a), Some(b)) = (self.inodes.get(&root), other.inodes.get(&root)) {
+            devs.insert(a.dev, b.dev);
+        }
+        for (path, dev) in &self.mounts {
+            devs.insert(*dev, other.found_mounts[path]);
+        }
+        let target_devs: HashSet<_> = devs.values().collect();
+        target_devs.len() == devs.len()
+            && self.inodes.keys().all(|path| {
+                match (self.inodes.get(path), other.inodes.get(path)) {
+                    (Some(a), Some(b)) => devs.get(&a.dev) == Some(&b.dev),
+                    _ => true,
+                }
+            })
+    }
+
+    /// Record a mount point found on the target, with the device number of the
+    /// mounted file system.
+    pub(crate) fn record_mount(&mut self, path: AbsPath, dev: u64) {
+        self.found_mounts.insert(path, dev);
+    }
+
+    /// Check if `path` is a mount point.
+    pub fn is_mount_point(&self, path: &AbsPath) -> bool {
+        self.mounts.contains_key(path)
+    }
+
+    /// Limit the number of inodes and blocks of the root file system.
+    ///
+    /// Choose the limits so that the free counts at start equal the target's.
+    pub fn set_capacity(&mut self, capacity: Capacity) {
+        self.capacity = Some(capacity);
+    }
+
+    /// Compare free blocks of the root file system, allowing a difference of
+    /// `tolerance` blocks. Only free inodes are compared by default, as block usage
+    /// of directories and metadata is only estimated.
+    pub fn check_free_blocks(&mut self, tolerance: usize) {
+        self.free_blocks_tolerance = Some(tolerance);
+    }
+
+    /// Compare `st_blocks` of regular files, allowing a difference of `tolerance`
+    /// 512-byte units, e.g. for indirect blocks or preallocation of the file
+    /// system under test.
+    pub fn check_blocks(&mut self, tolerance: usize) {
+        self.blocks_tolerance = Some(tolerance);
+    }
+
+    /// Check if `st_blocks` of regular files match within the tolerance.
+    fn blocks_match(&self, other: &Self) -> bool {
+        let tolerance = match self.blocks_tolerance.or(other.blocks_tolerance) {
+            // Allocation after a crash is unknown.
+            Some(_) if self.recovering || other.recovering => return true,
+            Some(tolerance) => tolerance,
+            None => return true,
+        };
+        self.inodes.keys().all(|path| {
+            let inode = self.inodes.get(path).unwrap();
+            match other.inodes.get(path) {
+                Some(other) if inode.is_file() => inode.blocks.abs_diff(other.blocks) <= tolerance,
+                _ => true,
+            }
+        })
+    }
+
+    /// Run the model inside `scope` of the target, the same scope as the test port.
+    ///
+    /// Model paths are mapped below the scope root: absolute paths of commands are
+    /// target paths, and the commander generates them below the root.
+    pub fn set_scope(&mut self, scope: Scope) {
+        self.scope = scope;
+    }
+
+    /// Get the part of the target the model runs in.
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    /// Get the directory of the target the model runs in.
+    pub fn test_root(&self) -> &AbsPath {
+        &self.scope.root
+    }
+
+    /// Map absolute target path `path` to a model path. Paths outside the test
+    /// root, or in ignored subtrees, are not modelled.
+    pub(crate) fn model_path(&self, path: AbsPath) -> Result<AbsPath, FsError> {
+        match path.strip(&self.scope.root) {
+            Some(path) if !self.scope.is_ignored(&path) => Ok(path),
+            _ => Err(FsError::InvalidPath),
+        }
+    }
+
+    /// Set the ordering guarantees of the file system under test, the current
+    /// state is taken as persisted.
+    pub fn set_crash_ordering(&mut self, ordering: CrashOrdering) {
+        self.inodes.set_crash_ordering(ordering);
+    }
+
+    /// Record the free space reported by the target.
+    pub(crate) fn record_free_space(&mut self, space: FreeSpace) {
+        self.reported_space = Some(space);
+    }
+
+    /// Get free space of the root file system, `None` if space is unlimited.
+    pub fn free_space(&self) -> Option<FreeSpace> {
+        if self.reported_space.is_some() {
+            return self.reported_space;
+        }
+        let capacity = self.capacity?;
+        let (inodes, blocks) = self.usage();
+        Some(FreeSpace {
+            inodes: capacity.inodes.saturating_sub(inodes),
+            blocks: capacity.blocks.saturating_sub(blocks),
+        })
+    }
+
     /// Open `stdin`, `stdout` and `stderr`.
     pub fn open_stdio(&mut self) {
         for i in 0..3 {
-            self.fd_table[i] = Some(Rc::new(RefCell::new(FileDescriptor::new_tmp(
-                usize::MAX,
-                OpenFlags::empty(),
-            ))))
+            let id = self.alloc_ofd_id();
+            self.fd_table[i] = Some(Rc::new(RefCell::new(FileDescriptor::new_stdio(id))))
         }
     }
 
+    /// Flush the inode referred to by `fd` to disk (`fsync`, `fdatasync`).
+    pub fn fsync(&mut self, fd: isize) -> Result<(), FsError> {
+        let fref = self.fd_ref(fd)?;
+        match fref {
+            FdRefType::Permanent(path) => self.inodes.fsync(&path),
+            // Unlinked inodes are gone after a crash anyway.
+            FdRefType::Temporary(_) => (),
+            FdRefType::Pipe(_) | FdRefType::Stdio => return Err(FsError::InvalidArgument),
+        }
+        Ok(())
+    }
+
+    /// Flush the file system containing the inode referred to by `fd` (`syncfs`).
+    pub fn syncfs(&mut self, fd: isize) -> Result<(), FsError> {
+        self.get_fd(fd)?;
+        self.sync();
+        Ok(())
+    }
+
+    /// Flush all file systems (`sync`).
+    pub fn sync(&mut self) {
+        self.inodes.sync();
+    }
+
+    /// Check if the target has crashed and the recovered state is not yet known.
+    pub fn recovering(&self) -> bool {
+        self.recovering
+    }
+
+    /// Crash and restart the target, by reboot or remount.
+    ///
+    /// The process starts over, so open files, pipes, locks and the working
+    /// directory are gone. The namespace recovered by the target is checked by
+    /// `matches` against the states allowed by the durability tracking.
+    pub fn crash(&mut self) {
+        self.recovering = true;
+        const NONE_FD: Option<Rc<RefCell<FileDescriptor>>> = None;
+        self.fd_table = [NONE_FD; FD_TABLE_SIZE];
+        self.open_stdio();
+        self.tmp_inodes.clear();
+        self.linkable.clear();
+        self.pipes.clear();
+        self.pipe_bufs.clear();
+        self.locks = LockTable::default();
+        self.mappings.clear();
+        // File data is not tracked for durability, so the content of every file
+        // is unknown after the crash.
+        let files: Vec<usize> = self
+            .inodes
+            .keys()
+            .map(|k| self.inodes.get(k).unwrap())
+            .filter(|inode| inode.is_file())
+            .map(|inode| inode.ino)
+            .collect();
+        self.unknown_contents.extend(files);
+        self.drop_stale_contents();
+        self.cwd = AbsPath::root();
+        self.umask = DEFAULT_UMASK;
+    }
+
+    /// Set the file mode creation mask, returning the previous one.
+    pub fn umask(&mut self, mask: FileMode) -> FileMode {
+        std::mem::replace(&mut self.umask, mask)
+    }
+
+    /// Get the user ID.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Get the group ID.
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
     /// Check if `path` exists.
     pub fn exists(&self, path: &AbsPath) -> bool {
         self.inodes.contains_key(path)
@@ -196,6 +915,8 @@ impl FileSystem {
 
     /// Makr a new name for an inode.
     pub fn link(&mut self, oldpath: &AbsPath, newpath: AbsPath) -> Result<(), FsError> {
+        self.check_search(oldpath)?;
+        self.check_search(&newpath)?;
         if !self.exists(oldpath) {
             return Err(FsError::NotFound);
         }
@@ -211,9 +932,19 @@ impl FileSystem {
         if !self.is_dir(&newpath.parent().unwrap()) {
             return Err(FsError::NotDirectory);
         }
+        // Hard links cannot cross mounted file systems.
+        if self.lookup(oldpath)?.dev != self.lookup(&newpath.parent().unwrap())?.dev {
+            return Err(FsError::CrossDevice);
+        }
+        self.permission(
+            &self.lookup(&newpath.parent().unwrap())?,
+            MAY_WRITE | MAY_EXEC,
+        )?;
+        self.check_space(&newpath.parent().unwrap(), true, None)?;
         // Link the inode.
-        self.inodes.insert_alias(oldpath, newpath);
-        self.increase_nlink(oldpath)
+        self.entry_changed(&newpath);
+        self.inodes.link(oldpath, newpath);
+        Ok(())
     }
 
     /// Delete a name and possibly the inode it refer to
@@ -221,61 +952,57 @@ impl FileSystem {
         if path.is_root() {
             return Err(FsError::InvalidPath);
         }
+        self.check_search(path)?;
         if !self.exists(path) {
             return Err(FsError::NotFound);
         }
+        self.permission(&self.lookup(&path.parent().unwrap())?, MAY_WRITE | MAY_EXEC)?;
+        self.check_sticky(path)?;
         if self.is_dir(path) {
             if !rmdir {
                 return Err(FsError::IsDirectory);
             }
+            if self.is_mount_point(path) {
+                return Err(FsError::Busy);
+            }
             if !self.is_empty_dir(path) {
                 return Err(FsError::DirectoryNotEmpty);
             }
-            // If inode is a directory, update parent link count
-            self.decrease_nlink(&path.parent().unwrap())?;
         } else {
             if rmdir {
                 return Err(FsError::NotDirectory);
             }
         }
+        self.entry_changed(path);
         // Unlink the inode.
-        // Get all fds referring to the inode.
-        let related_fds = self.all_fds_ref_same_inode(&FdRefType::Permanent(path.clone()));
+        // Get all fds and memory mappings referring to the inode.
+        let users = self.users(&FdRefType::Permanent(path.clone()));
         let aliases = self.inodes.aliases(path).unwrap();
         if aliases.len() == 1 {
-            // The inode will be removed. If there are fd pointing to it,
-            // the inode will be collected in `tmp_inodes`.
-            let inode = self.inodes.remove(path).unwrap();
-            if !related_fds.is_empty() {
-                // Some fds pointing to the inode, update their fref.
-                for fd in related_fds {
-                    self.fd_table[fd as usize]
-                        .as_mut()
-                        .unwrap()
-                        .borrow_mut()
-                        .fref = FdRefType::Temporary(self.tmp_idx);
-                }
+            // The inode will be removed, updating the parent link count if it is a
+            // directory. If there are fd or mappings pointing to it, the inode will
+            // be collected in `tmp_inodes`.
+            let mut inode = self.inodes.remove(path).unwrap();
+            if !users.is_empty() {
+                inode.nlink = 0;
+                // Some fds or mappings pointing to the inode, update their fref.
+                self.retarget(&users, FdRefType::Temporary(self.tmp_idx));
                 // Collect the inode in `tmp_inodes`.
                 self.tmp_inodes.insert(self.tmp_idx, inode);
                 self.tmp_idx += 1;
+            } else {
+                self.drop_contents(inode.ino);
             }
         } else {
             // The inode is still referenced by other paths, just remove the alias.
             self.inodes.remove_alias(path).unwrap();
-            self.decrease_nlink(path)?;
-            if !related_fds.is_empty() {
-                // Some fds pointing to the inode, update their fref.
+            if !users.is_empty() {
+                // Some fds or mappings pointing to the inode, update their fref.
                 let another_path = aliases
                     .into_iter()
                     .find(|p| self.inodes.contains_key(p))
                     .unwrap();
-                for fd in related_fds {
-                    self.fd_table[fd as usize]
-                        .as_mut()
-                        .unwrap()
-                        .borrow_mut()
-                        .fref = FdRefType::Permanent(another_path.clone());
-                }
+                self.retarget(&users, FdRefType::Permanent(another_path));
             }
         }
         Ok(())
@@ -283,6 +1010,18 @@ impl FileSystem {
 
     /// Create an inode by path.
     pub fn create(&mut self, path: AbsPath, kind: FileKind, mode: FileMode) -> Result<(), FsError> {
+        self.create_node(path, kind, mode, 0)
+    }
+
+    /// Create an inode by path, with device number `rdev` for device nodes.
+    fn create_node(
+        &mut self,
+        path: AbsPath,
+        kind: FileKind,
+        mode: FileMode,
+        rdev: u64,
+    ) -> Result<(), FsError> {
+        self.check_search(&path)?;
         if self.exists(&path) {
             return Err(FsError::AlreadyExists);
         }
@@ -292,89 +1031,1235 @@ impl FileSystem {
         if !self.is_dir(&path.parent().unwrap()) {
             return Err(FsError::NotDirectory);
         }
-        // Create the inode.
-        let inode = Inode::new(mode, self.uid, self.gid, kind);
-        self.inodes.insert(path.clone(), inode);
-        // If `inode` is a directory, update parent link count
-        if kind == FileKind::Directory {
-            self.increase_nlink(&path.parent().unwrap())?;
-        }
+        let mut inode = self.new_inode(&path.parent().unwrap(), kind, mode)?;
+        inode.rdev = rdev;
+        self.check_space(&path.parent().unwrap(), true, Some(kind))?;
+        self.entry_changed(&path);
+        // If `inode` is a directory, the parent link count is updated too.
+        self.inodes.insert(path, inode);
         Ok(())
     }
 
-    /// Change the current working directory.
-    pub fn chdir(&mut self, path: AbsPath) -> Result<(), FsError> {
-        if !self.exists(&path) {
+    /// Create an unnamed regular file in directory `dir` (`O_TMPFILE`), returning
+    /// the file descriptor opened with `flags`.
+    ///
+    /// The file must be opened for writing. Without `O_EXCL`, it may later be linked
+    /// into the file system by `linkat` with `AT_EMPTY_PATH`.
+    pub fn create_tmpfile(
+        &mut self,
+        dir: AbsPath,
+        flags: OpenFlags,
+        mode: FileMode,
+    ) -> Result<isize, FsError> {
+        let fd = FileDescriptor::new_tmp(self.alloc_ofd_id(), self.tmp_idx, flags);
+        if flags.contains(OpenFlags::CREAT) || !fd.writable() {
+            return Err(FsError::InvalidArgument);
+        }
+        // The fd is allocated before the path is resolved.
+        if self.fd_table.iter().all(|e| e.is_some()) {
+            return Err(FsError::NoAvailableFd);
+        }
+        self.check_search(&dir)?;
+        if !self.exists(&dir) {
             return Err(FsError::NotFound);
         }
-        if !self.is_dir(&path) {
+        if !self.is_dir(&dir) {
             return Err(FsError::NotDirectory);
         }
-        self.cwd = path;
+        let mut inode = self.new_inode(&dir, FileKind::File, mode)?;
+        inode.nlink = 0;
+        self.check_space(&dir, false, Some(FileKind::File))?;
+        self.tmp_inodes.insert(self.tmp_idx, inode);
+        if !flags.contains(OpenFlags::EXCL) {
+            self.linkable.insert(self.tmp_idx);
+        }
+        self.tmp_idx += 1;
+        self.alloc_fd(Rc::new(RefCell::new(fd)))
+    }
+
+    /// Give a name to the inode referred to by `fref` (`linkat` with `AT_EMPTY_PATH`).
+    ///
+    /// A temporary inode can only be linked if it was created by `O_TMPFILE` without
+    /// `O_EXCL`, other unlinked inodes give `ENOENT`. The linked inode is moved back
+    /// into the file system, and file descriptors referring to it follow.
+    pub fn link_fref(&mut self, fref: &FdRefType, newpath: AbsPath) -> Result<(), FsError> {
+        let idx = match fref {
+            FdRefType::Permanent(oldpath) => return self.link(oldpath, newpath),
+            FdRefType::Temporary(idx) => *idx,
+            // Pipes and terminals live on other file systems.
+            FdRefType::Pipe(_) | FdRefType::Stdio => return Err(FsError::CrossDevice),
+        };
+        self.check_search(&newpath)?;
+        let inode = self.inode(fref)?;
+        if self.exists(&newpath) {
+            return Err(FsError::AlreadyExists);
+        }
+        let parent = newpath.parent().unwrap();
+        if !self.exists(&parent) {
+            return Err(FsError::NotFound);
+        }
+        if !self.is_dir(&parent) {
+            return Err(FsError::NotDirectory);
+        }
+        if inode.dev != self.lookup(&parent)?.dev {
+            return Err(FsError::CrossDevice);
+        }
+        self.permission(&self.lookup(&parent)?, MAY_WRITE | MAY_EXEC)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        if !self.linkable.contains(&idx) {
+            return Err(FsError::NotFound);
+        }
+        self.check_space(&parent, true, None)?;
+        // Move the inode back into the file system.
+        let users = self.users(fref);
+        let mut inode = self.tmp_inodes.remove(&idx).unwrap();
+        inode.nlink = 1;
+        self.entry_changed(&newpath);
+        self.inodes.insert(newpath.clone(), inode);
+        self.linkable.remove(&idx);
+        self.retarget(&users, FdRefType::Permanent(newpath));
         Ok(())
     }
 
-    /// Get all allocated file descriptors.
-    pub fn all_fds(&self) -> Vec<isize> {
-        self.fd_table
-            .iter()
-            .enumerate()
-            .filter(|(_, e)| e.is_some())
-            .map(|(i, _)| i as isize)
-            .collect()
+    /// Create a special file (FIFO, device node or socket) by path.
+    pub fn mknod(
+        &mut self,
+        path: AbsPath,
+        kind: FileKind,
+        mode: FileMode,
+        rdev: u64,
+    ) -> Result<(), FsError> {
+        match kind {
+            // `mknod` cannot create directories.
+            FileKind::Directory => return Err(FsError::NotPermitted),
+            // Creating device nodes requires `CAP_MKNOD`.
+            FileKind::CharDevice | FileKind::BlockDevice if self.uid != 0 => {
+                return Err(FsError::NotPermitted)
+            }
+            _ => (),
+        }
+        let rdev = match kind {
+            FileKind::CharDevice | FileKind::BlockDevice => rdev,
+            _ => 0,
+        };
+        self.create_node(path, kind, mode, rdev)
     }
 
-    /// Get file descriptor by fd.
-    pub fn get_fd(&self, fd: isize) -> Result<Rc<RefCell<FileDescriptor>>, FsError> {
-        if fd < 0 || fd as usize >= self.fd_table.len() {
-            return Err(FsError::FdOutOfRange);
-        } else {
-            self.fd_table[fd as usize].clone().ok_or(FsError::NotOpened)
+    /// Check if an existing inode at `path` can be opened with `flags`.
+    ///
+    /// - A FIFO opened write-only without a reader fails with `ENXIO`. Blocking opens
+    ///   are treated as non-blocking, since the model cannot wait for a peer.
+    /// - The model has no device drivers, so opening a device node fails with `ENXIO`,
+    ///   which is what Linux returns for device numbers without a driver.
+    /// - Sockets cannot be opened.
+    pub fn check_open(&self, path: &AbsPath, flags: OpenFlags) -> Result<(), FsError> {
+        self.check_search(path)?;
+        let inode = self.lookup(path)?;
+        let fd = FileDescriptor::new_perm(self.ofd_idx, path.clone(), flags);
+        if fd.readable() {
+            self.permission(&inode, MAY_READ)?;
+        }
+        if fd.writable() || flags.contains(OpenFlags::TRUNC) {
+            self.permission(&inode, MAY_WRITE)?;
+        }
+        match inode.kind {
+            FileKind::Fifo => {
+                let has_reader = self.all_fds_ref_same_inode(&fd.fref).into_iter().any(|fd| {
+                    self.fd_table[fd as usize]
+                        .as_ref()
+                        .unwrap()
+                        .borrow()
+                        .readable()
+                });
+                if !fd.readable() && !has_reader {
+                    return Err(FsError::NoSuchDevice);
+                }
+                Ok(())
+            }
+            FileKind::CharDevice | FileKind::BlockDevice | FileKind::Socket => {
+                Err(FsError::NoSuchDevice)
+            }
+            _ => Ok(()),
         }
     }
 
-    /// Find the lowest available posistion in the fd table and write `fd` into it.
-    pub fn alloc_fd(&mut self, fd: Rc<RefCell<FileDescriptor>>) -> Result<isize, FsError> {
-        for (i, e) in self.fd_table.iter_mut().enumerate() {
-            if e.is_none() {
-                *e = Some(fd);
-                return Ok(i as isize);
+    /// Open an existing inode at `path` with `flags`. `O_TRUNC` truncates regular files.
+    pub fn open(&mut self, path: &AbsPath, flags: OpenFlags) -> Result<(), FsError> {
+        self.check_open(path, flags)?;
+        let inode = self.lookup(path)?;
+        if inode.is_file() && flags.contains(OpenFlags::TRUNC) {
+            let fref = FdRefType::Permanent(path.clone());
+            self.modify_data(&fref, 0, |data| data.truncate(0))?;
+            self.zap_private_pages(&fref, 0..usize::MAX);
+        }
+        Ok(())
+    }
+
+    /// Create an anonymous pipe, returning the read end and the write end.
+    pub fn pipe(&mut self, flags: OpenFlags) -> Result<(isize, isize), FsError> {
+        if !(flags - (OpenFlags::NONBLOCK | OpenFlags::CLOEXEC)).is_empty() {
+            return Err(FsError::InvalidArgument);
+        }
+        if self.fd_table.iter().filter(|e| e.is_none()).count() < 2 {
+            return Err(FsError::NoAvailableFd);
+        }
+        let mode = FileMode::USER_READ | FileMode::USER_WRITE;
+        let mut inode = Inode::new(mode, self.uid, self.gid, FileKind::Fifo);
+        inode.ino = self.alloc_ino();
+        let ino = inode.ino;
+        self.pipes.insert(ino, inode);
+        self.pipe_bufs.insert(ino, PipeBuffer::default());
+        let flags = flags - OpenFlags::CLOEXEC;
+        let rfd = FileDescriptor::new_pipe(self.alloc_ofd_id(), ino, flags | OpenFlags::RDONLY);
+        let wfd = FileDescriptor::new_pipe(self.alloc_ofd_id(), ino, flags | OpenFlags::WRONLY);
+        let rfd = self.alloc_fd(Rc::new(RefCell::new(rfd)))?;
+        let wfd = self.alloc_fd(Rc::new(RefCell::new(wfd)))?;
+        Ok((rfd, wfd))
+    }
+
+    /// Read up to `count` bytes from `fd`, returning the number of bytes read.
+    ///
+    /// Pipes and FIFOs are read from their buffers, regular files from the file offset.
+    pub fn read(&mut self, fd: isize, count: usize) -> Result<usize, FsError> {
+        let fd = self.get_fd(fd)?;
+        let (fref, offset) = {
+            let fd = fd.borrow();
+            if !fd.readable() {
+                return Err(FsError::WrongAccessMode);
+            }
+            (fd.fref.clone(), fd.offset)
+        };
+        let inode = self.inode(&fref)?.clone();
+        match inode.kind {
+            FileKind::Directory => Err(FsError::IsDirectory),
+            FileKind::Fifo => Ok(self.pipe_read(&fref, inode.ino, count)?.len()),
+            FileKind::File => {
+                let n = self.file_read(&inode, offset, count).len();
+                fd.borrow_mut().offset += n;
+                Ok(n)
             }
+            _ => Err(FsError::NoSuchDevice),
         }
-        Err(FsError::NoAvailableFd)
     }
 
-    /// Free the file descriptor.
-    pub fn free_fd(&mut self, fd: isize) -> Result<(), FsError> {
-        if self.get_fd(fd).is_ok() {
-            let fd = self.fd_table[fd as usize].take().unwrap();
-            let fref = &fd.borrow().fref;
-            if let FdRefType::Temporary(idx) = fref {
-                // If the file descriptor refers to a temporary file, and
-                // there is no other file descriptor referring to the same
-                // inode, then remove the inode.
-                let related_fds = self.all_fds_ref_same_inode(fref);
-                if related_fds.is_empty() {
-                    self.tmp_inodes.remove(idx);
-                }
+    /// Write `buf` to `fd`, returning the number of bytes written.
+    ///
+    /// Writes to regular files extend the file, and may be short if the file system
+    /// is running out of blocks.
+    pub fn write(&mut self, fd: isize, buf: &[u8]) -> Result<usize, FsError> {
+        let fd = self.get_fd(fd)?;
+        let (fref, flags, offset) = {
+            let fd = fd.borrow();
+            if !fd.writable() {
+                return Err(FsError::WrongAccessMode);
             }
-            Ok(())
-        } else {
-            Err(FsError::NotOpened)
+            (fd.fref.clone(), fd.flags, fd.offset)
+        };
+        let inode = self.inode(&fref)?.clone();
+        match inode.kind {
+            FileKind::Fifo => self.pipe_write(&fref, inode.ino, buf),
+            FileKind::File => {
+                let offset = if flags.contains(OpenFlags::APPEND) {
+                    inode.size
+                } else {
+                    offset
+                };
+                let n = self.file_write(&fref, offset, buf)?;
+                fd.borrow_mut().offset = offset + n;
+                Ok(n)
+            }
+            _ => Err(FsError::NoSuchDevice),
         }
     }
 
-    /// Parse `path` argument of fs syscall. For `openat`, `linkat`, `mkdirat` ...
+    /// Read up to `count` entries from directory `fd` (`getdents`), returning the
+    /// number of entries read. See `DirStream` for the entries which may appear.
     ///
-    /// The dirfd argument is used in conjunction with the pathname
-    /// argument as follows:
+    /// Only the number of entries is modeled, the names returned are not checked.
+    /// Names are checked by the walk of the test port, which lists every directory
+    /// of the target with `getdents` and compares the tree with the model.
     ///
-    /// -  If the pathname given in pathname is absolute, then dirfd is
-    ///       ignored.
-    /// -  If the pathname given in pathname is relative and dirfd is the
-    ///       special value AT_FDCWD, then pathname is interpreted relative
-    ///       to the current working directory of the calling process (like
-    ///       open()).
+    /// Reading a removed directory gives `ENOENT`.
+    pub fn getdents(&mut self, fd: isize, count: usize) -> Result<usize, FsError> {
+        let (fd, fref, inode) = self.fd_inode(fd)?;
+        if !inode.is_dir() {
+            return Err(FsError::NotDirectory);
+        }
+        if count == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let names = match &fref {
+            FdRefType::Permanent(path) => self.dir_names(path),
+            _ => return Err(FsError::NotFound),
+        };
+        let n = fd.borrow_mut().dir.read(&names, count);
+        Ok(n)
+    }
+
+    /// Check if reading up to `count` entries from directory `fd` gives a number
+    /// of entries known to the model. Directories changed during the iteration
+    /// may give any number within bounds.
+    pub fn getdents_determined(&self, fd: isize, count: usize) -> bool {
+        let fd = match self.get_fd(fd) {
+            Ok(fd) => fd,
+            Err(_) => return true,
+        };
+        let fd = fd.borrow();
+        match &fd.fref {
+            FdRefType::Permanent(path) if self.is_dir(path) => {
+                fd.dir.determined(&self.dir_names(path), count)
+            }
+            _ => true,
+        }
+    }
+
+    /// Get the entry names of directory `path`, "." and ".." included.
+    fn dir_names(&self, path: &AbsPath) -> Vec<String> {
+        let mut names = vec![".".to_owned(), "..".to_owned()];
+        names.extend(
+            self.inodes
+                .keys()
+                .filter(|k| k.parent().as_ref() == Some(path))
+                .filter_map(|k| k.name().map(str::to_owned)),
+        );
+        names
+    }
+
+    /// Note that entry `path` is created or removed, in the streams of the open
+    /// file descriptions of its parent.
+    fn entry_changed(&mut self, path: &AbsPath) {
+        let (parent, name) = match (path.parent(), path.name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return,
+        };
+        for fd in self.fd_table.iter().flatten() {
+            let mut fd = fd.borrow_mut();
+            if fd.fref == FdRefType::Permanent(parent.clone()) {
+                fd.dir.changed(name);
+            }
+        }
+    }
+
+    /// Read up to `count` bytes at `offset` of regular file `inode`.
+    fn file_read(&self, inode: &Inode, offset: usize, count: usize) -> Vec<u8> {
+        let n = count.min(inode.size.saturating_sub(offset));
+        let data = self.data(inode.ino);
+        (offset..offset + n).map(|off| data.byte(off)).collect()
+    }
+
+    /// Write `buf` at `offset` of regular file `fref`, returning the number of bytes
+    /// written. The write may be short if the file system is running out of blocks.
+    fn file_write(
+        &mut self,
+        fref: &FdRefType,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<usize, FsError> {
+        let inode = self.inode(fref)?.clone();
+        let n = self.check_write_space(&inode, offset, buf.len())?;
+        self.modify_data(fref, inode.size.max(offset + n), |data| {
+            if offset + n > inode.size {
+                data.zero_tail(inode.size);
+            }
+            data.write(offset, &buf[..n]);
+        })?;
+        Ok(n)
+    }
+
+    /// Copy up to `len` bytes between regular files (`copy_file_range`), returning
+    /// the number of bytes copied.
+    ///
+    /// An explicit offset is used instead of the file offset of its fd, which is
+    /// left unchanged. Overlapping ranges of the same file give `EINVAL`, files on
+    /// different file systems give `EXDEV`.
+    pub fn copy_file_range(
+        &mut self,
+        fd_in: isize,
+        off_in: Option<usize>,
+        fd_out: isize,
+        off_out: Option<usize>,
+        len: usize,
+        flags: u32,
+    ) -> Result<usize, FsError> {
+        if flags != 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let (fd_in, _, in_inode) = self.fd_inode(fd_in)?;
+        let (fd_out, out_ref, out_inode) = self.fd_inode(fd_out)?;
+        if in_inode.is_dir() || out_inode.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        if !in_inode.is_file() || !out_inode.is_file() {
+            return Err(FsError::InvalidArgument);
+        }
+        if !fd_in.borrow().readable()
+            || !fd_out.borrow().writable()
+            || fd_out.borrow().flags.contains(OpenFlags::APPEND)
+        {
+            return Err(FsError::WrongAccessMode);
+        }
+        if in_inode.dev != out_inode.dev {
+            return Err(FsError::CrossDevice);
+        }
+        let pos_in = off_in.unwrap_or(fd_in.borrow().offset);
+        let pos_out = off_out.unwrap_or(fd_out.borrow().offset);
+        // The copy is shortened to the end of the source file.
+        let count = len.min(in_inode.size.saturating_sub(pos_in));
+        if in_inode.ino == out_inode.ino && pos_out < pos_in + count && pos_in < pos_out + count {
+            return Err(FsError::InvalidArgument);
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+        let data = self.file_read(&in_inode, pos_in, count);
+        let n = self.file_write(&out_ref, pos_out, &data)?;
+        self.copy_unknown(in_inode.ino, out_inode.ino);
+        if off_in.is_none() {
+            fd_in.borrow_mut().offset = pos_in + n;
+        }
+        if off_out.is_none() {
+            fd_out.borrow_mut().offset = pos_out + n;
+        }
+        Ok(n)
+    }
+
+    /// Copy up to `count` bytes from regular file `in_fd` to `out_fd` (`sendfile`),
+    /// returning the number of bytes copied.
+    ///
+    /// With an explicit `offset`, the file offset of `in_fd` is left unchanged. The
+    /// file offset of `out_fd` is always used, `O_APPEND` is not supported.
+    pub fn sendfile(
+        &mut self,
+        out_fd: isize,
+        in_fd: isize,
+        offset: Option<usize>,
+        count: usize,
+    ) -> Result<usize, FsError> {
+        let (fd_in, _, in_inode) = self.fd_inode(in_fd)?;
+        if !fd_in.borrow().readable() {
+            return Err(FsError::WrongAccessMode);
+        }
+        // Pipes cannot be read at an offset, which is checked before `out_fd`.
+        if offset.is_some() && in_inode.kind == FileKind::Fifo {
+            return Err(FsError::IllegalSeek);
+        }
+        let (fd_out, out_ref, out_inode) = self.fd_inode(out_fd)?;
+        if !fd_out.borrow().writable() {
+            return Err(FsError::WrongAccessMode);
+        }
+        if !in_inode.is_file() {
+            return Err(FsError::InvalidArgument);
+        }
+        let pos_in = offset.unwrap_or(fd_in.borrow().offset);
+        let n = if out_inode.kind == FileKind::Fifo {
+            self.file_to_pipe(&in_inode, pos_in, &out_ref, count)?
+        } else {
+            if !out_inode.is_file() || fd_out.borrow().flags.contains(OpenFlags::APPEND) {
+                return Err(FsError::InvalidArgument);
+            }
+            if count == 0 {
+                return Ok(0);
+            }
+            let data = self.file_read(&in_inode, pos_in, count);
+            let pos_out = fd_out.borrow().offset;
+            let n = self.file_write(&out_ref, pos_out, &data)?;
+            self.copy_unknown(in_inode.ino, out_inode.ino);
+            fd_out.borrow_mut().offset = pos_out + n;
+            n
+        };
+        if offset.is_none() {
+            fd_in.borrow_mut().offset = pos_in + n;
+        }
+        Ok(n)
+    }
+
+    /// Move up to `len` bytes from `fd_in` to `fd_out` (`splice`), one of which
+    /// must be a pipe, returning the number of bytes moved.
+    ///
+    /// Offsets are only allowed for the end which is not a pipe. Like pipe reads and
+    /// writes, splices that would block give `EAGAIN`.
+    pub fn splice(
+        &mut self,
+        fd_in: isize,
+        off_in: Option<usize>,
+        fd_out: isize,
+        off_out: Option<usize>,
+        len: usize,
+    ) -> Result<usize, FsError> {
+        if len == 0 {
+            return Ok(0);
+        }
+        let (fd_in, in_ref, in_inode) = self.fd_inode(fd_in)?;
+        let (fd_out, out_ref, out_inode) = self.fd_inode(fd_out)?;
+        if !fd_in.borrow().readable() || !fd_out.borrow().writable() {
+            return Err(FsError::WrongAccessMode);
+        }
+        let in_pipe = in_inode.kind == FileKind::Fifo;
+        let out_pipe = out_inode.kind == FileKind::Fifo;
+        if (in_pipe && off_in.is_some()) || (out_pipe && off_out.is_some()) {
+            return Err(FsError::IllegalSeek);
+        }
+        match (in_pipe, out_pipe) {
+            (true, true) => {
+                if in_inode.ino == out_inode.ino {
+                    return Err(FsError::InvalidArgument);
+                }
+                self.pipe_to_pipe(&in_ref, &out_ref, len)
+            }
+            (true, false) => {
+                if !out_inode.is_file() || fd_out.borrow().flags.contains(OpenFlags::APPEND) {
+                    return Err(FsError::InvalidArgument);
+                }
+                let pos = off_out.unwrap_or(fd_out.borrow().offset);
+                // Data not written stays in the pipe.
+                let data = self.pipe_peek(&in_ref, in_inode.ino, len)?;
+                let n = self.file_write(&out_ref, pos, &data)?;
+                let buf = self.pipe_bufs.entry(in_inode.ino).or_default();
+                buf.consume(n);
+                if !buf.is_known() {
+                    self.unknown_contents.insert(out_inode.ino);
+                }
+                if off_out.is_none() {
+                    fd_out.borrow_mut().offset = pos + n;
+                }
+                Ok(n)
+            }
+            (false, true) => {
+                if !in_inode.is_file() {
+                    return Err(FsError::InvalidArgument);
+                }
+                let pos = off_in.unwrap_or(fd_in.borrow().offset);
+                let n = self.file_to_pipe(&in_inode, pos, &out_ref, len)?;
+                if off_in.is_none() {
+                    fd_in.borrow_mut().offset = pos + n;
+                }
+                Ok(n)
+            }
+            (false, false) => Err(FsError::InvalidArgument),
+        }
+    }
+
+    /// Get file descriptor `fd`, the inode it refers to and a copy of the inode.
+    fn fd_inode(
+        &self,
+        fd: isize,
+    ) -> Result<(Rc<RefCell<FileDescriptor>>, FdRefType, Inode), FsError> {
+        let fd = self.get_fd(fd)?;
+        let fref = fd.borrow().fref.clone();
+        let inode = self.inode(&fref)?.clone();
+        Ok((fd, fref, inode))
+    }
+
+    /// Move up to `len` bytes at `offset` of regular file `inode` into pipe `fref`.
+    ///
+    /// Each page of the file takes a slot of its own, see `PipeBuffer::splice_in`.
+    fn file_to_pipe(
+        &mut self,
+        inode: &Inode,
+        offset: usize,
+        fref: &FdRefType,
+        len: usize,
+    ) -> Result<usize, FsError> {
+        if self.pipe_ends(fref, false) == 0 {
+            return Err(FsError::BrokenPipe);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        let ino = self.inode(fref)?.ino;
+        if self.pipe_bufs.get(&ino).is_some_and(PipeBuffer::is_full) {
+            return Err(FsError::WouldBlock);
+        }
+        let data = self.file_read(inode, offset, len);
+        let buf = self.pipe_bufs.entry(ino).or_default();
+        if self.unknown_contents.contains(&inode.ino) {
+            buf.mark_unknown();
+        }
+        Ok(buf.splice_in(&data, offset))
+    }
+
+    /// Move up to `len` bytes from pipe `in_ref` to pipe `out_ref`.
+    ///
+    /// Data is moved by whole slots, so the `PIPE_BUF` atomicity of writes does
+    /// not apply.
+    fn pipe_to_pipe(
+        &mut self,
+        in_ref: &FdRefType,
+        out_ref: &FdRefType,
+        len: usize,
+    ) -> Result<usize, FsError> {
+        let in_ino = self.inode(in_ref)?.ino;
+        let out_ino = self.inode(out_ref)?.ino;
+        if self
+            .pipe_bufs
+            .get(&in_ino)
+            .map_or(true, PipeBuffer::is_empty)
+        {
+            return if self.pipe_ends(in_ref, true) == 0 {
+                Ok(0)
+            } else {
+                Err(FsError::WouldBlock)
+            };
+        }
+        if self.pipe_ends(out_ref, false) == 0 {
+            return Err(FsError::BrokenPipe);
+        }
+        if self
+            .pipe_bufs
+            .get(&out_ino)
+            .is_some_and(PipeBuffer::is_full)
+        {
+            return Err(FsError::WouldBlock);
+        }
+        let mut in_buf = self.pipe_bufs.remove(&in_ino).unwrap();
+        let n = in_buf.move_to(self.pipe_bufs.entry(out_ino).or_default(), len);
+        self.pipe_bufs.insert(in_ino, in_buf);
+        Ok(n)
+    }
+
+    /// Truncate regular file `path` to `len` bytes (`truncate`), which needs write
+    /// permission on the file.
+    pub fn truncate(&mut self, path: &AbsPath, len: usize) -> Result<(), FsError> {
+        self.check_search(path)?;
+        let inode = self.lookup(path)?;
+        if inode.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        if !inode.is_file() {
+            return Err(FsError::InvalidArgument);
+        }
+        self.permission(&inode, MAY_WRITE)?;
+        self.resize(&FdRefType::Permanent(path.clone()), len)
+    }
+
+    /// Truncate the regular file referred to by `fd` to `len` bytes (`ftruncate`),
+    /// `fd` must be opened for writing.
+    pub fn ftruncate(&mut self, fd: isize, len: usize) -> Result<(), FsError> {
+        let fd = self.get_fd(fd)?;
+        let fref = fd.borrow().fref.clone();
+        if !fd.borrow().writable() || !self.inode(&fref).is_ok_and(|inode| inode.is_file()) {
+            return Err(FsError::InvalidArgument);
+        }
+        self.resize(&fref, len)
+    }
+
+    /// Set the size of regular file `fref` to `len`.
+    ///
+    /// Growing a file leaves a hole, except on FAT, which has no sparse files.
+    fn resize(&mut self, fref: &FdRefType, len: usize) -> Result<(), FsError> {
+        let inode = self.inode(fref)?.clone();
+        let sparse = !cfg!(feature = "fat");
+        if !sparse && len > inode.size {
+            let needed = self
+                .data(inode.ino)
+                .new_blocks(inode.size, len - inode.size);
+            self.check_alloc_space(&inode, needed)?;
+        }
+        self.modify_data(fref, len, |data| {
+            if len > inode.size {
+                data.zero_tail(inode.size);
+            }
+            data.truncate(len);
+            if !sparse && len > inode.size {
+                data.allocate(inode.size, len - inode.size);
+            }
+        })?;
+        self.zap_private_pages(fref, len..usize::MAX);
+        Ok(())
+    }
+
+    /// Manipulate the blocks of the regular file referred to by `fd` (`fallocate`).
+    ///
+    /// - Mode 0 allocates [`offset`, `offset + len`), extending the file.
+    /// - `FALLOC_FL_PUNCH_HOLE` frees the blocks in the range and zeros the rest,
+    ///   it must come with `FALLOC_FL_KEEP_SIZE`.
+    /// - `FALLOC_FL_ZERO_RANGE` zeros the range, allocating its blocks.
+    /// - `FALLOC_FL_KEEP_SIZE` keeps the file size, blocks past the end of file stay
+    ///   allocated until truncation.
+    ///
+    /// FAT only supports allocation.
+    pub fn fallocate(
+        &mut self,
+        fd: isize,
+        mode: FallocFlags,
+        offset: usize,
+        len: usize,
+    ) -> Result<(), FsError> {
+        if len == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let punch = mode.contains(FallocFlags::PUNCH_HOLE);
+        let zero = mode.contains(FallocFlags::ZERO_RANGE);
+        let keep = mode.contains(FallocFlags::KEEP_SIZE);
+        if (punch && zero) || (punch && !keep) {
+            return Err(FsError::NotSupported);
+        }
+        let fd = self.get_fd(fd)?;
+        if !fd.borrow().writable() {
+            return Err(FsError::WrongAccessMode);
+        }
+        let fref = fd.borrow().fref.clone();
+        let inode = self.inode(&fref)?.clone();
+        match inode.kind {
+            FileKind::File => (),
+            FileKind::Fifo => return Err(FsError::IllegalSeek),
+            FileKind::Directory => return Err(FsError::IsDirectory),
+            _ => return Err(FsError::NoDevice),
+        }
+        if cfg!(feature = "fat") && (punch || zero) {
+            return Err(FsError::NotSupported);
+        }
+        if !punch {
+            let needed = self.data(inode.ino).new_blocks(offset, len);
+            self.check_alloc_space(&inode, needed)?;
+        }
+        let end = offset + len;
+        let size = if keep {
+            inode.size
+        } else {
+            inode.size.max(end)
+        };
+        self.modify_data(&fref, size, |data| {
+            if size > inode.size {
+                data.zero_tail(inode.size);
+            }
+            if punch {
+                data.punch_hole(offset, len);
+            } else if zero {
+                data.zero_range(offset, len);
+            } else {
+                data.allocate(offset, len);
+            }
+        })?;
+        if punch || zero {
+            self.zap_private_pages(&fref, offset..end);
+        }
+        Ok(())
+    }
+
+    /// Reposition the offset of `fd` (`lseek`), returning the new offset.
+    ///
+    /// `SEEK_DATA` and `SEEK_HOLE` find the data and holes of regular files, the end
+    /// of file counts as a hole. Offsets at or past the end of file give `ENXIO`.
+    ///
+    /// Seeking a directory to 0 rewinds its stream. Other directory offsets are
+    /// cookies of the file system, and not modeled.
+    pub fn lseek(&mut self, fd: isize, offset: i64, whence: Whence) -> Result<usize, FsError> {
+        let fd = self.get_fd(fd)?;
+        let (fref, cur) = {
+            let fd = fd.borrow();
+            (fd.fref.clone(), fd.offset)
+        };
+        if matches!(fref, FdRefType::Pipe(_) | FdRefType::Stdio) {
+            return Err(FsError::IllegalSeek);
+        }
+        let inode = self.inode(&fref)?.clone();
+        if inode.kind == FileKind::Fifo {
+            return Err(FsError::IllegalSeek);
+        }
+        let new = match whence {
+            Whence::Set => Some(offset),
+            Whence::Cur => (cur as i64).checked_add(offset),
+            Whence::End => (inode.size as i64).checked_add(offset),
+            Whence::Data | Whence::Hole => {
+                if !inode.is_file() {
+                    return Err(FsError::InvalidArgument);
+                }
+                if offset < 0 || offset as usize >= inode.size {
+                    return Err(FsError::NoSuchDevice);
+                }
+                let data = self.data(inode.ino);
+                let found = match whence {
+                    Whence::Data => data.seek_data(offset as usize, inode.size),
+                    _ => Some(data.seek_hole(offset as usize, inode.size)),
+                };
+                Some(found.ok_or(FsError::NoSuchDevice)? as i64)
+            }
+        };
+        let new = new
+            .filter(|&new| new >= 0)
+            .ok_or(FsError::InvalidArgument)? as usize;
+        fd.borrow_mut().offset = new;
+        if inode.is_dir() && new == 0 {
+            fd.borrow_mut().dir.rewind();
+        }
+        Ok(new)
+    }
+
+    /// Read up to `count` bytes from the buffer of pipe or FIFO `ino`.
+    ///
+    /// An empty buffer gives EOF if there are no writers, otherwise `EAGAIN`. Blocking
+    /// reads are treated as non-blocking, since the model cannot wait for a writer.
+    fn pipe_read(
+        &mut self,
+        fref: &FdRefType,
+        ino: usize,
+        count: usize,
+    ) -> Result<Vec<u8>, FsError> {
+        let data = self.pipe_peek(fref, ino, count)?;
+        self.pipe_bufs.entry(ino).or_default().consume(data.len());
+        Ok(data)
+    }
+
+    /// Get up to `count` bytes a read from pipe or FIFO `ino` would give, without
+    /// removing them from the buffer.
+    fn pipe_peek(&self, fref: &FdRefType, ino: usize, count: usize) -> Result<Vec<u8>, FsError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        match self.pipe_bufs.get(&ino) {
+            Some(buf) if !buf.is_empty() => Ok(buf.peek(count)),
+            _ if self.pipe_ends(fref, true) == 0 => Ok(Vec::new()),
+            _ => Err(FsError::WouldBlock),
+        }
+    }
+
+    /// Write `data` to the buffer of pipe or FIFO `ino`.
+    ///
+    /// Writing without readers gives `EPIPE`, the target is expected to ignore
+    /// `SIGPIPE`. Writes up to `PIPE_BUF` bytes are atomic, as they take at most one
+    /// slot, larger writes may be partial. Writes that would block give `EAGAIN`, as
+    /// the model cannot wait for a reader.
+    fn pipe_write(&mut self, fref: &FdRefType, ino: usize, data: &[u8]) -> Result<usize, FsError> {
+        if self.pipe_ends(fref, false) == 0 {
+            return Err(FsError::BrokenPipe);
+        }
+        self.pipe_bufs.entry(ino).or_default().write(data)
+    }
+
+    /// Count the file descriptors referring to the same pipe or FIFO as `fref`,
+    /// which are opened for writing if `writers` is set, otherwise for reading.
+    fn pipe_ends(&self, fref: &FdRefType, writers: bool) -> usize {
+        self.all_fds_ref_same_inode(fref)
+            .into_iter()
+            .filter(|&fd| {
+                let fd = self.fd_table[fd as usize].as_ref().unwrap().borrow();
+                if writers {
+                    fd.writable()
+                } else {
+                    fd.readable()
+                }
+            })
+            .count()
+    }
+
+    /// Change the mode of the inode referred to by `fref`, only the owner or root
+    /// may do it.
+    pub fn chmod(&mut self, fref: &FdRefType, mode: FileMode) -> Result<(), FsError> {
+        let (uid, gid) = (self.uid, self.gid);
+        let inode = self.inode_mut(fref)?;
+        if uid != 0 && uid != inode.uid {
+            return Err(FsError::NotPermitted);
+        }
+        let mut mode = mode;
+        if uid != 0 && gid != inode.gid {
+            // Unprivileged users cannot set the setgid bit for other groups.
+            mode.remove(MODE_SETGID);
+        }
+        inode.chmod(mode);
+        Ok(())
+    }
+
+    /// Apply `flock` operation `op` on the open file description of `fd`.
+    pub fn flock(&mut self, fd: isize, op: FlockFlags) -> Result<(), FsError> {
+        let fd = self.get_fd(fd)?;
+        let fd = fd.borrow();
+        let ino = self.inode(&fd.fref)?.ino;
+        self.locks.flock(fd.id, ino, op)
+    }
+
+    /// Set a POSIX record lock (`F_SETLK`) on the inode referred to by `fd`.
+    ///
+    /// Read locks need `fd` opened for reading, write locks need it opened for writing.
+    pub fn setlk(
+        &mut self,
+        fd: isize,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<(), FsError> {
+        let fd = self.get_fd(fd)?;
+        let fd = fd.borrow();
+        match kind {
+            LockKind::Read if !fd.readable() => return Err(FsError::WrongAccessMode),
+            LockKind::Write if !fd.writable() => return Err(FsError::WrongAccessMode),
+            _ => (),
+        }
+        let ino = self.inode(&fd.fref)?.ino;
+        self.locks.setlk(ino, kind, start, len)
+    }
+
+    /// Test for a POSIX record lock (`F_GETLK`) conflicting with the given one.
+    ///
+    /// Only the arguments are checked. The model has a single process, whose own
+    /// locks never conflict, so `F_GETLK` never reports a conflict. Conflict
+    /// reporting is not modelled, as no command can place a lock of another
+    /// process or an open file description lock (`F_OFD_SETLK`).
+    pub fn getlk(&self, fd: isize, kind: LockKind, start: u64, len: u64) -> Result<(), FsError> {
+        let fd = self.get_fd(fd)?;
+        self.inode(&fd.borrow().fref)?;
+        LockTable::check_getlk(kind, start, len)
+    }
+
+    /// Change the current working directory.
+    pub fn chdir(&mut self, path: AbsPath) -> Result<(), FsError> {
+        self.check_search(&path)?;
+        if !self.exists(&path) {
+            return Err(FsError::NotFound);
+        }
+        if !self.is_dir(&path) {
+            return Err(FsError::NotDirectory);
+        }
+        self.permission(&self.lookup(&path)?, MAY_EXEC)?;
+        self.cwd = path;
+        Ok(())
+    }
+
+    /// Get all allocated file descriptors.
+    pub fn all_fds(&self) -> Vec<isize> {
+        self.fd_table
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.is_some())
+            .map(|(i, _)| i as isize)
+            .collect()
+    }
+
+    /// Get file descriptor by fd.
+    pub fn get_fd(&self, fd: isize) -> Result<Rc<RefCell<FileDescriptor>>, FsError> {
+        if fd < 0 || fd as usize >= self.fd_table.len() {
+            return Err(FsError::FdOutOfRange);
+        } else {
+            self.fd_table[fd as usize].clone().ok_or(FsError::NotOpened)
+        }
+    }
+
+    /// Get a fresh open file description id. Ids are never reused, so `flock` locks
+    /// of a closed description cannot be taken over by a new one.
+    pub fn alloc_ofd_id(&mut self) -> usize {
+        self.ofd_idx += 1;
+        self.ofd_idx - 1
+    }
+
+    /// Find the lowest available posistion in the fd table and write `fd` into it.
+    pub fn alloc_fd(&mut self, fd: Rc<RefCell<FileDescriptor>>) -> Result<isize, FsError> {
+        for (i, e) in self.fd_table.iter_mut().enumerate() {
+            if e.is_none() {
+                *e = Some(fd);
+                return Ok(i as isize);
+            }
+        }
+        Err(FsError::NoAvailableFd)
+    }
+
+    /// Free the file descriptor.
+    pub fn free_fd(&mut self, fd: isize) -> Result<(), FsError> {
+        if self.get_fd(fd).is_ok() {
+            let fd = self.fd_table[fd as usize].take().unwrap();
+            let fref = fd.borrow().fref.clone();
+            let ino = self.inode(&fref).map(|inode| inode.ino).ok();
+            // Closing any file descriptor of an inode drops all record locks the
+            // process holds on it, even those placed through another descriptor.
+            if let Some(ino) = ino {
+                self.locks.release_records(ino);
+            }
+            // `flock` locks are dropped with the last file descriptor of the open
+            // file description.
+            if !self.fd_table.iter().flatten().any(|e| Rc::ptr_eq(e, &fd)) {
+                self.locks.release_ofd(fd.borrow().id);
+            }
+            self.release_inode(&fref);
+            Ok(())
+        } else {
+            Err(FsError::NotOpened)
+        }
+    }
+
+    /// Release the inode referred to by `fref` after a file descriptor or memory
+    /// mapping is dropped.
+    ///
+    /// If nothing else refers to the inode, pipe data is discarded, and temporary
+    /// inodes and pipes are removed.
+    fn release_inode(&mut self, fref: &FdRefType) {
+        if !self.users(fref).is_empty() {
+            return;
+        }
+        if let Ok(inode) = self.inode(fref) {
+            let ino = inode.ino;
+            self.pipe_bufs.remove(&ino);
+        }
+        match fref {
+            FdRefType::Temporary(idx) => {
+                if let Some(inode) = self.tmp_inodes.remove(idx) {
+                    self.drop_contents(inode.ino);
+                }
+                self.linkable.remove(idx);
+            }
+            FdRefType::Pipe(ino) => {
+                self.pipes.remove(ino);
+            }
+            _ => (),
+        }
+    }
+
+    /// Map `len` bytes of the file referred to by `fd` from `offset` at `addr`.
+    ///
+    /// Mappings are placed at `addr` as with `MAP_FIXED`, replacing the parts of
+    /// existing mappings they overlap. Writable mappings are also readable, as on
+    /// most architectures. `MAP_SHARED | MAP_PRIVATE` is `MAP_SHARED_VALIDATE`, a
+    /// shared mapping which rejects flags the model does not know.
+    pub fn mmap(
+        &mut self,
+        addr: usize,
+        len: usize,
+        prot: ProtFlags,
+        flags: MapFlags,
+        fd: isize,
+        offset: usize,
+    ) -> Result<usize, FsError> {
+        let shared = flags.contains(MapFlags::SHARED);
+        if len == 0 || addr % PAGE_SIZE != 0 || offset % PAGE_SIZE != 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let fd = self.get_fd(fd)?;
+        let fd = fd.borrow();
+        if !flags.intersects(MapFlags::SHARED | MapFlags::PRIVATE) {
+            return Err(FsError::InvalidArgument);
+        }
+        let known = MapFlags::SHARED | MapFlags::PRIVATE | MapFlags::FIXED;
+        if flags.contains(MapFlags::SHARED | MapFlags::PRIVATE) && !(flags - known).is_empty() {
+            return Err(FsError::NotSupported);
+        }
+        let writable = prot.contains(ProtFlags::WRITE);
+        if !fd.readable() || (shared && writable && !fd.writable()) {
+            return Err(FsError::PermissionDenied);
+        }
+        if !self.inode(&fd.fref).is_ok_and(|inode| inode.is_file()) {
+            return Err(FsError::NoDevice);
+        }
+        let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let replaced: Vec<FdRefType> = self
+            .mappings
+            .range(addr, len)
+            .map(|m| m.fref.clone())
+            .collect();
+        self.mappings.insert(Mapping {
+            start: addr,
+            len,
+            fref: fd.fref.clone(),
+            offset,
+            readable: prot.contains(ProtFlags::READ) || writable,
+            writable,
+            shared,
+            private_pages: BTreeMap::new(),
+        });
+        for fref in replaced {
+            self.release_inode(&fref);
+        }
+        Ok(addr)
+    }
+
+    /// Unmap [`addr`, `addr + len`), which needs not be mapped.
+    pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), FsError> {
+        if len == 0 || addr % PAGE_SIZE != 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let removed: Vec<FdRefType> = self
+            .mappings
+            .range(addr, len)
+            .map(|m| m.fref.clone())
+            .collect();
+        self.mappings.remove(addr, len);
+        for fref in removed {
+            self.release_inode(&fref);
+        }
+        Ok(())
+    }
+
+    /// Flush the shared mappings in [`addr`, `addr + len`) to the files.
+    ///
+    /// The page cache is shared, so only `MS_SYNC` matters, which persists the
+    /// mapped files like `fdatasync`.
+    pub fn msync(&mut self, addr: usize, len: usize, flags: MsyncFlags) -> Result<(), FsError> {
+        if addr % PAGE_SIZE != 0
+            || flags.contains(MsyncFlags::ASYNC | MsyncFlags::SYNC)
+            || !(flags - (MsyncFlags::ASYNC | MsyncFlags::SYNC | MsyncFlags::INVALIDATE)).is_empty()
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        if !self.mappings.covers(addr, len) {
+            return Err(FsError::OutOfMemory);
+        }
+        if flags.contains(MsyncFlags::SYNC) {
+            let paths: Vec<AbsPath> = self
+                .mappings
+                .range(addr, len)
+                .filter(|m| m.shared)
+                .filter_map(|m| match &m.fref {
+                    FdRefType::Permanent(path) => Some(path.clone()),
+                    _ => None,
+                })
+                .collect();
+            for path in paths {
+                self.inodes.fsync(&path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the byte at `addr` through a memory mapping.
+    ///
+    /// Unmapped or unreadable addresses fault with `SIGSEGV`, pages past the end
+    /// of file fault with `SIGBUS`.
+    pub fn mem_read(&self, addr: usize) -> Result<u8, FsError> {
+        let (mapping, file_off) = self.mapped_at(addr, false)?;
+        let page = (addr - mapping.start) / PAGE_SIZE;
+        if let Some(data) = mapping.private_pages.get(&page) {
+            return Ok(data[(addr - mapping.start) % PAGE_SIZE]);
+        }
+        Ok(self.file_byte(self.inode(&mapping.fref)?.ino, file_off))
+    }
+
+    /// Write `value` to `addr` through a memory mapping.
+    ///
+    /// Writes to shared mappings go to the file, writes to private mappings go to
+    /// a private copy of the page, made on the first write.
+    pub fn mem_write(&mut self, addr: usize, value: u8) -> Result<(), FsError> {
+        let (mapping, file_off) = self.mapped_at(addr, true)?;
+        let ino = self.inode(&mapping.fref)?.ino;
+        if mapping.shared {
+            // Space is not checked, a failed page allocation would be a `SIGBUS`.
+            let size = self.inode(&mapping.fref)?.size;
+            self.modify_data(&mapping.fref, size, |data| data.write(file_off, &[value]))?;
+            return Ok(());
+        }
+        let page = (addr - mapping.start) / PAGE_SIZE;
+        let page_off = file_off - file_off % PAGE_SIZE;
+        let copy: Vec<u8> = (page_off..page_off + PAGE_SIZE)
+            .map(|off| self.file_byte(ino, off))
+            .collect();
+        let mapping = self.mappings.find_mut(addr).unwrap();
+        let data = mapping.private_pages.entry(page).or_insert(copy);
+        data[addr % PAGE_SIZE] = value;
+        Ok(())
+    }
+
+    /// Check if the byte read at `addr` through a memory mapping is known to the
+    /// model, i.e. the mapped file is not of unknown content.
+    pub fn mem_read_determined(&self, addr: usize) -> bool {
+        self.mappings
+            .find(addr)
+            .and_then(|mapping| self.inode(&mapping.fref).ok())
+            .map_or(true, |inode| !self.unknown_contents.contains(&inode.ino))
+    }
+
+    /// Get the mapping containing `addr` and the file offset of `addr`, checking
+    /// the access.
+    fn mapped_at(&self, addr: usize, write: bool) -> Result<(Mapping, usize), FsError> {
+        let mapping = self.mappings.find(addr).ok_or(FsError::Fault)?;
+        if !mapping.readable || (write && !mapping.writable) {
+            return Err(FsError::Fault);
+        }
+        let file_off = mapping.offset + (addr - mapping.start);
+        let size = self.inode(&mapping.fref)?.size;
+        if file_off >= size.div_ceil(PAGE_SIZE) * PAGE_SIZE {
+            return Err(FsError::BusError);
+        }
+        Ok((mapping.clone(), file_off))
+    }
+
+    /// Mark inode `to` as of unknown content if inode `from` is, after data is
+    /// copied from `from` to `to`.
+    fn copy_unknown(&mut self, from: usize, to: usize) {
+        if self.unknown_contents.contains(&from) {
+            self.unknown_contents.insert(to);
+        }
+    }
+
+    /// Get the byte at offset `off` of the content of inode `ino`.
+    fn file_byte(&self, ino: usize, off: usize) -> u8 {
+        self.contents.get(&ino).map_or(0, |data| data.byte(off))
+    }
+
+    /// Get the content of inode `ino`, an empty file if it has none.
+    fn data(&self, ino: usize) -> &FileData {
+        self.contents.get(&ino).unwrap_or(&EMPTY_DATA)
+    }
+
+    /// Change the content of regular file `fref` by `f` and set its size to `size`,
+    /// keeping the block count of the inode in sync. An empty file has no unknown
+    /// content left.
+    fn modify_data<F: FnOnce(&mut FileData)>(
+        &mut self,
+        fref: &FdRefType,
+        size: usize,
+        f: F,
+    ) -> Result<(), FsError> {
+        let ino = self.inode(fref)?.ino;
+        let data = self.contents.entry(ino).or_default();
+        f(data);
+        let blocks = data.blocks() * SECTORS_PER_BLOCK;
+        if size == 0 {
+            self.unknown_contents.remove(&ino);
+        }
+        match fref {
+            FdRefType::Permanent(path) => self.inodes.resize(path, size, blocks),
+            FdRefType::Temporary(idx) => self.tmp_inodes.get_mut(idx).map(|inode| {
+                inode.size = size;
+                inode.blocks = blocks;
+            }),
+            FdRefType::Pipe(_) | FdRefType::Stdio => None,
+        }
+        .ok_or(FsError::NotFound)
+    }
+
+    /// Drop the pages copied on write by private mappings of the inode referred to
+    /// by `fref` which lie entirely in the file range `range`, as truncation and
+    /// hole punching do.
+    fn zap_private_pages(&mut self, fref: &FdRefType, range: Range<usize>) {
+        for start in self.users(fref).mappings {
+            let mapping = self.mappings.find_mut(start).unwrap();
+            let offset = mapping.offset;
+            mapping.private_pages.retain(|page, _| {
+                let page_start = offset + page * PAGE_SIZE;
+                page_start < range.start || page_start + PAGE_SIZE > range.end
+            });
+        }
+    }
+
+    /// Drop the content of inode `ino`, which is removed.
+    fn drop_contents(&mut self, ino: usize) {
+        self.contents.remove(&ino);
+        self.unknown_contents.remove(&ino);
+    }
+
+    /// Drop the contents of inodes no longer in the file system.
+    fn drop_stale_contents(&mut self) {
+        let inos: HashSet<usize> = self
+            .inodes
+            .keys()
+            .map(|k| self.inodes.get(k).unwrap().ino)
+            .chain(self.tmp_inodes.values().map(|inode| inode.ino))
+            .collect();
+        self.contents.retain(|ino, _| inos.contains(ino));
+        self.unknown_contents.retain(|ino| inos.contains(ino));
+    }
+
+    /// Parse `path` argument of fs syscall. For `openat`, `linkat`, `mkdirat` ...
+    ///
+    /// The dirfd argument is used in conjunction with the pathname
+    /// argument as follows:
+    ///
+    /// -  If the pathname given in pathname is absolute, then dirfd is
+    ///       ignored.
+    /// -  If the pathname given in pathname is relative and dirfd is the
+    ///       special value AT_FDCWD, then pathname is interpreted relative
+    ///       to the current working directory of the calling process (like
+    ///       open()).
     /// -  If the pathname given in pathname is relative, then it is
     ///       interpreted relative to the directory referred to by the file
     ///       descriptor dirfd (rather than relative to the current working
@@ -385,12 +2270,15 @@ impl FileSystem {
     ///
     /// Ref: https://man7.org/linux/man-pages/man2/open.2.html
     ///
+    /// Paths are normalized lexically, so ".." at the root of a mounted file system
+    /// steps to the parent of the mount point, as Linux does.
+    ///
     /// If `dirfd` refers to a temporary file, then `NotDirectory` error is returned.
     /// If `dirfd` refers to a temporary directory, then `NotFound` is returned because
     /// a path relative to a temporary directory does not exist in the file system.
     pub fn parse_path(&self, dirfd: isize, path: Path) -> Result<AbsPath, FsError> {
         if path.absolute() {
-            path.try_into()
+            self.model_path(path.try_into()?)
         } else {
             if dirfd == FDCWD {
                 Ok(self.cwd.join(&path.try_into()?)?)
@@ -415,34 +2303,295 @@ impl FileSystem {
                             Err(FsError::NotFound)
                         }
                     }
+                    FdRefType::Pipe(_) | FdRefType::Stdio => Err(FsError::NotDirectory),
                 }
             }
         }
     }
 
-    /// Increase link count of an inode
-    fn increase_nlink(&mut self, path: &AbsPath) -> Result<(), FsError> {
-        let inode = self.inodes.get_mut(path).ok_or(FsError::NotFound)?;
-        inode.nlink += 1;
+    /// Check if the process is granted `want` on `inode`.
+    fn permission(&self, inode: &Inode, want: u16) -> Result<(), FsError> {
+        inode.permission(self.uid, self.gid, want)
+    }
+
+    /// Check search permission on the directories walked to resolve `path`.
+    ///
+    /// The walk stops at the first missing or non-directory component, which
+    /// is reported by the caller.
+    fn check_search(&self, path: &AbsPath) -> Result<(), FsError> {
+        let mut dirs = vec![];
+        let mut cur = path.parent();
+        while let Some(dir) = cur {
+            cur = dir.parent();
+            dirs.push(dir);
+        }
+        for dir in dirs.into_iter().rev() {
+            match self.inodes.get(&dir) {
+                Some(inode) if inode.is_dir() => self.permission(inode, MAY_EXEC)?,
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the sticky bit of the parent directory before removing or renaming `path`.
+    ///
+    /// In a sticky directory, only the owner of the entry, the owner of the directory
+    /// or root may remove the entry.
+    fn check_sticky(&self, path: &AbsPath) -> Result<(), FsError> {
+        let parent = self.lookup(&path.parent().ok_or(FsError::InvalidPath)?)?;
+        let inode = self.lookup(path)?;
+        if parent.is_sticky() && self.uid != 0 && self.uid != inode.uid && self.uid != parent.uid {
+            return Err(FsError::NotPermitted);
+        }
         Ok(())
     }
 
-    /// Decrease link count of an inode
-    fn decrease_nlink(&mut self, path: &AbsPath) -> Result<(), FsError> {
-        let inode = self.inodes.get_mut(path).ok_or(FsError::NotFound)?;
-        inode.nlink -= 1;
+    /// Build a new inode of `kind` to be created in directory `parent`.
+    ///
+    /// The permission bits are masked by the default ACL of the parent if present,
+    /// otherwise by umask. Ownership and the setgid bit follow the parent.
+    fn new_inode(
+        &self,
+        parent: &AbsPath,
+        kind: FileKind,
+        mode: FileMode,
+    ) -> Result<Inode, FsError> {
+        let parent = self.lookup(parent)?;
+        self.permission(&parent, MAY_WRITE | MAY_EXEC)?;
+        let default_acl = parent.default_acl();
+        let mut mode = match default_acl {
+            Some(_) => mode,
+            None => mode.difference(self.umask),
+        };
+        if kind == FileKind::Directory {
+            // `mkdir` ignores the setgid bit, directories only inherit it.
+            mode.remove(MODE_SETGID);
+        }
+        let mut inode = Inode::new(mode, self.uid, self.gid, kind);
+        inode.dev = parent.dev;
+        inode.ino = self.alloc_ino();
+        if let Some(acl) = default_acl {
+            inode.inherit_acl(&acl);
+        }
+        if parent.is_setgid() {
+            // Entries in a setgid directory belong to the directory's group,
+            // and subdirectories inherit the setgid bit.
+            inode.gid = parent.gid;
+            if kind == FileKind::Directory {
+                inode.mode.insert(MODE_SETGID);
+            }
+        }
+        if kind != FileKind::Directory && inode.gid != self.gid && self.uid != 0 {
+            // Unprivileged users cannot create setgid files for other groups.
+            inode.mode.remove(MODE_SETGID);
+        }
+        Ok(inode)
+    }
+
+    /// Get the number of inodes and blocks used by the root file system.
+    ///
+    /// Each directory takes one block per `DIRENTS_PER_BLOCK` entries, each regular
+    /// file takes its allocated blocks, other inodes take no blocks.
+    /// Unlinked inodes still held open take an inode and their blocks.
+    fn usage(&self) -> (usize, usize) {
+        let (inodes, blocks) = self.inodes.usage();
+        let tmp_blocks: usize = self
+            .tmp_inodes
+            .values()
+            .map(|inode| inode.blocks.div_ceil(SECTORS_PER_BLOCK))
+            .sum();
+        (inodes + self.tmp_inodes.len(), blocks + tmp_blocks)
+    }
+
+    /// Check if there is space for a new entry in directory `parent` if `new_entry`
+    /// is set. If `kind` is given, a new inode of that kind is also needed.
+    fn check_space(
+        &self,
+        parent: &AbsPath,
+        new_entry: bool,
+        kind: Option<FileKind>,
+    ) -> Result<(), FsError> {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return Ok(()),
+        };
+        let root_dev = self.inodes.get(&AbsPath::root()).unwrap().dev;
+        if self.lookup(parent)?.dev != root_dev {
+            return Ok(());
+        }
+        let (mut inodes, mut blocks) = self.usage();
+        // The parent directory may need another block.
+        let entries = self.inodes.entries(parent).unwrap_or(0);
+        if new_entry {
+            blocks += dir_blocks(entries + 1) - dir_blocks(entries);
+        }
+        if let Some(kind) = kind {
+            inodes += 1;
+            if kind == FileKind::Directory {
+                blocks += dir_blocks(0);
+            }
+        }
+        if inodes > capacity.inodes || blocks > capacity.blocks {
+            return Err(FsError::NoSpace);
+        }
         Ok(())
     }
 
+    /// Get how many of `len` bytes written at `offset` of regular file `inode` fit in
+    /// the free blocks. Give `ENOSPC` if none of them fits.
+    fn check_write_space(
+        &self,
+        inode: &Inode,
+        offset: usize,
+        len: usize,
+    ) -> Result<usize, FsError> {
+        let free = match self.free_blocks(inode) {
+            Some(free) => free,
+            None => return Ok(len),
+        };
+        let n = self.data(inode.ino).fit(offset, len, free);
+        if n == 0 && len != 0 {
+            return Err(FsError::NoSpace);
+        }
+        Ok(n)
+    }
+
+    /// Check if `blocks` more blocks can be allocated for regular file `inode`.
+    fn check_alloc_space(&self, inode: &Inode, blocks: usize) -> Result<(), FsError> {
+        match self.free_blocks(inode) {
+            Some(free) if free < blocks => Err(FsError::NoSpace),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the free blocks of the file system containing `inode`, `None` if space
+    /// is unlimited.
+    fn free_blocks(&self, inode: &Inode) -> Option<usize> {
+        let capacity = self.capacity?;
+        if inode.dev != self.inodes.get(&AbsPath::root()).unwrap().dev {
+            return None;
+        }
+        Some(capacity.blocks.saturating_sub(self.usage().1))
+    }
+
+    /// Get the inode reference of file descriptor `fd`.
+    pub fn fd_ref(&self, fd: isize) -> Result<FdRefType, FsError> {
+        Ok(self.get_fd(fd)?.borrow().fref.clone())
+    }
+
+    /// Resolve the inode referred to by an `*at` syscall.
+    ///
+    /// With `AT_EMPTY_PATH` and an empty `path`, the inode is the one `dirfd`
+    /// refers to. Otherwise an empty `path` is not found.
+    pub fn resolve(&self, dirfd: isize, path: Path, flags: AtFlags) -> Result<FdRefType, FsError> {
+        if path.0.is_empty() {
+            if !flags.contains(AtFlags::EMPTY_PATH) {
+                return Err(FsError::NotFound);
+            }
+            if dirfd == FDCWD {
+                return Ok(FdRefType::Permanent(self.cwd.clone()));
+            }
+            return self.fd_ref(dirfd);
+        }
+        let path = self.parse_path(dirfd, path)?;
+        if !self.exists(&path) {
+            return Err(FsError::NotFound);
+        }
+        Ok(FdRefType::Permanent(path))
+    }
+
+    /// Get the inode referred to by `fref`.
+    pub fn inode(&self, fref: &FdRefType) -> Result<&Inode, FsError> {
+        match fref {
+            FdRefType::Permanent(path) => self.inodes.get(path),
+            FdRefType::Temporary(idx) => self.tmp_inodes.get(idx),
+            FdRefType::Pipe(ino) => self.pipes.get(ino),
+            FdRefType::Stdio => None,
+        }
+        .ok_or(FsError::NotFound)
+    }
+
+    /// Get the mutable inode referred to by `fref`.
+    pub fn inode_mut(&mut self, fref: &FdRefType) -> Result<&mut Inode, FsError> {
+        match fref {
+            FdRefType::Permanent(path) => self.inodes.get_mut(path),
+            FdRefType::Temporary(idx) => self.tmp_inodes.get_mut(idx),
+            FdRefType::Pipe(ino) => self.pipes.get_mut(ino),
+            FdRefType::Stdio => None,
+        }
+        .ok_or(FsError::NotFound)
+    }
+
+    /// Get an unused inode number.
+    fn alloc_ino(&self) -> usize {
+        self.inodes
+            .keys()
+            .map(|k| self.inodes.get(k).unwrap().ino)
+            .chain(self.tmp_inodes.values().map(|inode| inode.ino))
+            .chain(self.pipes.keys().copied())
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+
+    /// Keep the inode numbers of `old_inodes` for the paths still present, and give
+    /// fresh numbers to new inodes, so that the lock table stays valid after `update`.
+    fn keep_inos(&mut self, old_inodes: &MultiKeyMap<AbsPath, Inode>) {
+        let paths = self.paths();
+        for path in &paths {
+            self.inodes.get_mut(path).unwrap().ino = 0;
+        }
+        for path in &paths {
+            if let Some(old) = old_inodes.get(path) {
+                self.inodes.get_mut(path).unwrap().ino = old.ino;
+            }
+        }
+        for path in &paths {
+            if self.inodes.get(path).unwrap().ino == 0 {
+                let ino = self.alloc_ino();
+                self.inodes.get_mut(path).unwrap().ino = ino;
+            }
+        }
+    }
+
     /// Check if 2 frefs refer to the same inode.
     fn ref_same_inode(&self, a: &FdRefType, b: &FdRefType) -> bool {
         match (a, b) {
             (FdRefType::Permanent(a), FdRefType::Permanent(b)) => self.inodes.are_aliases(a, b),
             (FdRefType::Temporary(a), FdRefType::Temporary(b)) => a == b,
+            (FdRefType::Pipe(a), FdRefType::Pipe(b)) => a == b,
             _ => false,
         }
     }
 
+    /// Get all file descriptors and memory mappings referring to the same inode as `fref`.
+    fn users(&self, fref: &FdRefType) -> InodeUsers {
+        InodeUsers {
+            fds: self.all_fds_ref_same_inode(fref),
+            mappings: self
+                .mappings
+                .iter()
+                .filter(|m| self.ref_same_inode(&m.fref, fref))
+                .map(|m| m.start)
+                .collect(),
+        }
+    }
+
+    /// Make the file descriptors and memory mappings in `users` refer to `fref`.
+    fn retarget(&mut self, users: &InodeUsers, fref: FdRefType) {
+        for &fd in &users.fds {
+            self.fd_table[fd as usize]
+                .as_mut()
+                .unwrap()
+                .borrow_mut()
+                .fref = fref.clone();
+        }
+        for &start in &users.mappings {
+            self.mappings.find_mut(start).unwrap().fref = fref.clone();
+        }
+    }
+
     /// Get all file descriptors referring to the same inode as `fref`
     fn all_fds_ref_same_inode(&self, fref: &FdRefType) -> Vec<isize> {
         self.all_fds()
@@ -456,3 +2605,195 @@ impl FileSystem {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe::{PIPE_CAPACITY, PIPE_SLOTS};
+
+    /// Address of test mappings.
+    const ADDR: usize = 0x4000_0000;
+
+    fn file(name: &str) -> AbsPath {
+        AbsPath::new(name).unwrap()
+    }
+
+    /// Create regular file `name` if missing, and open it with `flags`.
+    fn open(fs: &mut FileSystem, name: &str, flags: OpenFlags) -> isize {
+        let path = file(name);
+        if fs.lookup(&path).is_err() {
+            fs.create(path.clone(), FileKind::File, FileMode::all())
+                .unwrap();
+        } else {
+            fs.open(&path, flags).unwrap();
+        }
+        let id = fs.alloc_ofd_id();
+        fs.alloc_fd(Rc::new(RefCell::new(FileDescriptor::new_perm(
+            id, path, flags,
+        ))))
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_stop_at_end_of_file() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        assert_eq!(fs.write(fd, &[1; 10]).unwrap(), 10);
+        assert_eq!(fs.lookup(&file("f")).unwrap().size, 10);
+        let fd = open(&mut fs, "f", OpenFlags::RDONLY);
+        assert_eq!(fs.read(fd, 6).unwrap(), 6);
+        assert_eq!(fs.read(fd, 6).unwrap(), 4);
+        assert_eq!(fs.read(fd, 6).unwrap(), 0);
+    }
+
+    #[test]
+    fn append_writes_at_end_of_file() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::WRONLY);
+        let append = open(&mut fs, "f", OpenFlags::WRONLY | OpenFlags::APPEND);
+        fs.write(fd, &[1; 10]).unwrap();
+        fs.write(append, &[1; 5]).unwrap();
+        assert_eq!(fs.lookup(&file("f")).unwrap().size, 15);
+        // The other description keeps its own offset.
+        fs.write(fd, &[1; 2]).unwrap();
+        assert_eq!(fs.lookup(&file("f")).unwrap().size, 15);
+    }
+
+    #[test]
+    fn truncating_open_frees_blocks() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let (_, empty) = fs.usage();
+        let fd = open(&mut fs, "f", OpenFlags::WRONLY);
+        fs.write(fd, &[1; BLOCK_SIZE + 1]).unwrap();
+        assert_eq!(fs.usage().1, empty + 2);
+        open(&mut fs, "f", OpenFlags::WRONLY | OpenFlags::TRUNC);
+        assert_eq!(fs.lookup(&file("f")).unwrap().size, 0);
+        assert_eq!(fs.usage().1, empty);
+    }
+
+    #[test]
+    fn writes_are_short_when_out_of_blocks() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::WRONLY);
+        let (inodes, blocks) = fs.usage();
+        fs.set_capacity(Capacity {
+            inodes,
+            blocks: blocks + 1,
+        });
+        assert_eq!(fs.write(fd, &[1; 2 * BLOCK_SIZE]).unwrap(), BLOCK_SIZE);
+        assert!(matches!(fs.write(fd, &[1]), Err(FsError::NoSpace)));
+    }
+
+    #[test]
+    fn files_are_of_unknown_content_after_crash() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        fs.write(fd, &[1; 10]).unwrap();
+        fs.crash();
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        assert_eq!(fs.read(fd, 20).unwrap(), 10);
+        let flags = MapFlags::SHARED | MapFlags::FIXED;
+        fs.mmap(ADDR, PAGE_SIZE, ProtFlags::READ, flags, fd, 0)
+            .unwrap();
+        assert!(!fs.mem_read_determined(ADDR));
+        // Truncating leaves nothing unknown.
+        open(&mut fs, "f", OpenFlags::RDWR | OpenFlags::TRUNC);
+        fs.write(fd, &[2]).unwrap();
+        assert!(fs.mem_read_determined(ADDR));
+    }
+
+    #[test]
+    fn truncation_drops_private_pages() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        fs.write(fd, &[1; 10]).unwrap();
+        let prot = ProtFlags::READ | ProtFlags::WRITE;
+        let flags = MapFlags::PRIVATE | MapFlags::FIXED;
+        fs.mmap(ADDR, PAGE_SIZE, prot, flags, fd, 0).unwrap();
+        fs.mem_write(ADDR, 3).unwrap();
+        assert_eq!(fs.mem_read(ADDR).unwrap(), 3);
+        let fd = open(&mut fs, "f", OpenFlags::RDWR | OpenFlags::TRUNC);
+        fs.write(fd, &[2]).unwrap();
+        assert_eq!(fs.mem_read(ADDR).unwrap(), 2);
+    }
+
+    #[test]
+    #[cfg(not(feature = "fat"))]
+    fn punching_holes_frees_blocks() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let (_, empty) = fs.usage();
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        fs.fallocate(fd, FallocFlags::empty(), 0, 3 * BLOCK_SIZE)
+            .unwrap();
+        assert_eq!(fs.usage().1, empty + 3);
+        let mode = FallocFlags::PUNCH_HOLE | FallocFlags::KEEP_SIZE;
+        fs.fallocate(fd, mode, BLOCK_SIZE, BLOCK_SIZE).unwrap();
+        assert_eq!(fs.usage().1, empty + 2);
+        assert_eq!(fs.lookup(&file("f")).unwrap().size, 3 * BLOCK_SIZE);
+        let hole = fs.lseek(fd, 0, Whence::Hole).unwrap();
+        assert_eq!(hole, BLOCK_SIZE);
+        fs.ftruncate(fd, BLOCK_SIZE).unwrap();
+        assert_eq!(fs.usage().1, empty + 1);
+    }
+
+    #[test]
+    fn spliced_pages_are_not_appended_to() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        fs.write(fd, &[1; PIPE_SLOTS]).unwrap();
+        let (rfd, wfd) = fs.pipe(OpenFlags::NONBLOCK).unwrap();
+        for off in 0..PIPE_SLOTS {
+            assert_eq!(fs.splice(fd, Some(off), wfd, None, 1).unwrap(), 1);
+        }
+        assert!(matches!(fs.write(wfd, &[1]), Err(FsError::WouldBlock)));
+        assert_eq!(fs.read(rfd, PIPE_CAPACITY).unwrap(), PIPE_SLOTS);
+    }
+
+    #[test]
+    fn file_sizes_are_compared() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::WRONLY);
+        let before = fs.clone();
+        fs.write(fd, &[1]).unwrap();
+        assert!(!fs.matches(&before));
+    }
+
+    #[test]
+    fn clones_copy_open_file_descriptions() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        let dup = fs.alloc_fd(fs.get_fd(fd).unwrap()).unwrap();
+        let mut copy = fs.clone();
+        copy.lseek(fd, 1, Whence::Set).unwrap();
+        assert_eq!(copy.get_fd(dup).unwrap().borrow().offset, 1);
+        assert_eq!(fs.get_fd(fd).unwrap().borrow().offset, 0);
+    }
+
+    #[test]
+    fn snapshots_keep_shared_descriptions() {
+        let mut fs = FileSystem::new_root(0, 0);
+        let fd = open(&mut fs, "f", OpenFlags::RDWR);
+        let dup = fs.alloc_fd(fs.get_fd(fd).unwrap()).unwrap();
+        fs.flock(fd, FlockFlags::EX).unwrap();
+        let ino = fs.lookup(&file("f")).unwrap().ino;
+        fs.unknown_contents.insert(ino);
+
+        let mut loaded = FileSystem::from_json(&fs.to_json()).unwrap();
+        assert!(Rc::ptr_eq(
+            &loaded.get_fd(fd).unwrap(),
+            &loaded.get_fd(dup).unwrap()
+        ));
+        assert!(loaded.unknown_contents.contains(&ino));
+        // A new open file description gets a fresh id, and conflicts with the
+        // lock the dup owns.
+        let other = open(&mut loaded, "f", OpenFlags::RDWR);
+        assert!(matches!(
+            loaded.flock(other, FlockFlags::SH | FlockFlags::NB),
+            Err(FsError::WouldBlock)
+        ));
+        loaded.flock(dup, FlockFlags::UN).unwrap();
+        loaded
+            .flock(other, FlockFlags::SH | FlockFlags::NB)
+            .unwrap();
+    }
+}
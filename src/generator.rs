@@ -0,0 +1,184 @@
+use bitflags::Flags;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Random stream shared by the generators of a step.
+///
+/// The stream of step `n` of a run with seed `seed` is stream `n` of the ChaCha
+/// generator seeded with `seed`, so any step can be drawn again from the seed and
+/// its number alone.
+#[derive(Clone)]
+pub struct Rng(Rc<RefCell<ChaCha8Rng>>);
+
+impl Rng {
+    /// Get the stream of step `step` of a run with `seed`.
+    pub fn new(seed: u64, step: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        rng.set_stream(step);
+        Self(Rc::new(RefCell::new(rng)))
+    }
+
+    /// Draw an index below `n`, which must not be 0.
+    fn below(&self, n: usize) -> usize {
+        (self.0.borrow_mut().next_u64() % n as u64) as usize
+    }
+
+    /// Draw true with probability `p`.
+    fn chance(&self, p: f64) -> bool {
+        // The 53 high bits give a uniform float in [0, 1).
+        let x = (self.0.borrow_mut().next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        x < p
+    }
+}
+
+/// Generator of argument values.
+pub trait Generator<T> {
+    /// Generate a value, `None` if there is none to choose from.
+    fn try_generate(&mut self) -> Option<T>;
+
+    /// Generate a value. Panics if there is none to choose from.
+    fn generate(&mut self) -> T {
+        self.try_generate().expect("no value to generate")
+    }
+}
+
+/// Always the same value.
+pub struct Constant<T>(T);
+
+impl<T: Clone> Constant<T> {
+    /// Create a generator of `value`.
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Clone> Generator<T> for Constant<T> {
+    fn try_generate(&mut self) -> Option<T> {
+        Some(self.0.clone())
+    }
+}
+
+/// One of a collection of values, with equal probability. Values may be repeated
+/// to weigh them.
+pub struct UniformCollection<T> {
+    rng: Rng,
+    values: Vec<T>,
+}
+
+impl<T: Clone> UniformCollection<T> {
+    /// Create a generator of `values`, drawing from `rng`.
+    pub fn new(rng: &Rng, values: Vec<T>) -> Self {
+        Self {
+            rng: rng.clone(),
+            values,
+        }
+    }
+}
+
+impl<T: Clone> Generator<T> for UniformCollection<T> {
+    fn try_generate(&mut self) -> Option<T> {
+        if self.values.is_empty() {
+            return None;
+        }
+        Some(self.values[self.rng.below(self.values.len())].clone())
+    }
+}
+
+/// A constant with probability `p`, otherwise a value of another generator.
+pub struct SwitchConstant<T, G> {
+    rng: Rng,
+    constant: Constant<T>,
+    other: G,
+    p: f64,
+}
+
+impl<T: Clone, G: Generator<T>> SwitchConstant<T, G> {
+    /// Create a generator of `constant` with probability `p`, and of `other`
+    /// otherwise, drawing from `rng`.
+    pub fn new(rng: &Rng, constant: Constant<T>, other: G, p: f64) -> Self {
+        Self {
+            rng: rng.clone(),
+            constant,
+            other,
+            p,
+        }
+    }
+}
+
+impl<T: Clone, G: Generator<T>> Generator<T> for SwitchConstant<T, G> {
+    fn try_generate(&mut self) -> Option<T> {
+        if self.rng.chance(self.p) {
+            self.constant.try_generate()
+        } else {
+            self.other.try_generate()
+        }
+    }
+}
+
+/// A value of another generator, or `default` if it has none to choose from.
+pub struct DefaultOr<T, G> {
+    default: T,
+    other: G,
+}
+
+impl<T: Clone, G: Generator<T>> DefaultOr<T, G> {
+    /// Create a generator of the values of `other`, or `default`.
+    pub fn new(default: T, other: G) -> Self {
+        Self { default, other }
+    }
+}
+
+impl<T: Clone, G: Generator<T>> Generator<T> for DefaultOr<T, G> {
+    fn try_generate(&mut self) -> Option<T> {
+        Some(
+            self.other
+                .try_generate()
+                .unwrap_or_else(|| self.default.clone()),
+        )
+    }
+}
+
+/// Flags, each named flag set with probability `p`. Included flags are always
+/// set, excluded ones never.
+pub struct RandomFlags<F> {
+    rng: Rng,
+    p: f64,
+    include: F,
+    exclude: F,
+}
+
+impl<F: Flags + Copy> RandomFlags<F> {
+    /// Create a generator setting each flag with probability `p`, drawing from `rng`.
+    pub fn new(rng: &Rng, p: f64) -> Self {
+        Self {
+            rng: rng.clone(),
+            p,
+            include: F::empty(),
+            exclude: F::empty(),
+        }
+    }
+
+    /// Always set `flags`.
+    pub fn include(&mut self, flags: F) {
+        self.include.insert(flags);
+    }
+
+    /// Never set `flags`.
+    pub fn exclude(&mut self, flags: F) {
+        self.exclude.insert(flags);
+    }
+}
+
+impl<F: Flags + Copy> Generator<F> for RandomFlags<F> {
+    fn try_generate(&mut self) -> Option<F> {
+        let mut flags = F::empty();
+        for flag in F::FLAGS {
+            if self.rng.chance(self.p) {
+                flags.insert(*flag.value());
+            }
+        }
+        Some(flags.difference(self.exclude).union(self.include))
+    }
+}
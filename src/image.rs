@@ -0,0 +1,294 @@
+use crate::{
+    finding::Finding,
+    inode::Inode,
+    path::{AbsPath, RelPath},
+    FileSystem,
+};
+use km_command::fs::{FileKind, FileMode};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+/// Size of a tar header or data block.
+const TAR_BLOCK: usize = 512;
+
+/// Entry of an initial state image.
+#[derive(Debug, Clone)]
+pub(crate) enum Entry {
+    /// Inode first found at `path`, with its content for a regular file. The
+    /// content may be shorter than the file, the rest reads as zeros.
+    Inode {
+        path: AbsPath,
+        inode: Inode,
+        data: Vec<u8>,
+    },
+    /// Hard link at `path` to the inode first found at `target`.
+    Link { path: AbsPath, target: AbsPath },
+}
+
+/// Failure to read an initial state image.
+#[derive(Debug)]
+pub enum ImageError {
+    /// The image could not be read.
+    Io(io::Error),
+    /// The tar archive is malformed.
+    Format(String),
+    /// The entry at the path has a kind the model does not support, e.g. a
+    /// symbolic link.
+    Unsupported(String),
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Initial state of a file system: inodes with their modes, owners and contents,
+/// and the hard links between them.
+///
+/// An image is read from a local directory tree, a ustar archive, or a state
+/// retrieved from the target, and the model starts from it with
+/// `FileSystem::from_image`.
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    /// Entries, each after its parent directory. Links come last.
+    entries: Vec<Entry>,
+    /// Whether the contents of regular files are unknown, for a state retrieved
+    /// from the target.
+    unknown_contents: bool,
+    /// Inconsistencies found while retrieving the state of the target.
+    findings: Vec<Finding>,
+}
+
+impl Image {
+    /// Read the tree below local directory `dir`, which becomes the root.
+    pub fn from_dir(dir: &Path) -> Result<Self, ImageError> {
+        let mut image = Self::default();
+        let mut seen = HashMap::new();
+        let mut pending = vec![(dir.to_path_buf(), AbsPath::root())];
+        while let Some((local, path)) = pending.pop() {
+            let meta = fs::symlink_metadata(&local)?;
+            let ft = meta.file_type();
+            let kind = if ft.is_dir() {
+                FileKind::Directory
+            } else if ft.is_file() {
+                FileKind::File
+            } else if ft.is_fifo() {
+                FileKind::Fifo
+            } else if ft.is_char_device() {
+                FileKind::CharDevice
+            } else if ft.is_block_device() {
+                FileKind::BlockDevice
+            } else if ft.is_socket() {
+                FileKind::Socket
+            } else {
+                return Err(ImageError::Unsupported(format!("{:?}", path)));
+            };
+            if kind != FileKind::Directory {
+                if let Some(target) = seen.get(&(meta.dev(), meta.ino())) {
+                    image.push_link(path, target.clone());
+                    continue;
+                }
+                seen.insert((meta.dev(), meta.ino()), path.clone());
+            }
+            let mut inode = Inode::new(
+                FileMode::from_bits_truncate((meta.mode() & 0o7777) as _),
+                meta.uid(),
+                meta.gid(),
+                kind,
+            );
+            if matches!(kind, FileKind::CharDevice | FileKind::BlockDevice) {
+                inode.rdev = meta.rdev();
+            }
+            let data = match kind {
+                FileKind::File => fs::read(&local)?,
+                FileKind::Directory => {
+                    for child in fs::read_dir(&local)? {
+                        let child = child?;
+                        let name = child.file_name().into_string().map_err(|name| {
+                            ImageError::Unsupported(format!("{:?}/{:?}", path, name))
+                        })?;
+                        pending.push((child.path(), path.join(&RelPath::new(name)).unwrap()));
+                    }
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            };
+            image.push_inode(path, inode, data);
+        }
+        image.sort();
+        Ok(image)
+    }
+
+    /// Read a ustar archive. Paths are taken relative to the root, and directories
+    /// missing from the archive are not created.
+    pub fn from_tar(bytes: &[u8]) -> Result<Self, ImageError> {
+        let mut image = Self::default();
+        let mut off = 0;
+        while off + TAR_BLOCK <= bytes.len() {
+            let header = &bytes[off..off + TAR_BLOCK];
+            off += TAR_BLOCK;
+            // The archive ends with zero blocks.
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            let size = octal(&header[124..136])? as usize;
+            let data_end = off + size;
+            if data_end > bytes.len() {
+                return Err(ImageError::Format("truncated entry data".to_owned()));
+            }
+            let data = &bytes[off..data_end];
+            off += size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+            // Long names are split into a prefix and a name.
+            let prefix = field(&header[345..500])?;
+            let name = if prefix.is_empty() {
+                field(&header[0..100])?.to_owned()
+            } else {
+                format!("{}/{}", prefix, field(&header[0..100])?)
+            };
+            let path = tar_path(&name)?;
+            let kind = match header[156] {
+                b'0' | b'\0' | b'7' => FileKind::File,
+                b'1' => {
+                    let target = tar_path(field(&header[157..257])?)?;
+                    image.push_link(path, target);
+                    continue;
+                }
+                b'3' => FileKind::CharDevice,
+                b'4' => FileKind::BlockDevice,
+                b'5' => FileKind::Directory,
+                b'6' => FileKind::Fifo,
+                // Extended headers only carry metadata the model does not check.
+                b'x' | b'g' => continue,
+                _ => return Err(ImageError::Unsupported(name)),
+            };
+            let mut inode = Inode::new(
+                FileMode::from_bits_truncate((octal(&header[100..108])? & 0o7777) as _),
+                octal(&header[108..116])? as u32,
+                octal(&header[116..124])? as u32,
+                kind,
+            );
+            if matches!(kind, FileKind::CharDevice | FileKind::BlockDevice) {
+                inode.rdev = makedev(octal(&header[329..337])?, octal(&header[337..345])?);
+            }
+            let data = match kind {
+                FileKind::File => data.to_vec(),
+                _ => Vec::new(),
+            };
+            image.push_inode(path, inode, data);
+        }
+        image.sort();
+        Ok(image)
+    }
+
+    /// Take the inodes and links of a state retrieved from the target, e.g. by
+    /// `FsTestPort::snapshot`.
+    ///
+    /// Contents are not retrieved, so regular files are of unknown content, and the
+    /// model does not check the bytes read from them. Mounted file systems are
+    /// taken as part of the root one.
+    pub fn from_state(state: &FileSystem) -> Self {
+        let mut image = Self {
+            unknown_contents: true,
+            ..Self::default()
+        };
+        let mut seen = HashMap::new();
+        let mut paths = state.paths();
+        paths.sort();
+        for path in paths {
+            let inode = state.lookup(&path).unwrap();
+            if inode.kind != FileKind::Directory {
+                if let Some(target) = seen.get(&(inode.dev, inode.ino)) {
+                    image.push_link(path, target.clone());
+                    continue;
+                }
+                seen.insert((inode.dev, inode.ino), path.clone());
+            }
+            image.push_inode(path, inode, Vec::new());
+        }
+        image.sort();
+        image
+    }
+
+    /// Keep `findings` of the target the image is taken from.
+    pub(crate) fn with_findings(self, findings: Vec<Finding>) -> Self {
+        Self { findings, ..self }
+    }
+
+    /// Get the inconsistencies found while retrieving the state the image is taken
+    /// from. The model starts from the image all the same.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Get the entries, each after its parent directory, links last.
+    pub(crate) fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Check if the contents of regular files are unknown, see `from_state`.
+    pub(crate) fn unknown_contents(&self) -> bool {
+        self.unknown_contents
+    }
+
+    /// Add inode `inode` at `path` with content `data`.
+    fn push_inode(&mut self, path: AbsPath, inode: Inode, data: Vec<u8>) {
+        self.entries.push(Entry::Inode { path, inode, data });
+    }
+
+    /// Add a hard link at `path` to the inode at `target`.
+    fn push_link(&mut self, path: AbsPath, target: AbsPath) {
+        self.entries.push(Entry::Link { path, target });
+    }
+
+    /// Order the entries so that parents come before children, and links after
+    /// all inodes.
+    fn sort(&mut self) {
+        self.entries.sort_by(|a, b| match (a, b) {
+            (Entry::Inode { path: a, .. }, Entry::Inode { path: b, .. })
+            | (Entry::Link { path: a, .. }, Entry::Link { path: b, .. }) => a.cmp(b),
+            (Entry::Inode { .. }, Entry::Link { .. }) => std::cmp::Ordering::Less,
+            (Entry::Link { .. }, Entry::Inode { .. }) => std::cmp::Ordering::Greater,
+        });
+    }
+}
+
+/// Parse a NUL or space terminated octal number of a tar header.
+fn octal(field: &[u8]) -> Result<u64, ImageError> {
+    let digits = field
+        .iter()
+        .take_while(|&&b| b != 0 && b != b' ')
+        .skip_while(|&&b| b == b' ');
+    let mut value = 0u64;
+    for &b in digits {
+        if !(b'0'..=b'7').contains(&b) {
+            return Err(ImageError::Format(format!("bad octal field {:?}", field)));
+        }
+        value = value * 8 + (b - b'0') as u64;
+    }
+    Ok(value)
+}
+
+/// Get a NUL terminated string field of a tar header.
+fn field(field: &[u8]) -> Result<&str, ImageError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    std::str::from_utf8(&field[..len])
+        .map_err(|_| ImageError::Format(format!("bad name field {:?}", field)))
+}
+
+/// Get the path of a tar entry name, e.g. "./a/b/" is "/a/b".
+fn tar_path(name: &str) -> Result<AbsPath, ImageError> {
+    AbsPath::new(&format!("/{}", name))
+        .map_err(|_| ImageError::Format(format!("bad path {}", name)))
+}
+
+/// Make a Linux device number from its major and minor parts.
+fn makedev(major: u64, minor: u64) -> u64 {
+    ((major & 0xfffff000) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & 0xffffff00) << 12)
+        | (minor & 0xff)
+}
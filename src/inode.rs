@@ -1,9 +1,18 @@
 use km_command::fs::{FileKind, FileMode, FileStat};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-/// File system I-node type, regular file or directory.
-#[derive(Debug, Clone)]
+/// Set-group-ID bit of a file mode.
+pub const MODE_SETGID: FileMode = FileMode::from_bits_retain(0o2000);
+
+/// Sticky bit of a file mode.
+pub const MODE_STICKY: FileMode = FileMode::from_bits_retain(0o1000);
+
+/// File system I-node type, regular file, directory or special file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Inode {
     /// File model.
+    #[serde(with = "crate::snapshot::file_mode")]
     pub mode: FileMode,
     /// User ID.
     pub uid: u32,
@@ -12,13 +21,32 @@ pub struct Inode {
     /// Link count.
     pub nlink: usize,
     /// File kind.
+    #[serde(with = "crate::snapshot::file_kind")]
     pub kind: FileKind,
+    /// File size in bytes, only checked for regular files.
+    pub size: usize,
+    /// Number of 512-byte units allocated, as `st_blocks`. Not checked here, the
+    /// file system compares it with a tolerance if asked to.
+    pub blocks: usize,
+    /// Device number, for character and block devices.
+    pub rdev: u64,
+    /// Device number of the file system containing the inode. Not checked,
+    /// only used to tell mounted file systems apart.
+    pub dev: u64,
+    /// Extended attributes, name to value.
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// Inode number. Not checked, only used to identify the inode, e.g. in the
+    /// lock table.
+    pub ino: usize,
 }
 
 #[cfg(feature = "fat")]
 impl PartialEq for Inode {
     fn eq(&self, other: &Self) -> bool {
-        self.uid == other.uid && self.gid == other.gid && self.kind == other.kind
+        self.uid == other.uid
+            && self.gid == other.gid
+            && self.kind == other.kind
+            && (!self.is_file() || self.size == other.size)
     }
 }
 
@@ -30,6 +58,9 @@ impl PartialEq for Inode {
             && self.gid == other.gid
             && self.nlink == other.nlink
             && self.kind == other.kind
+            && (!self.is_file() || self.size == other.size)
+            && self.rdev == other.rdev
+            && self.xattrs == other.xattrs
     }
 }
 
@@ -47,6 +78,12 @@ impl Inode {
             gid,
             nlink,
             kind,
+            size: 0,
+            blocks: 0,
+            rdev: 0,
+            dev: 0,
+            xattrs: BTreeMap::new(),
+            ino: 0,
         }
     }
     /// Create an inode file file stat.
@@ -57,6 +94,12 @@ impl Inode {
             gid: stat.gid,
             nlink: stat.nlink,
             kind: stat.kind,
+            size: stat.size,
+            blocks: stat.blocks as usize,
+            rdev: stat.rdev,
+            dev: stat.dev,
+            xattrs: BTreeMap::new(),
+            ino: stat.ino,
         }
     }
     /// Check if the file is a directory.
@@ -67,4 +110,16 @@ impl Inode {
     pub fn is_file(&self) -> bool {
         self.kind == FileKind::File
     }
+    /// Check if the file is a FIFO, a device node or a socket.
+    pub fn is_special(&self) -> bool {
+        !self.is_dir() && !self.is_file()
+    }
+    /// Check if the set-group-ID bit is set.
+    pub fn is_setgid(&self) -> bool {
+        self.mode.contains(MODE_SETGID)
+    }
+    /// Check if the sticky bit is set.
+    pub fn is_sticky(&self) -> bool {
+        self.mode.contains(MODE_STICKY)
+    }
 }
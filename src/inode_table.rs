@@ -0,0 +1,292 @@
+use crate::content::SECTORS_PER_BLOCK;
+use crate::durability::{CrashOrdering, Durability};
+use crate::fs::DIRENTS_PER_BLOCK;
+use crate::inode::Inode;
+use crate::path::AbsPath;
+use multi_key_map::MultiKeyMap;
+use std::collections::HashMap;
+use std::ops::Deref;
+
+/// Inodes by absolute path, keeping count of the space the root file system uses and
+/// the history of the namespace since the last sync point.
+///
+/// Reads go to the inner map through `Deref`. All writes go through the methods
+/// below, which update the counters, so the usage never needs a rescan, and record
+/// the namespace before each change as a state that may be recovered after a crash.
+/// Each file system operation makes a single write, so that no intermediate state
+/// is recorded.
+#[derive(Clone)]
+pub struct InodeTable {
+    /// Inodes. An inode may have multiple absolutes paths (hard links).
+    inodes: MultiKeyMap<AbsPath, Inode>,
+    /// Inodes of the root file system, each counted once.
+    used_inodes: usize,
+    /// Blocks taken by directories and regular files of the root file system.
+    used_blocks: usize,
+    /// Number of entries of each directory of the root file system.
+    entries: HashMap<AbsPath, usize>,
+    /// Durability tracking, deciding the states allowed after a crash.
+    durability: Durability,
+}
+
+impl Deref for InodeTable {
+    type Target = MultiKeyMap<AbsPath, Inode>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inodes
+    }
+}
+
+impl PartialEq for InodeTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.inodes == other.inodes
+    }
+}
+
+impl Eq for InodeTable {}
+
+impl InodeTable {
+    /// Wrap `inodes`, counting the space they use. `inodes` are taken as persisted.
+    pub fn new(inodes: MultiKeyMap<AbsPath, Inode>) -> Self {
+        let mut table = Self {
+            inodes: MultiKeyMap::new(),
+            used_inodes: 0,
+            used_blocks: 0,
+            entries: HashMap::new(),
+            durability: Durability::new(CrashOrdering::Ordered, inodes.clone()),
+        };
+        // Insert parents first, so that entries are counted in their directory.
+        // The smallest path of an inode comes first of its names.
+        let mut paths: Vec<_> = inodes.keys().cloned().collect();
+        paths.sort();
+        for path in paths {
+            let first = inodes
+                .aliases(&path)
+                .unwrap()
+                .iter()
+                .min()
+                .cloned()
+                .unwrap();
+            if first == path {
+                table.add(path.clone(), inodes.get(&path).unwrap().clone());
+            } else {
+                table.add_alias(&first, path);
+            }
+        }
+        table
+    }
+
+    /// Wrap `inodes` with the history `durability`, counting the space they use.
+    pub fn with_durability(inodes: MultiKeyMap<AbsPath, Inode>, durability: Durability) -> Self {
+        Self {
+            durability,
+            ..Self::new(inodes)
+        }
+    }
+
+    /// Take the inodes of `other`, keeping the history. Return the replaced inodes.
+    pub fn update(&mut self, other: &Self) -> MultiKeyMap<AbsPath, Inode> {
+        self.used_inodes = other.used_inodes;
+        self.used_blocks = other.used_blocks;
+        self.entries = other.entries.clone();
+        std::mem::replace(&mut self.inodes, other.inodes.clone())
+    }
+
+    /// Durability tracking of the namespace.
+    pub fn durability(&self) -> &Durability {
+        &self.durability
+    }
+
+    /// Set the ordering guarantees of the file system under test, the current
+    /// state is taken as persisted.
+    pub fn set_crash_ordering(&mut self, ordering: CrashOrdering) {
+        self.durability = Durability::new(ordering, self.inodes.clone());
+    }
+
+    /// Persist the current state as a whole.
+    pub fn sync(&mut self) {
+        self.durability.sync(&self.inodes);
+    }
+
+    /// Persist the inode at `path`, and its entries if it is a directory.
+    pub fn fsync(&mut self, path: &AbsPath) {
+        self.durability.fsync(&self.inodes, path);
+    }
+
+    /// Check if `recovered` is a state allowed after a crash in the current state.
+    pub fn allows(&self, recovered: &Self) -> bool {
+        self.durability.allows(&self.inodes, &recovered.inodes)
+    }
+
+    /// Number of inodes and blocks used by the root file system.
+    pub fn usage(&self) -> (usize, usize) {
+        (self.used_inodes, self.used_blocks)
+    }
+
+    /// Number of entries of directory `path`, `None` if it is not a directory of
+    /// the root file system.
+    pub fn entries(&self, path: &AbsPath) -> Option<usize> {
+        self.entries.get(path).copied()
+    }
+
+    /// Insert a new inode at `path`. A directory adds a link to its parent.
+    pub fn insert(&mut self, path: AbsPath, inode: Inode) {
+        let parent = path.parent();
+        self.touch(std::iter::once(&path).chain(&parent));
+        let is_dir = inode.is_dir();
+        self.add(path, inode);
+        if let Some(parent) = parent.filter(|_| is_dir) {
+            self.inodes.get_mut(&parent).unwrap().nlink += 1;
+        }
+    }
+
+    /// Make `path` another name of the inode at `existing`, adding a link to it.
+    pub fn link(&mut self, existing: &AbsPath, path: AbsPath) {
+        self.touch([existing, &path]);
+        self.add_alias(existing, path);
+        self.inodes.get_mut(existing).unwrap().nlink += 1;
+    }
+
+    /// Remove the inode at `path`, which must be its only name. A directory drops
+    /// a link of its parent.
+    pub fn remove(&mut self, path: &AbsPath) -> Option<Inode> {
+        let parent = path.parent();
+        self.touch(std::iter::once(path).chain(&parent));
+        let inode = self.inodes.get(path)?.clone();
+        self.inodes.remove(path).unwrap();
+        self.count_inode(path, &inode, false);
+        self.count_entry(path, false);
+        if let Some(parent) = parent.filter(|_| inode.is_dir()) {
+            self.inodes.get_mut(&parent).unwrap().nlink -= 1;
+        }
+        Some(inode)
+    }
+
+    /// Remove the name `path` of an inode which has other names, dropping a link
+    /// to it.
+    pub fn remove_alias(&mut self, path: &AbsPath) -> Option<()> {
+        self.touch([path]);
+        let aliases = self.inodes.aliases(path)?;
+        self.inodes.remove_alias(path).unwrap();
+        self.count_entry(path, false);
+        let other = aliases.iter().find(|alias| *alias != path).unwrap();
+        self.inodes.get_mut(other).unwrap().nlink -= 1;
+        Some(())
+    }
+
+    /// Replace the inode at `path` by another one, e.g. the root of a file system
+    /// mounted there. Return the replaced inode.
+    pub fn replace(&mut self, path: &AbsPath, inode: Inode) -> Option<Inode> {
+        self.touch([path]);
+        let old = self.inodes.get(path)?.clone();
+        self.count_inode(path, &old, false);
+        self.count_inode(path, &inode, true);
+        *self.inodes.get_mut(path)? = inode;
+        Some(old)
+    }
+
+    /// Set the size and the allocated 512-byte units of the regular file at `path`.
+    pub fn resize(&mut self, path: &AbsPath, size: usize, blocks: usize) -> Option<()> {
+        self.touch([path]);
+        let root_dev = self.root_dev();
+        let inode = self.inodes.get_mut(path)?;
+        inode.size = size;
+        let old = std::mem::replace(&mut inode.blocks, blocks);
+        if Some(inode.dev) == root_dev {
+            self.used_blocks = self.used_blocks + blocks.div_ceil(SECTORS_PER_BLOCK)
+                - old.div_ceil(SECTORS_PER_BLOCK);
+        }
+        Some(())
+    }
+
+    /// Get the inode at `path` for modification. The kind, device number, size and
+    /// blocks of the inode must not be changed, use `replace` and `resize` for that.
+    pub fn get_mut(&mut self, path: &AbsPath) -> Option<&mut Inode> {
+        self.touch([path]);
+        self.inodes.get_mut(path)
+    }
+
+    /// Note that the inodes at `paths`, under all their names, are about to change.
+    fn touch<'a>(&mut self, paths: impl IntoIterator<Item = &'a AbsPath>) {
+        let mut touched = Vec::new();
+        for path in paths {
+            match self.inodes.aliases(path) {
+                Some(aliases) => touched.extend(aliases.iter().cloned()),
+                None => touched.push(path.clone()),
+            }
+        }
+        self.durability.touch(&self.inodes, &touched);
+    }
+
+    /// Add `inode` at `path`, counting it.
+    fn add(&mut self, path: AbsPath, inode: Inode) {
+        self.count_inode(&path, &inode, true);
+        self.count_entry(&path, true);
+        self.inodes.insert(path, inode);
+    }
+
+    /// Add `path` as another name of the inode at `existing`, counting it.
+    fn add_alias(&mut self, existing: &AbsPath, path: AbsPath) {
+        self.count_entry(&path, true);
+        self.inodes.insert_alias(existing, path);
+    }
+
+    /// Device number of the root file system.
+    fn root_dev(&self) -> Option<u64> {
+        self.inodes.get(&AbsPath::root()).map(|inode| inode.dev)
+    }
+
+    /// Count `inode` at `path` as added or removed.
+    fn count_inode(&mut self, path: &AbsPath, inode: &Inode, added: bool) {
+        let root_dev = if path.is_root() {
+            Some(inode.dev)
+        } else {
+            self.root_dev()
+        };
+        if root_dev != Some(inode.dev) {
+            return;
+        }
+        let file_blocks = if inode.is_file() {
+            inode.blocks.div_ceil(SECTORS_PER_BLOCK)
+        } else {
+            0
+        };
+        if added {
+            self.used_inodes += 1;
+            self.used_blocks += file_blocks;
+            if inode.is_dir() {
+                self.entries.insert(path.clone(), 0);
+                self.used_blocks += dir_blocks(0);
+            }
+        } else {
+            self.used_inodes -= 1;
+            self.used_blocks -= file_blocks;
+            if let Some(entries) = self.entries.remove(path) {
+                self.used_blocks -= dir_blocks(entries);
+            }
+        }
+    }
+
+    /// Count the entry `path` as added to or removed from its parent directory.
+    fn count_entry(&mut self, path: &AbsPath, added: bool) {
+        let entries = match path
+            .parent()
+            .and_then(|parent| self.entries.get_mut(&parent))
+        {
+            Some(entries) => entries,
+            None => return,
+        };
+        let old = *entries;
+        if added {
+            *entries += 1;
+        } else {
+            *entries -= 1;
+        }
+        self.used_blocks = self.used_blocks + dir_blocks(*entries) - dir_blocks(old);
+    }
+}
+
+/// Number of blocks taken by a directory with `entries` entries.
+pub fn dir_blocks(entries: usize) -> usize {
+    (entries + 2).div_ceil(DIRENTS_PER_BLOCK)
+}
@@ -1,11 +1,42 @@
+mod acl;
+mod bisect;
+mod checkpoint;
 mod command;
 mod commander;
+mod content;
+#[cfg(feature = "crash")]
+mod crashpoint;
+mod dirstream;
+mod durability;
 mod error;
+mod finding;
 mod fs;
+mod generator;
+mod image;
 mod inode;
+mod inode_table;
+mod lock;
+mod mmap;
 mod path;
+mod pipe;
 mod port;
+mod scope;
+mod snapshot;
+mod xattr;
 
+pub use bisect::{bisect, CommandBuilder, Divergence, Trace};
+pub use checkpoint::{Checkpoint, SyncedPort};
 pub use commander::FsCommander;
-pub use fs::FileSystem;
-pub use port::FsTestPort;
+#[cfg(feature = "crash")]
+pub use crashpoint::{
+    check_crash_points, BlockOp, CrashError, CrashPoint, CrashRecorder, CrashSession,
+    RecordingDevice, RecoveryPort,
+};
+pub use durability::CrashOrdering;
+pub use finding::{Corruption, Finding};
+pub use fs::{Capacity, FileSystem, FreeSpace};
+pub use image::{Image, ImageError};
+pub use path::AbsPath;
+pub use port::{DirNlink, FsTestPort, WalkLimits};
+pub use scope::Scope;
+pub use snapshot::SNAPSHOT_VERSION;
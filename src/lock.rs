@@ -0,0 +1,176 @@
+use crate::error::FsError;
+use km_command::fs::{FlockFlags, LockKind};
+use serde::{Deserialize, Serialize};
+
+/// A `flock` lock, owned by an open file description.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileLock {
+    /// Id of the owner open file description.
+    ofd: usize,
+    /// Locked inode.
+    ino: usize,
+    /// Exclusive or shared.
+    exclusive: bool,
+}
+
+/// A POSIX record lock, owned by the process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RecordLock {
+    /// Locked inode.
+    ino: usize,
+    /// Start offset of the range.
+    start: u64,
+    /// End offset of the range, exclusive. `u64::MAX` means to end of file.
+    end: u64,
+    /// Write lock or read lock.
+    write: bool,
+}
+
+/// Advisory lock table.
+///
+/// - `flock` locks belong to open file descriptions. Locks of different open file
+///   descriptions on the same inode conflict, and are released when the last fd
+///   referring to the open file description is closed.
+/// - POSIX record locks belong to the process. As the model has only one process,
+///   they never conflict, and a new lock replaces the overlapping part of old locks.
+///   Closing any fd referring to an inode releases all record locks on the inode.
+///   `F_GETLK` therefore has nothing to report.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LockTable {
+    /// `flock` locks.
+    flocks: Vec<FileLock>,
+    /// POSIX record locks, sorted by (ino, start).
+    records: Vec<RecordLock>,
+}
+
+impl LockTable {
+    /// Apply `flock` operation `op` of open file description `ofd` on inode `ino`.
+    ///
+    /// Converting a lock first releases the old one, so a failed non-blocking
+    /// conversion leaves the open file description unlocked, as Linux does.
+    /// Blocking requests that conflict are treated as non-blocking, since the model
+    /// cannot wait.
+    pub fn flock(&mut self, ofd: usize, ino: usize, op: FlockFlags) -> Result<(), FsError> {
+        let kind = op - FlockFlags::NB;
+        let exclusive = if kind == FlockFlags::EX {
+            true
+        } else if kind == FlockFlags::SH {
+            false
+        } else if kind == FlockFlags::UN {
+            self.flocks.retain(|l| !(l.ofd == ofd && l.ino == ino));
+            return Ok(());
+        } else {
+            return Err(FsError::InvalidArgument);
+        };
+        let own = self
+            .flocks
+            .iter()
+            .position(|l| l.ofd == ofd && l.ino == ino);
+        if let Some(i) = own {
+            if self.flocks[i].exclusive == exclusive {
+                return Ok(());
+            }
+            self.flocks.remove(i);
+        }
+        let conflict = self
+            .flocks
+            .iter()
+            .any(|l| l.ino == ino && l.ofd != ofd && (exclusive || l.exclusive));
+        if conflict {
+            return Err(FsError::WouldBlock);
+        }
+        self.flocks.push(FileLock {
+            ofd,
+            ino,
+            exclusive,
+        });
+        Ok(())
+    }
+
+    /// Release `flock` locks of open file description `ofd`.
+    pub fn release_ofd(&mut self, ofd: usize) {
+        self.flocks.retain(|l| l.ofd != ofd);
+    }
+
+    /// Set a POSIX record lock of `kind` on range [`start`, `start + len`) of `ino`.
+    /// A zero `len` locks to end of file.
+    pub fn setlk(
+        &mut self,
+        ino: usize,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<(), FsError> {
+        let end = Self::range_end(start, len)?;
+        // Remove the range from the existing locks, splitting them if needed.
+        let mut records = Vec::new();
+        for lock in self.records.drain(..) {
+            if lock.ino != ino || lock.end <= start || lock.start >= end {
+                records.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                records.push(RecordLock {
+                    end: start,
+                    ..lock.clone()
+                });
+            }
+            if lock.end > end {
+                records.push(RecordLock { start: end, ..lock });
+            }
+        }
+        let write = match kind {
+            LockKind::Read => Some(false),
+            LockKind::Write => Some(true),
+            LockKind::Unlock => None,
+        };
+        if let Some(write) = write {
+            records.push(RecordLock {
+                ino,
+                start,
+                end,
+                write,
+            });
+        }
+        // Merge adjacent locks of the same kind.
+        records.sort_by_key(|l| (l.ino, l.start));
+        self.records.clear();
+        for lock in records {
+            match self.records.last_mut() {
+                Some(last)
+                    if last.ino == lock.ino
+                        && last.write == lock.write
+                        && last.end == lock.start =>
+                {
+                    last.end = lock.end;
+                }
+                _ => self.records.push(lock),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the arguments of a test for a POSIX record lock of `kind` on range
+    /// (`start`, `len`).
+    pub fn check_getlk(kind: LockKind, start: u64, len: u64) -> Result<(), FsError> {
+        if matches!(kind, LockKind::Unlock) {
+            return Err(FsError::InvalidArgument);
+        }
+        Self::range_end(start, len)?;
+        Ok(())
+    }
+
+    /// Release all POSIX record locks of the process on `ino`.
+    pub fn release_records(&mut self, ino: usize) {
+        self.records.retain(|l| l.ino != ino);
+    }
+
+    /// Get the exclusive end offset of range (`start`, `len`).
+    fn range_end(start: u64, len: u64) -> Result<u64, FsError> {
+        if len == 0 {
+            Ok(u64::MAX)
+        } else {
+            start.checked_add(len).ok_or(FsError::InvalidArgument)
+        }
+    }
+}
@@ -1,22 +1,236 @@
-use km_checker::{CheckLevel, Checker, MockTestPort, StdoutPrinter};
-use model_fs::{FileSystem, FsCommander};
+use km_checker::{CheckLevel, Checker, MockTestPort, StdoutPrinter, TestPort};
+use model_fs::{bisect, AbsPath, Capacity, Checkpoint, FileSystem, Image, Scope, Trace};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of steps between checkpoints and state dumps.
+const CHECKPOINT_INTERVAL: u64 = 5000;
+
+/// Default allowed difference of `st_blocks`, in 512-byte units, one 4 KiB block
+/// for indirect blocks of the target.
+const BLOCKS_TOLERANCE: usize = 8;
+
+/// Command line options.
+///
+/// `model-fs [--seed N] [--checkpoint FILE] [--resume FILE] [--bisect] [--check-every N]
+/// [--capacity INODES:BLOCKS] [--free-blocks-tolerance N] [--blocks-tolerance N]
+/// [--test-root DIR] [--ignore PATH]... [IMAGE]`
+#[derive(Default)]
+struct Options {
+    /// Seed of the command generator, random if not given.
+    seed: Option<u64>,
+    /// File to save checkpoints to.
+    checkpoint: Option<PathBuf>,
+    /// Checkpoint to resume from.
+    resume: Option<PathBuf>,
+    /// Replay from the last checkpoint on mismatch to find the first divergent step.
+    bisect: bool,
+    /// Check the full state only every N steps, and at checkpoints. The state is
+    /// checked after every step if not given.
+    check_every: Option<u64>,
+    /// Directory or ustar archive holding the initial state.
+    image: Option<PathBuf>,
+    /// Capacity of the file system under test, space is unlimited if not given.
+    capacity: Option<Capacity>,
+    /// Allowed difference of free blocks, free blocks are not compared if not given.
+    free_blocks_tolerance: Option<usize>,
+    /// Allowed difference of `st_blocks` of regular files, `BLOCKS_TOLERANCE` if not
+    /// given. FAT allocates whole clusters, so `st_blocks` is not compared there.
+    blocks_tolerance: Option<usize>,
+    /// Part of the target the model runs in, for both the model and the test port.
+    scope: Scope,
+}
+
+impl Options {
+    fn parse() -> Self {
+        let mut options = Self::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--seed" => {
+                    let seed = args.next().and_then(|s| s.parse().ok());
+                    options.seed = Some(seed.expect("--seed needs a number"));
+                }
+                "--checkpoint" => {
+                    options.checkpoint =
+                        Some(args.next().expect("--checkpoint needs a file").into())
+                }
+                "--resume" => {
+                    options.resume = Some(args.next().expect("--resume needs a file").into())
+                }
+                "--bisect" => options.bisect = true,
+                "--check-every" => {
+                    let n = args.next().and_then(|s| s.parse().ok()).filter(|&n| n > 0);
+                    options.check_every = Some(n.expect("--check-every needs a positive number"));
+                }
+                "--capacity" => {
+                    let capacity = args.next().and_then(|s| {
+                        let (inodes, blocks) = s.split_once(':')?;
+                        Some(Capacity {
+                            inodes: inodes.parse().ok()?,
+                            blocks: blocks.parse().ok()?,
+                        })
+                    });
+                    options.capacity = Some(capacity.expect("--capacity needs INODES:BLOCKS"));
+                }
+                "--free-blocks-tolerance" => {
+                    let tolerance = args.next().and_then(|s| s.parse().ok());
+                    options.free_blocks_tolerance =
+                        Some(tolerance.expect("--free-blocks-tolerance needs a number"));
+                }
+                "--blocks-tolerance" => {
+                    let tolerance = args.next().and_then(|s| s.parse().ok());
+                    options.blocks_tolerance =
+                        Some(tolerance.expect("--blocks-tolerance needs a number"));
+                }
+                "--test-root" => {
+                    let root = args.next().and_then(|s| AbsPath::new(&s).ok());
+                    options.scope.root = root.expect("--test-root needs a directory");
+                }
+                "--ignore" => {
+                    let path = args.next().and_then(|s| AbsPath::new(&s).ok());
+                    options.scope.ignore(path.expect("--ignore needs a path"));
+                }
+                _ if !arg.starts_with("--") && options.image.is_none() => {
+                    options.image = Some(arg.into())
+                }
+                _ => panic!("unknown option {}", arg),
+            }
+        }
+        options
+    }
+}
+
+/// Get the initial state, read from the directory or ustar archive `path`, or an
+/// empty root.
+fn initial_state(path: Option<&Path>) -> FileSystem {
+    let Some(path) = path else {
+        return FileSystem::new_root(0, 0);
+    };
+    let image = if path.is_dir() {
+        Image::from_dir(path)
+    } else {
+        std::fs::read(path)
+            .map_err(Into::into)
+            .and_then(|bytes| Image::from_tar(&bytes))
+    }
+    .expect("cannot read initial state image");
+    FileSystem::from_image(&image, 0, 0).expect("bad initial state image")
+}
+
+/// Get the initial state configured by `options`.
+fn configured_state(options: &Options) -> FileSystem {
+    let mut state = initial_state(options.image.as_deref());
+    if let Some(capacity) = options.capacity {
+        state.set_capacity(capacity);
+    }
+    if let Some(tolerance) = options.free_blocks_tolerance {
+        state.check_free_blocks(tolerance);
+    }
+    if !cfg!(feature = "fat") {
+        state.check_blocks(options.blocks_tolerance.unwrap_or(BLOCKS_TOLERANCE));
+    }
+    state.set_scope(options.scope.clone());
+    state
+}
 
 fn main() {
-    let mock_port = MockTestPort::new(FileSystem::new_root(0, 0));
-    let mut checker = Checker::new(
-        FsCommander,
-        mock_port,
-        StdoutPrinter,
-        FileSystem::new_root(0, 0),
-    );
-    let mut i = 1;
+    let options = Options::parse();
+    let checkpoint = match &options.resume {
+        Some(path) => Checkpoint::load(path).expect("cannot load checkpoint"),
+        None => {
+            let seed = options.seed.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64
+            });
+            Checkpoint::new(seed, 0, 0, &configured_state(&options))
+        }
+    };
+    // This binary drives the mock target, which holds a copy of the model state:
+    // verifying it on resume always succeeds, and replays start from the model
+    // state of the checkpoint. A `FsTestPort` on a real target takes its place,
+    // with the scope of the model set by `set_scope`.
+    let port = MockTestPort::new(checkpoint.model.clone());
+    run(&options, checkpoint, port, |last| {
+        MockTestPort::new(last.model.clone())
+    });
+}
+
+/// Run the checking loop from `checkpoint` on `port`.
+///
+/// On resume, the target state is first verified against the checkpoint. On a
+/// mismatch with `--bisect`, the steps since the last checkpoint are replayed on
+/// the port made by `replay_port`, once its target is verified to hold the state
+/// of that checkpoint.
+fn run<P, F>(options: &Options, checkpoint: Checkpoint, mut port: P, replay_port: F)
+where
+    P: TestPort<FileSystem>,
+    F: Fn(&Checkpoint) -> P,
+{
+    if options.resume.is_some() {
+        // Re-synchronize with the target before going on.
+        if !checkpoint
+            .verify(&mut port)
+            .expect("cannot retrieve target state")
+        {
+            println!("Error: target state does not match the checkpoint");
+            return;
+        }
+    }
+    eprintln!("Seed: {}", checkpoint.seed);
+    let seed = checkpoint.seed;
+    let mut i = checkpoint.steps + 1;
+    let trace = Trace::new();
+    let mut commander = checkpoint.commander();
+    if options.bisect {
+        commander.record(trace.clone());
+    }
+    let generated = commander.generated();
+    // Last checkpoint the target matched, to replay from.
+    let mut last = checkpoint.clone();
+    let mut checker = Checker::new(commander, port, StdoutPrinter, checkpoint.model);
     loop {
-        if let Err(e) = checker.step(CheckLevel::Relaxed, CheckLevel::Strict) {
+        // Checkpoints must be of a checked state, and a crash leaves the state
+        // unknown until it is checked.
+        let state_level = match options.check_every {
+            Some(n)
+                if i % n != 0 && i % CHECKPOINT_INTERVAL != 0 && !checker.state().recovering() =>
+            {
+                CheckLevel::None
+            }
+            _ => CheckLevel::Strict,
+        };
+        if let Err(e) = checker.step(CheckLevel::Relaxed, state_level) {
             println!("Error: {:?}", e);
+            if options.bisect {
+                println!("Replaying from step {}", last.steps);
+                let result = last.sync(replay_port(&last)).and_then(|port| match port {
+                    Some(port) => bisect(&last, &trace, port).map(Some),
+                    None => Ok(None),
+                });
+                match result {
+                    Ok(Some(Some(divergence))) => println!(
+                        "First divergent step: {}: {:?}",
+                        divergence.step, divergence.error
+                    ),
+                    Ok(Some(None)) => println!("Replay did not diverge"),
+                    Ok(None) => println!("Replay target does not match the checkpoint"),
+                    Err(e) => println!("Replay failed: {:?}", e),
+                }
+            }
             break;
         }
-        if i % 5000 == 0 {
+        if i % CHECKPOINT_INTERVAL == 0 {
             eprintln!("State: {:?}", checker.state());
+            last = Checkpoint::new(seed, i, generated.get(), checker.state());
+            trace.clear();
+            if let Some(path) = &options.checkpoint {
+                if let Err(e) = last.save(path) {
+                    eprintln!("Cannot save checkpoint: {}", e);
+                }
+            }
         }
         i += 1;
     }
@@ -0,0 +1,143 @@
+use crate::fs::FdRefType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Page size of the target.
+pub const PAGE_SIZE: usize = 4096;
+
+/// A file-backed memory mapping.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mapping {
+    /// Start address, page aligned.
+    pub start: usize,
+    /// Length in bytes, a multiple of the page size.
+    pub len: usize,
+    /// The mapped inode.
+    pub fref: FdRefType,
+    /// File offset of the start address, page aligned.
+    pub offset: usize,
+    /// Whether the mapping may be read.
+    pub readable: bool,
+    /// Whether the mapping may be written.
+    pub writable: bool,
+    /// `MAP_SHARED` or `MAP_PRIVATE`.
+    pub shared: bool,
+    /// Pages copied on write of a private mapping, by page index in the mapping.
+    pub private_pages: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Mapping {
+    /// Get the end address, exclusive.
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    /// Get the part of the mapping in [`start`, `end`), `None` if they do not overlap.
+    fn slice(&self, start: usize, end: usize) -> Option<Mapping> {
+        let start = start.max(self.start);
+        let end = end.min(self.end());
+        if start >= end {
+            return None;
+        }
+        let first_page = (start - self.start) / PAGE_SIZE;
+        let last_page = (end - self.start) / PAGE_SIZE;
+        Some(Mapping {
+            start,
+            len: end - start,
+            fref: self.fref.clone(),
+            offset: self.offset + (start - self.start),
+            private_pages: self
+                .private_pages
+                .range(first_page..last_page)
+                .map(|(idx, page)| (idx - first_page, page.clone()))
+                .collect(),
+            ..*self
+        })
+    }
+}
+
+/// Memory mappings of the process, by start address. Mappings never overlap.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MappingTable {
+    maps: BTreeMap<usize, Mapping>,
+}
+
+impl MappingTable {
+    /// Add `mapping`, replacing the parts of existing mappings it overlaps, as
+    /// `MAP_FIXED` does.
+    pub fn insert(&mut self, mapping: Mapping) {
+        self.remove(mapping.start, mapping.len);
+        self.maps.insert(mapping.start, mapping);
+    }
+
+    /// Remove [`start`, `start + len`) from the mappings, splitting the mappings
+    /// partially covered.
+    pub fn remove(&mut self, start: usize, len: usize) {
+        let end = start + len;
+        let overlapped: Vec<usize> = self
+            .maps
+            .values()
+            .filter(|m| m.start < end && start < m.end())
+            .map(|m| m.start)
+            .collect();
+        for addr in overlapped {
+            let mapping = self.maps.remove(&addr).unwrap();
+            for part in [
+                mapping.slice(mapping.start, start),
+                mapping.slice(end, mapping.end()),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                self.maps.insert(part.start, part);
+            }
+        }
+    }
+
+    /// Check if every page in [`start`, `start + len`) is mapped.
+    pub fn covers(&self, start: usize, len: usize) -> bool {
+        let mut addr = start;
+        while addr < start + len {
+            match self.find(addr) {
+                Some(mapping) => addr = mapping.end(),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Get the mappings overlapping [`start`, `start + len`).
+    pub fn range(&self, start: usize, len: usize) -> impl Iterator<Item = &Mapping> {
+        self.maps
+            .values()
+            .filter(move |m| m.start < start + len && start < m.end())
+    }
+
+    /// Find the mapping containing `addr`.
+    pub fn find(&self, addr: usize) -> Option<&Mapping> {
+        self.maps
+            .range(..=addr)
+            .next_back()
+            .map(|(_, m)| m)
+            .filter(|m| addr < m.end())
+    }
+
+    /// Find the mutable mapping containing `addr`.
+    pub fn find_mut(&mut self, addr: usize) -> Option<&mut Mapping> {
+        self.maps
+            .range_mut(..=addr)
+            .next_back()
+            .map(|(_, m)| m)
+            .filter(|m| addr < m.end())
+    }
+
+    /// Iterate over all mappings.
+    pub fn iter(&self) -> impl Iterator<Item = &Mapping> {
+        self.maps.values()
+    }
+
+    /// Remove all mappings.
+    pub fn clear(&mut self) {
+        self.maps.clear();
+    }
+}
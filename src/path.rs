@@ -1,12 +1,13 @@
 use crate::error::FsError;
 use km_command::fs::Path;
+use serde::{Deserialize, Serialize};
 use std::{fmt::Debug, vec};
 
 /// Normalized absolute file path.
 ///
 /// - Cannot contain "." or "..".
 /// - Cannot start or end with "/".
-#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
 pub struct AbsPath(String);
 
 impl Debug for AbsPath {
@@ -63,6 +64,40 @@ impl AbsPath {
         }
     }
 
+    /// Get the last component of this absolute path, `None` for root.
+    pub fn name(&self) -> Option<&str> {
+        if self.is_root() {
+            None
+        } else {
+            self.0.rsplit('/').next()
+        }
+    }
+
+    /// Get this path as seen from directory `base`, i.e. with the `base` prefix
+    /// removed. `None` if this path is neither `base` nor below it.
+    pub fn strip(&self, base: &Self) -> Option<Self> {
+        if base.is_root() {
+            Some(self.clone())
+        } else if self == base {
+            Some(Self::root())
+        } else {
+            self.0
+                .strip_prefix(&format!("{}/", base.0))
+                .map(|rest| Self(rest.to_owned()))
+        }
+    }
+
+    /// Get this path, seen from directory `base`, as a path below `base`.
+    pub fn under(&self, base: &Self) -> Self {
+        if self.is_root() {
+            base.clone()
+        } else if base.is_root() {
+            self.clone()
+        } else {
+            Self(format!("{}/{}", base.0, self.0))
+        }
+    }
+
     /// Concatenate a relative path to this absolute path.
     pub fn join(&self, rel_path: &RelPath) -> Result<Self, FsError> {
         let mut path = self.0.clone();
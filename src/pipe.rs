@@ -0,0 +1,168 @@
+use crate::error::FsError;
+use crate::mmap::PAGE_SIZE;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Number of buffer slots of a pipe, the Linux default.
+pub const PIPE_SLOTS: usize = 16;
+
+/// Capacity of a pipe in bytes, if every slot holds a full page.
+pub const PIPE_CAPACITY: usize = PIPE_SLOTS * PAGE_SIZE;
+
+/// A pipe buffer slot, holding part of a page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Slot {
+    /// Offset of the data in the page.
+    offset: usize,
+    /// Unread data.
+    data: Vec<u8>,
+    /// Whether writes may append to the page, i.e. the page is owned by the pipe.
+    can_merge: bool,
+}
+
+impl Slot {
+    /// Check if `len` more bytes can be appended to the slot.
+    fn fits(&self, len: usize) -> bool {
+        self.can_merge && self.offset + self.data.len() + len <= PAGE_SIZE
+    }
+}
+
+/// Buffer of a pipe or FIFO.
+///
+/// Like Linux, data is held in up to `PIPE_SLOTS` slots of one page each. A write
+/// appends its partial page to the last slot if it fits, and takes a new slot for
+/// each further page. Pages spliced in from files are not appended to, so a pipe may
+/// be full with less than `PIPE_CAPACITY` bytes in it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PipeBuffer {
+    /// Slots in use, oldest first.
+    slots: VecDeque<Slot>,
+    /// Whether data of unknown content was ever added, e.g. from a file of unknown
+    /// content.
+    unknown: bool,
+}
+
+impl PipeBuffer {
+    /// Get the number of bytes in the buffer.
+    pub fn len(&self) -> usize {
+        self.slots.iter().map(|slot| slot.data.len()).sum()
+    }
+
+    /// Check if the buffer holds no data.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Check if no slot is free.
+    pub fn is_full(&self) -> bool {
+        self.slots.len() >= PIPE_SLOTS
+    }
+
+    /// Mark the data added to the buffer as unknown.
+    pub fn mark_unknown(&mut self) {
+        self.unknown = true;
+    }
+
+    /// Check if all data ever added to the buffer is known to the model.
+    pub fn is_known(&self) -> bool {
+        !self.unknown
+    }
+
+    /// Write `data` as `pipe_write` does, returning the number of bytes written.
+    ///
+    /// The partial page at the start of `data` is appended to the last slot if it
+    /// fits, the rest is written page by page to free slots. Give `EAGAIN` if
+    /// nothing could be written.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FsError> {
+        let mut written = 0;
+        let chars = data.len() % PAGE_SIZE;
+        if chars != 0 {
+            if let Some(last) = self.slots.back_mut().filter(|last| last.fits(chars)) {
+                last.data.extend_from_slice(&data[..chars]);
+                written = chars;
+            }
+        }
+        while written < data.len() && !self.is_full() {
+            let n = (data.len() - written).min(PAGE_SIZE);
+            self.slots.push_back(Slot {
+                offset: 0,
+                data: data[written..written + n].to_vec(),
+                can_merge: true,
+            });
+            written += n;
+        }
+        if written == 0 && !data.is_empty() {
+            return Err(FsError::WouldBlock);
+        }
+        Ok(written)
+    }
+
+    /// Add up to `data.len()` bytes of a file at file offset `pos` in slots of their
+    /// own, split at page boundaries of the file, returning the number of bytes added.
+    pub fn splice_in(&mut self, data: &[u8], pos: usize) -> usize {
+        let mut added = 0;
+        while added < data.len() && !self.is_full() {
+            let offset = (pos + added) % PAGE_SIZE;
+            let n = (data.len() - added).min(PAGE_SIZE - offset);
+            self.slots.push_back(Slot {
+                offset,
+                data: data[added..added + n].to_vec(),
+                can_merge: false,
+            });
+            added += n;
+        }
+        added
+    }
+
+    /// Get up to `count` bytes from the front of the buffer, without removing them.
+    pub fn peek(&self, count: usize) -> Vec<u8> {
+        self.slots
+            .iter()
+            .flat_map(|slot| slot.data.iter().copied())
+            .take(count)
+            .collect()
+    }
+
+    /// Remove up to `count` bytes from the front of the buffer.
+    pub fn consume(&mut self, count: usize) {
+        let mut left = count;
+        while let Some(first) = self.slots.front_mut() {
+            if left < first.data.len() {
+                first.data.drain(..left);
+                first.offset += left;
+                return;
+            }
+            left -= first.data.len();
+            self.slots.pop_front();
+        }
+    }
+
+    /// Move up to `len` bytes to pipe buffer `out`, returning the number of bytes
+    /// moved.
+    ///
+    /// Whole slots are moved while they fit in `len`. A slot moved in part is split,
+    /// and the part in `out` is not appended to.
+    pub fn move_to(&mut self, out: &mut PipeBuffer, len: usize) -> usize {
+        out.unknown |= self.unknown;
+        let mut moved = 0;
+        while moved < len && !out.is_full() {
+            let Some(first) = self.slots.front_mut() else {
+                break;
+            };
+            let n = len - moved;
+            if n >= first.data.len() {
+                moved += first.data.len();
+                out.slots.push_back(self.slots.pop_front().unwrap());
+            } else {
+                out.slots.push_back(Slot {
+                    offset: first.offset,
+                    data: first.data.drain(..n).collect(),
+                    can_merge: false,
+                });
+                first.offset += n;
+                moved += n;
+            }
+        }
+        moved
+    }
+}
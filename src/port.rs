@@ -1,10 +1,18 @@
 use crate::{
     command::{
-        Close as ModelClose, Fstat as ModelFstat, Getcwd as ModelGetcwd, Getdents as ModelGetdents,
-        Nop, Openat as ModelOpenat,
+        Chdir as ModelChdir, Close as ModelClose, Fgetxattr as ModelFgetxattr,
+        Flistxattr as ModelFlistxattr, Fstat as ModelFstat, Fstatat as ModelFstatat,
+        Getcwd as ModelGetcwd, Getdents as ModelGetdents, Getxattrat as ModelGetxattrat,
+        Listxattrat as ModelListxattrat, Lseek as ModelLseek, Nop, Openat as ModelOpenat,
+        Statfs as ModelStatfs,
     },
+    finding::{Corruption, Finding},
+    fs::FreeSpace,
+    image::Image,
     inode::Inode,
-    path::AbsPath,
+    path::{AbsPath, RelPath},
+    scope::Scope,
+    xattr::{XATTR_LIST_MAX, XATTR_SIZE_MAX},
     FileSystem,
 };
 use core::str;
@@ -12,11 +20,17 @@ use km_checker::{
     Command, CommandChannel, Error, MemCommandChannel, QemuMem, StateChannel, TestPort,
 };
 use km_command::fs::{
-    Close, DirEntry, FileKind, FileMode, FileStat, Fstat, Getcwd, Getdents, OpenFlags, Openat,
-    Path, MAX_PATH_LEN,
+    AtFlags, Chdir, Close, DirEntry, Fgetxattr, FileKind, FileMode, FileStat, Flistxattr, FsStat,
+    Fstat, Fstatat, Getcwd, Getdents, Getxattrat, Listxattrat, Lseek, OpenFlags, Openat, Path,
+    Statfs, Whence, MAX_PATH_LEN,
 };
+use km_command::linux_err;
 use multi_key_map::MultiKeyMap;
-use std::{collections::HashMap, mem::size_of, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    mem::size_of,
+    str::FromStr,
+};
 
 /// Execution step of `FsTestPort`.
 enum Step {
@@ -24,12 +38,81 @@ enum Step {
     Open,
     /// Reading directory entries.
     Getdents,
+    /// Rewinding a directory to list it again.
+    Rewind,
+    /// Listing a directory again, to check that no entry is omitted.
+    Relist,
     /// Reading inode metadata.
     Fstat,
+    /// Reading metadata of a directory entry without opening it.
+    Fstatat,
+    /// Listing extended attribute names of an inode.
+    Listxattr,
+    /// Reading an extended attribute value of an inode.
+    Getxattr,
     /// Closing an inode.
     Close,
     /// Get current working directory.
     Getcwd,
+    /// Entering the test root again, after the target restarted.
+    Reenter,
+    /// Get free space of the file system.
+    Statfs,
+}
+
+/// Limits of the walk over the target directory graph.
+///
+/// A corrupt target may list a directory inside itself, nest directories without
+/// end, or list entries forever. Exceeding a limit is recorded as a finding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkLimits {
+    /// Maximum depth of directories below root.
+    pub depth: usize,
+    /// Maximum number of entries listed by a directory, "." and ".." included.
+    pub entries: usize,
+    /// Maximum number of steps of a retrieval.
+    pub steps: usize,
+}
+
+impl Default for WalkLimits {
+    fn default() -> Self {
+        Self {
+            depth: 64,
+            entries: 4096,
+            steps: 1 << 20,
+        }
+    }
+}
+
+/// `nlink` reported for directories by the file system under test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirNlink {
+    /// 2 plus the number of subdirectories, as most Unix file systems do.
+    Subdirs,
+    /// Always 1, as btrfs does.
+    One,
+    /// Not checked, e.g. FAT, whose driver makes link counts up.
+    Unchecked,
+}
+
+impl Default for DirNlink {
+    fn default() -> Self {
+        if cfg!(feature = "fat") {
+            Self::Unchecked
+        } else {
+            Self::Subdirs
+        }
+    }
+}
+
+/// Links found to an inode during the walk.
+struct Links {
+    /// Kind of the inode when first found.
+    kind: FileKind,
+    /// `nlink` of the inode when first found.
+    nlink: usize,
+    /// Paths found for the inode.
+    paths: Vec<AbsPath>,
 }
 
 /// Test port to communicate with target kernel.
@@ -39,8 +122,42 @@ enum Step {
 ///
 /// `FsTestPort` uses constant FS commands to get target file system state.
 ///
-/// - `getdents` to get directory structure.
+/// - `getdents` to get directory structure. The walk does not change the
+///   directories it reads, so each entry appears exactly once (see `DirStream`).
+///   Each directory is listed a second time after rewinding it with `lseek`, and
+///   a name listed twice in a pass, or in only one of the passes, is recorded as
+///   a finding. This is where the names returned by `getdents` are checked, the
+///   `Getdents` command of the model only checks the number of entries.
+/// - `fstatat` to get directory entry metadata. Only regular files and
+///   directories are opened, opening a FIFO would block.
 /// - `fstat` to get inode metadata.
+/// - `flistxattr` and `fgetxattr` to get extended attributes, `listxattrat` and
+///   `getxattrat` for special files. POSIX ACLs are compared as "system.posix_acl_*"
+///   attributes. Failing to list or read them is recorded as a finding.
+/// - `statfs` to get free inodes and blocks.
+///
+/// A directory whose `st_dev` differs from its parent's is recorded as a mount point.
+///
+/// Directory entries are checked against what `fstatat` reports, and "." and ".."
+/// against the directories they name. Inconsistencies are recorded as findings,
+/// which fail the retrieval once the walk is done, see `findings`. Entries listed
+/// twice, with an invalid name, or which cannot be opened are recorded and skipped;
+/// failing to open root fails the retrieval at once.
+///
+/// The `nlink` of each inode is checked against the links found: the paths of a
+/// file, or as set by `DirNlink` for a directory. Links may be in parts of the graph
+/// not walked, so a file found with fewer paths than its `nlink` is only reported
+/// if the walk skipped nothing, and a directory only if all its entries were
+/// inspected. An inode number found with two kinds is reported as reused.
+///
+/// A directory found twice is not walked again, and the walk stays within
+/// `WalkLimits`. On exceeding the step budget, the open directories are closed
+/// and the retrieval fails.
+///
+/// The walk may be scoped to a test root directory of the target and skip ignored
+/// subtrees, see `set_scope`. Ignored subdirectories are not counted in the `nlink`
+/// of their parent. A target found outside the test root has restarted, e.g. after
+/// a `Reboot`, and enters it again.
 pub struct FsTestPort {
     /// Command channel to send command to target kernel.
     cmd_chan: MemCommandChannel<QemuMem, QemuMem>,
@@ -50,8 +167,55 @@ pub struct FsTestPort {
     fs: MultiKeyMap<AbsPath, Inode>,
     /// DFS stack of opened inodes, (fd, name).
     stack: Vec<(isize, String)>,
-    /// Seen inode_id set, need to resolve hard links.
-    seen_inodes: HashMap<usize, AbsPath>,
+    /// Seen (device, inode_id) set, need to resolve hard links.
+    seen_inodes: HashMap<(u64, usize), AbsPath>,
+    /// Mount points found, with the device number of the mounted file system.
+    mounts: Vec<(AbsPath, u64)>,
+    /// Names listed by the first pass over each directory, "." and ".." included.
+    listed_names: HashMap<AbsPath, HashSet<String>>,
+    /// Names listed by the second pass over the stack top directory.
+    relisted_names: HashSet<String>,
+    /// Free space of the file system.
+    space: Option<FreeSpace>,
+    /// Name of the directory entry being inspected by `fstatat`.
+    entry: String,
+    /// `d_type` of the entry, `None` if unknown.
+    entry_kind: Option<FileKind>,
+    /// `d_ino` of the entry.
+    entry_ino: usize,
+    /// Links found to each (device, inode_id), for the `nlink` cross-check.
+    links: BTreeMap<(u64, usize), Links>,
+    /// Number of subdirectories found in each directory.
+    subdirs: HashMap<AbsPath, usize>,
+    /// Whether parts of the graph were not walked, which may hold links.
+    partial: bool,
+    /// Directories with entries of unknown kind.
+    partial_dirs: HashSet<AbsPath>,
+    /// Directory `nlink` rule of the file system under test.
+    dir_nlink: DirNlink,
+    /// Number of entries listed by each directory.
+    listed: HashMap<AbsPath, usize>,
+    /// Part of the target the model runs in, the root of the walk and the
+    /// subtrees excluded from it.
+    scope: Scope,
+    /// Number of ignored subdirectories in each directory.
+    ignored_subdirs: HashMap<AbsPath, usize>,
+    /// Inodes with links in ignored subtrees.
+    hidden_links: HashSet<(u64, usize)>,
+    /// Limits of the walk.
+    limits: WalkLimits,
+    /// Number of steps of the current retrieval.
+    steps: usize,
+    /// Whether the walk is closing the open directories to stop early.
+    unwinding: bool,
+    /// Inconsistencies found during the walk.
+    findings: Vec<Finding>,
+    /// Path of the inode whose extended attributes are being read.
+    xattr_path: AbsPath,
+    /// Whether the inode is the directory entry `entry`, rather than the stack top.
+    xattr_at_entry: bool,
+    /// Extended attribute names left to read, the last one is being read.
+    xattr_names: Vec<String>,
     /// Execution step.
     step: Step,
 }
@@ -65,10 +229,97 @@ impl FsTestPort {
             fs: MultiKeyMap::new(),
             stack: Vec::new(),
             seen_inodes: HashMap::new(),
+            mounts: Vec::new(),
+            listed_names: HashMap::new(),
+            relisted_names: HashSet::new(),
+            space: None,
+            entry: String::new(),
+            entry_kind: None,
+            entry_ino: 0,
+            links: BTreeMap::new(),
+            subdirs: HashMap::new(),
+            partial: false,
+            partial_dirs: HashSet::new(),
+            dir_nlink: DirNlink::default(),
+            listed: HashMap::new(),
+            scope: Scope::default(),
+            ignored_subdirs: HashMap::new(),
+            hidden_links: HashSet::new(),
+            limits: WalkLimits::default(),
+            steps: 0,
+            unwinding: false,
+            findings: Vec::new(),
+            xattr_path: AbsPath::root(),
+            xattr_at_entry: false,
+            xattr_names: Vec::new(),
             step: Step::Open,
         }
     }
 
+    /// Get the inconsistencies found by the last retrieval, which fails if there
+    /// are any.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Set the limits of the walk over the target directory graph.
+    pub fn set_walk_limits(&mut self, limits: WalkLimits) {
+        self.limits = limits;
+    }
+
+    /// Set the directory `nlink` rule of the file system under test.
+    pub fn set_dir_nlink(&mut self, dir_nlink: DirNlink) {
+        self.dir_nlink = dir_nlink;
+    }
+
+    /// Run the model inside `scope` of the target, the same scope as the model.
+    ///
+    /// The target enters the scope root. The walk starts there, skips the ignored
+    /// subtrees, and retrieved paths are seen from the root.
+    pub fn set_scope(&mut self, scope: Scope) -> Result<(), Error> {
+        self.scope = scope;
+        self.enter_test_root_command()?;
+        if self.receive_retv() < 0 {
+            return Err(Error::Io);
+        }
+        Ok(())
+    }
+
+    /// Enter the test root.
+    /// Send `chdir` command to target kernel.
+    fn enter_test_root_command(&mut self) -> Result<(), Error> {
+        let target = "/".to_owned() + &self.scope.root.to_string();
+        self.send_command(&ModelChdir(Chdir::new(Path(
+            heapless::String::from_str(&target).unwrap(),
+        ))))
+    }
+
+    /// Retrieve the state of the target as an image, so that the model starts
+    /// from what the target already holds.
+    ///
+    /// Findings do not fail the snapshot, they are kept in the image instead, see
+    /// `Image::findings`.
+    pub fn snapshot(&mut self) -> Result<Image, Error> {
+        self.start_state_retrieval()?;
+        while !self.retrieve_state_data()? {}
+        self.send_command(&Nop(km_command::Nop {}))?;
+        let state = self.retrieved_state();
+        Ok(Image::from_state(&state).with_findings(self.findings.clone()))
+    }
+
+    /// Build the state found by the walk, and check the `nlink` of files.
+    fn retrieved_state(&mut self) -> FileSystem {
+        let mut fs = FileSystem::new(self.fs.clone(), self.cwd.clone(), 0, 0);
+        for (path, dev) in self.mounts.drain(..) {
+            fs.record_mount(path, dev);
+        }
+        if let Some(space) = self.space.take() {
+            fs.record_free_space(space);
+        }
+        self.check_file_nlinks();
+        fs
+    }
+
     /// Get the stack top inode.
     fn top(&self) -> &(isize, String) {
         self.stack.last().unwrap()
@@ -104,13 +355,13 @@ impl FsTestPort {
         )))
     }
 
-    /// Get the newly opened fd from target kernel.
-    fn openat_result(&mut self) -> Result<isize, Error> {
+    /// Get the newly opened fd from target kernel, or the error number.
+    fn openat_result(&mut self) -> Result<isize, isize> {
         let retv = self.receive_retv();
         if retv >= 0 {
             Ok(retv)
         } else {
-            Err(Error::Io)
+            Err(retv)
         }
     }
 
@@ -133,20 +384,449 @@ impl FsTestPort {
         }
     }
 
+    /// Rewind the stack top directory to list it again.
+    /// Send `lseek` command to target kernel.
+    fn rewind_command(&mut self) -> Result<(), Error> {
+        self.send_command(&ModelLseek(Lseek::new(self.top().0, 0, Whence::Set)))
+    }
+
+    /// Get the rewind result from target kernel.
+    fn rewind_result(&mut self) -> Result<(), Error> {
+        if self.receive_retv() == 0 {
+            Ok(())
+        } else {
+            Err(Error::Io)
+        }
+    }
+
+    /// Check the inode number of "." or ".." entry `dent` of the stack top directory.
+    ///
+    /// The ".." of root, or of the root of a mounted file system, is the directory
+    /// itself.
+    fn check_dot(&mut self, dent: &DirEntry) {
+        let dir = self.top_path();
+        let own = self.fs.get(&dir).unwrap();
+        let found = dent.ino as usize;
+        if dent.name() == "." {
+            if found != own.ino {
+                self.findings.push(Finding::BadDot {
+                    dir,
+                    expected: own.ino,
+                    found,
+                });
+            }
+            return;
+        }
+        if dir.is_root() && !self.scope.root.is_root() {
+            // The parent of the test root is not walked.
+            return;
+        }
+        let expected = match dir.parent().and_then(|parent| self.fs.get(&parent)) {
+            Some(parent) if parent.dev == own.dev => parent.ino,
+            _ => own.ino,
+        };
+        if found != expected {
+            self.findings.push(Finding::BadDotDot {
+                dir,
+                expected,
+                found,
+            });
+        }
+    }
+
+    /// Check the `d_type` and `d_ino` of the entry being inspected against its
+    /// status. Mount points are listed with the inode they cover.
+    ///
+    /// FAT has no inode numbers, the `d_ino` it lists need not match `st_ino`, so
+    /// it is not checked.
+    fn check_entry(&mut self, stat: &FileStat) {
+        let dir = self.top_path();
+        let path = dir.join(&RelPath::new(self.entry.clone())).unwrap();
+        if let Some(listed) = self.entry_kind {
+            if listed != stat.kind {
+                self.findings.push(Finding::TypeMismatch {
+                    path: path.clone(),
+                    listed,
+                    stat: stat.kind,
+                });
+            }
+        }
+        if !cfg!(feature = "fat")
+            && self.fs.get(&dir).unwrap().dev == stat.dev
+            && self.entry_ino != stat.ino
+        {
+            self.findings.push(Finding::InoMismatch {
+                path,
+                listed: self.entry_ino,
+                stat: stat.ino,
+            });
+        }
+    }
+
+    /// Count the entry being inspected as a link to its inode.
+    ///
+    /// An inode number found again with another kind is reused by the target.
+    fn count_link(&mut self, stat: &FileStat) {
+        let dir = self.top_path();
+        let path = dir.join(&RelPath::new(self.entry.clone())).unwrap();
+        if stat.kind == FileKind::Directory {
+            *self.subdirs.entry(dir).or_default() += 1;
+        }
+        let links = self.links.entry((stat.dev, stat.ino)).or_insert(Links {
+            kind: stat.kind,
+            nlink: stat.nlink,
+            paths: Vec::new(),
+        });
+        if links.kind != stat.kind {
+            self.findings.push(Finding::InoReused {
+                path,
+                first: links.paths[0].clone(),
+                ino: stat.ino,
+                kind: stat.kind,
+                first_kind: links.kind,
+            });
+        } else {
+            links.paths.push(path);
+        }
+    }
+
+    /// Check the `nlink` of the stack top inode if it is a directory, see `DirNlink`.
+    ///
+    /// Unless the file system counts subdirectories, the `nlink` is then set to 2
+    /// plus the subdirectories found, so that the model compares the tree instead.
+    fn check_dir_nlink(&mut self) {
+        if self.unwinding {
+            return;
+        }
+        let dir = self.top_path();
+        let Some(inode) = self.fs.get(&dir) else {
+            return;
+        };
+        // Only directories fully walked at this path have their subdirectories counted.
+        if !inode.is_dir()
+            || self.seen_inodes.get(&(inode.dev, inode.ino)) != Some(&dir)
+            || self.partial_dirs.contains(&dir)
+            || self
+                .listed
+                .get(&dir)
+                .is_some_and(|&n| n > self.limits.entries)
+        {
+            return;
+        }
+        let nlink = inode.nlink;
+        let found = 2 + self.subdirs.get(&dir).copied().unwrap_or(0);
+        let expected = match self.dir_nlink {
+            DirNlink::Subdirs => found,
+            DirNlink::One => 1,
+            DirNlink::Unchecked => nlink,
+        };
+        if nlink != expected {
+            self.findings.push(Finding::NlinkMismatch {
+                path: dir.clone(),
+                nlink,
+                found: expected,
+            });
+        }
+        if self.dir_nlink != DirNlink::Subdirs {
+            self.fs.get_mut(&dir).unwrap().nlink = found;
+        }
+    }
+
+    /// Hide the ignored subdirectories of the stack top directory from its `nlink`.
+    fn hide_ignored_subdirs(&mut self) {
+        if self.dir_nlink != DirNlink::Subdirs {
+            return;
+        }
+        let dir = self.top_path();
+        if let Some(&n) = self.ignored_subdirs.get(&dir) {
+            if let Some(inode) = self.fs.get_mut(&dir) {
+                inode.nlink = inode.nlink.saturating_sub(n);
+            }
+        }
+    }
+
+    /// Record the target directory graph as corrupt at `path`.
+    fn corrupt(&mut self, path: AbsPath, corruption: Corruption) {
+        self.findings
+            .push(Finding::CorruptGraph { path, corruption });
+    }
+
+    /// Start closing the open directories if the retrieval exceeds its step budget,
+    /// return `true` if so.
+    fn exceeds_steps(&mut self) -> Result<bool, Error> {
+        if self.steps <= self.limits.steps {
+            return Ok(false);
+        }
+        let dir = self.top_path();
+        self.corrupt(dir, Corruption::Steps(self.limits.steps));
+        self.unwinding = true;
+        self.close_command()?;
+        self.step = Step::Close;
+        Ok(true)
+    }
+
+    /// Check the `nlink` of every non-directory inode found against the number
+    /// of paths found for it, unless the walk stopped early.
+    ///
+    /// Inodes with links in ignored subtrees are not checked, nor inodes with
+    /// fewer paths found than their `nlink` if parts of the graph were not walked.
+    fn check_file_nlinks(&mut self) {
+        if self.unwinding {
+            return;
+        }
+        for (key, links) in &self.links {
+            if links.kind == FileKind::Directory || self.hidden_links.contains(key) {
+                continue;
+            }
+            let found = links.paths.len();
+            if links.nlink != found && !(self.partial && found < links.nlink) {
+                self.findings.push(Finding::NlinkMismatch {
+                    path: links.paths[0].clone(),
+                    nlink: links.nlink,
+                    found,
+                });
+            }
+        }
+    }
+
     /// Get the file status of the stack top inode.
     /// Send `fstat` command to target kernel.
     fn fstat_command(&mut self) -> Result<(), Error> {
         self.send_command(&ModelFstat(Fstat::new(self.top().0)))
     }
 
+    /// Get the file status of entry `name` in the stack top directory.
+    /// Send `fstatat` command to target kernel.
+    fn fstatat_command(&mut self, name: &str) -> Result<(), Error> {
+        self.send_command(&ModelFstatat(Fstatat::new(
+            self.top().0,
+            Path(heapless::String::from_str(name).unwrap()),
+        )))
+    }
+
     /// Get the newly read file status from target kernel.
-    fn fstat_result(&mut self) -> Result<FileStat, Error> {
-        if self.receive_retv() >= 0 {
+    ///
+    /// Used for both `fstat` and `fstatat`. Give the error number on failure.
+    fn fstat_result(&mut self) -> Result<FileStat, isize> {
+        let retv = self.receive_retv();
+        if retv >= 0 {
             let data = self.receive_extra_data(size_of::<FileStat>()).unwrap();
             Ok(unsafe { *(data.as_ptr() as *const FileStat) })
         } else {
-            Err(Error::Io)
+            Err(retv)
+        }
+    }
+
+    /// Record entry `path` as listed but not openable, and go on reading the stack
+    /// top directory. What the entry links to is not walked.
+    fn skip_unopenable(&mut self, path: AbsPath, errno: isize) -> Result<(), Error> {
+        self.partial = true;
+        self.findings.push(Finding::Unopenable { path, errno });
+        self.getdents_command()?;
+        self.step = Step::Getdents;
+        Ok(())
+    }
+
+    /// Note that an entry of unknown kind is listed in the stack top directory, which
+    /// may be a subdirectory or a link.
+    fn skip_unknown_entry(&mut self) {
+        self.partial = true;
+        let dir = self.top_path();
+        self.partial_dirs.insert(dir);
+    }
+
+    /// Check a name listed by the first pass over the stack top directory, return
+    /// `false` if it is skipped.
+    ///
+    /// A name listed twice in the pass is only walked the first time. Names
+    /// containing '/' or NUL cannot be looked up.
+    fn check_name(&mut self, name: &str) -> bool {
+        let dir = self.top_path();
+        let listed = self.listed_names.entry(dir.clone()).or_default();
+        if !listed.insert(name.to_owned()) {
+            self.findings.push(Finding::DuplicateEntry {
+                dir,
+                name: name.to_owned(),
+            });
+            return false;
+        }
+        if name.is_empty() || name.contains(['/', '\0']) {
+            self.skip_unknown_entry();
+            self.findings.push(Finding::InvalidName {
+                dir,
+                name: name.to_owned(),
+            });
+            return false;
         }
+        true
+    }
+
+    /// Note that `name` is listed by the second pass over the stack top directory.
+    /// A name listed twice in the pass is recorded, unless the first pass did too.
+    fn relist_name(&mut self, name: &str) {
+        if self.relisted_names.insert(name.to_owned()) {
+            return;
+        }
+        let finding = Finding::DuplicateEntry {
+            dir: self.top_path(),
+            name: name.to_owned(),
+        };
+        if !self.findings.contains(&finding) {
+            self.findings.push(finding);
+        }
+    }
+
+    /// Record the names listed by only one of the passes over the stack top
+    /// directory.
+    fn check_omitted(&mut self) {
+        let dir = self.top_path();
+        let listed = self.listed_names.get(&dir).cloned().unwrap_or_default();
+        let mut omitted: Vec<String> = listed
+            .symmetric_difference(&self.relisted_names)
+            .cloned()
+            .collect();
+        omitted.sort();
+        for name in omitted {
+            self.findings.push(Finding::OmittedEntry {
+                dir: dir.clone(),
+                name,
+            });
+        }
+    }
+
+    /// Record an inode at `path`, or an alias if the inode is already visited.
+    ///
+    /// Return `true` if the inode is newly visited.
+    fn record_inode(&mut self, path: AbsPath, stat: &FileStat) -> bool {
+        if let Some(parent) = path.parent() {
+            if self.fs.get(&parent).unwrap().dev != stat.dev {
+                // Crossing a mount boundary.
+                self.mounts.push((path.clone(), stat.dev));
+            }
+        }
+        if let Some(seen) = self.seen_inodes.get(&(stat.dev, stat.ino)) {
+            // The inode is already been visited i.e. a hard link.
+            // Create an alias in the filesystem.
+            self.fs.insert_alias(seen, path);
+            false
+        } else {
+            self.seen_inodes.insert((stat.dev, stat.ino), path.clone());
+            self.fs.insert(path, Inode::from_stat(stat));
+            true
+        }
+    }
+
+    /// List extended attribute names of the inode being inspected.
+    /// Send `flistxattr` or `listxattrat` command to target kernel.
+    fn listxattr_command(&mut self) -> Result<(), Error> {
+        if self.xattr_at_entry {
+            self.send_command(&ModelListxattrat(Listxattrat::new(
+                self.top().0,
+                Path(heapless::String::from_str(&self.entry).unwrap()),
+                AtFlags::empty(),
+                XATTR_LIST_MAX,
+            )))
+        } else {
+            self.send_command(&ModelFlistxattr(Flistxattr::new(
+                self.top().0,
+                XATTR_LIST_MAX,
+            )))
+        }
+    }
+
+    /// Get the extended attribute names from target kernel.
+    ///
+    /// File systems without extended attribute support have no names. Other errors
+    /// are recorded as findings, and give no names either.
+    fn listxattr_result(&mut self) -> Vec<String> {
+        let retv = self.receive_retv();
+        if retv < 0 && retv != linux_err!(EOPNOTSUPP) {
+            self.findings.push(Finding::UnlistableXattrs {
+                path: self.xattr_path.clone(),
+                errno: retv,
+            });
+        }
+        if retv <= 0 {
+            return Vec::new();
+        }
+        let data = self.receive_extra_data(retv as usize).unwrap();
+        data.split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect()
+    }
+
+    /// Read extended attribute `name` of the inode being inspected.
+    /// Send `fgetxattr` or `getxattrat` command to target kernel.
+    fn getxattr_command(&mut self, name: &str) -> Result<(), Error> {
+        let name = heapless::String::from_str(name).unwrap();
+        if self.xattr_at_entry {
+            self.send_command(&ModelGetxattrat(Getxattrat::new(
+                self.top().0,
+                Path(heapless::String::from_str(&self.entry).unwrap()),
+                AtFlags::empty(),
+                name,
+                XATTR_SIZE_MAX,
+            )))
+        } else {
+            self.send_command(&ModelFgetxattr(Fgetxattr::new(
+                self.top().0,
+                name,
+                XATTR_SIZE_MAX,
+            )))
+        }
+    }
+
+    /// Get the value of extended attribute `name` from target kernel, `None` if
+    /// reading it fails, which is recorded as a finding.
+    fn getxattr_result(&mut self, name: &str) -> Option<Vec<u8>> {
+        let retv = self.receive_retv();
+        if retv >= 0 {
+            Some(self.receive_extra_data(retv as usize).unwrap())
+        } else {
+            self.findings.push(Finding::UnreadableXattr {
+                path: self.xattr_path.clone(),
+                name: name.to_owned(),
+                errno: retv,
+            });
+            None
+        }
+    }
+
+    /// Start reading extended attributes of the newly visited inode at `path`.
+    fn inspect_xattrs(&mut self, path: AbsPath, at_entry: bool) -> Result<(), Error> {
+        self.xattr_path = path;
+        self.xattr_at_entry = at_entry;
+        self.listxattr_command()?;
+        self.step = Step::Listxattr;
+        Ok(())
+    }
+
+    /// Read the next extended attribute, or go on walking if all are read.
+    fn next_xattr(&mut self) -> Result<(), Error> {
+        if let Some(name) = self.xattr_names.last().cloned() {
+            self.getxattr_command(&name)?;
+            self.step = Step::Getxattr;
+            Ok(())
+        } else {
+            self.walk_on(self.xattr_at_entry)
+        }
+    }
+
+    /// Go on walking after an inode is inspected.
+    ///
+    /// A directory entry inspected by `fstatat` is not opened, so continue reading
+    /// the stack top directory. Otherwise read the stack top directory, or close
+    /// the stack top file.
+    fn walk_on(&mut self, at_entry: bool) -> Result<(), Error> {
+        if at_entry || self.fs.get(&self.top_path()).unwrap().is_dir() {
+            self.getdents_command()?;
+            self.step = Step::Getdents;
+        } else {
+            self.close_command()?;
+            self.step = Step::Close;
+        }
+        Ok(())
     }
 
     /// Close the stack top inode.
@@ -170,14 +850,38 @@ impl FsTestPort {
         self.send_command(&ModelGetcwd(Getcwd::new()))
     }
 
-    /// Get current working directory from target kernel.
-    fn getcwd_result(&mut self) -> Result<AbsPath, Error> {
+    /// Get current working directory from target kernel, seen from the test root.
+    /// `None` if the cwd is outside the test root.
+    fn getcwd_result(&mut self) -> Result<Option<AbsPath>, Error> {
         if self.receive_retv() >= 0 {
             let data = self.receive_extra_data(MAX_PATH_LEN).unwrap();
             // 2 + n format
             let len = u16::from_le_bytes(data[0..2].try_into().unwrap());
             let path = unsafe { str::from_utf8_unchecked(&data[2..2 + len as usize]) };
-            Ok(AbsPath::new(path).unwrap())
+            Ok(AbsPath::new(path).unwrap().strip(&self.scope.root))
+        } else {
+            Err(Error::Io)
+        }
+    }
+
+    /// Get free space of the root file system.
+    /// Send `statfs` command to target kernel.
+    fn statfs_command(&mut self) -> Result<(), Error> {
+        let target = "/".to_owned() + &self.scope.root.to_string();
+        self.send_command(&ModelStatfs(Statfs::new(Path(
+            heapless::String::from_str(&target).unwrap(),
+        ))))
+    }
+
+    /// Get free space of the root file system from target kernel.
+    fn statfs_result(&mut self) -> Result<FreeSpace, Error> {
+        if self.receive_retv() >= 0 {
+            let data = self.receive_extra_data(size_of::<FsStat>()).unwrap();
+            let stat = unsafe { *(data.as_ptr() as *const FsStat) };
+            Ok(FreeSpace {
+                inodes: stat.ffree as usize,
+                blocks: stat.bfree as usize,
+            })
         } else {
             Err(Error::Io)
         }
@@ -206,11 +910,24 @@ impl StateChannel<FileSystem> for FsTestPort {
         // Clear collections
         self.stack.clear();
         self.seen_inodes.clear();
+        self.mounts.clear();
+        self.listed_names.clear();
+        self.links.clear();
+        self.subdirs.clear();
+        self.partial = false;
+        self.partial_dirs.clear();
+        self.listed.clear();
+        self.ignored_subdirs.clear();
+        self.hidden_links.clear();
+        self.steps = 0;
+        self.unwinding = false;
+        self.findings.clear();
         self.fs.clear();
         // Open root directory
         // Push to stack, fd is set later
         self.stack.push((0, String::new()));
-        self.openat_command("/")?;
+        let target = "/".to_owned() + &self.scope.root.to_string();
+        self.openat_command(&target)?;
         self.step = Step::Open;
         Ok(())
     }
@@ -219,9 +936,20 @@ impl StateChannel<FileSystem> for FsTestPort {
     ///
     /// This function is the state transition function.
     fn retrieve_state_data(&mut self) -> Result<bool, Error> {
+        self.steps += 1;
         match self.step {
             Step::Open => {
-                let fd = self.openat_result()?;
+                let fd = match self.openat_result() {
+                    Ok(fd) => fd,
+                    // Failing to open root fails the retrieval.
+                    Err(_) if self.stack.len() == 1 => return Err(Error::Io),
+                    Err(errno) => {
+                        let path = self.top_path();
+                        self.stack.pop();
+                        self.skip_unopenable(path, errno)?;
+                        return Ok(false);
+                    }
+                };
                 // `top` is pushed at `Getdents` step.
                 self.top_mut().0 = fd;
                 self.fstat_command()?;
@@ -229,35 +957,111 @@ impl StateChannel<FileSystem> for FsTestPort {
                 Ok(false)
             }
             Step::Fstat => {
-                let stat = self.fstat_result()?;
-                if let Some(path) = self.seen_inodes.get(&stat.ino) {
-                    // The inode is already been visited i.e. a hard link.
-                    // Create an alias in the filesystem.
-                    self.fs.insert_alias(path, self.top_path());
-                } else {
-                    self.fs.insert(self.top_path(), Inode::from_stat(&stat));
-                }
-                match stat.kind {
-                    FileKind::File => {
-                        // The inode is a file, close it.
+                let stat = self.fstat_result().map_err(|_| Error::Io)?;
+                let path = self.top_path();
+                if stat.kind == FileKind::Directory {
+                    if let Some(first) = self.seen_inodes.get(&(stat.dev, stat.ino)) {
+                        // Directories have no hard links, don't walk it again.
+                        let first = first.clone();
+                        self.corrupt(path, Corruption::Cycle { first });
                         self.close_command()?;
                         self.step = Step::Close;
+                        return Ok(false);
                     }
-                    FileKind::Directory => {
-                        // The inode is a directory, get its entries.
+                }
+                if self.record_inode(path.clone(), &stat) {
+                    self.inspect_xattrs(path, false)?;
+                } else {
+                    // A hard link, the inode is already inspected.
+                    self.walk_on(false)?;
+                }
+                Ok(false)
+            }
+            Step::Fstatat => {
+                let name = self.entry.clone();
+                let path = self.top_path().join(&RelPath::new(name.clone())).unwrap();
+                let stat = match self.fstat_result() {
+                    Ok(stat) => stat,
+                    Err(_) if self.scope.ignored.contains(&path) => {
+                        self.skip_unknown_entry();
                         self.getdents_command()?;
                         self.step = Step::Getdents;
+                        return Ok(false);
+                    }
+                    Err(errno) => {
+                        self.skip_unknown_entry();
+                        self.skip_unopenable(path, errno)?;
+                        return Ok(false);
                     }
+                };
+                if self.scope.ignored.contains(&path) {
+                    if stat.kind == FileKind::Directory {
+                        *self.ignored_subdirs.entry(self.top_path()).or_default() += 1;
+                        self.partial = true;
+                    } else {
+                        self.hidden_links.insert((stat.dev, stat.ino));
+                    }
+                    self.getdents_command()?;
+                    self.step = Step::Getdents;
+                    return Ok(false);
+                }
+                self.check_entry(&stat);
+                self.count_link(&stat);
+                if stat.kind == FileKind::Directory && self.stack.len() > self.limits.depth {
+                    self.corrupt(path, Corruption::TooDeep(self.limits.depth));
+                    self.partial = true;
+                    self.getdents_command()?;
+                    self.step = Step::Getdents;
+                    return Ok(false);
                 }
+                match stat.kind {
+                    FileKind::File | FileKind::Directory => {
+                        self.openat_command(&name)?;
+                        // Push to stack, fd will be updated later.
+                        self.stack.push((-1, name));
+                        self.step = Step::Open;
+                    }
+                    _ => {
+                        // Special files are recorded without being opened.
+                        if self.record_inode(path.clone(), &stat) {
+                            self.inspect_xattrs(path, true)?;
+                        } else {
+                            self.walk_on(true)?;
+                        }
+                    }
+                }
+                Ok(false)
+            }
+            Step::Listxattr => {
+                self.xattr_names = self.listxattr_result();
+                self.next_xattr()?;
+                Ok(false)
+            }
+            Step::Getxattr => {
+                let name = self.xattr_names.pop().unwrap();
+                if let Some(value) = self.getxattr_result(&name) {
+                    self.fs
+                        .get_mut(&self.xattr_path)
+                        .unwrap()
+                        .xattrs
+                        .insert(name, value);
+                }
+                self.next_xattr()?;
                 Ok(false)
             }
             Step::Close => {
                 self.close_result()?;
+                self.hide_ignored_subdirs();
+                self.check_dir_nlink();
                 self.stack.pop();
                 if self.stack.is_empty() {
                     // No more directories to visit, get cwd.
                     self.getcwd_command()?;
                     self.step = Step::Getcwd;
+                } else if self.unwinding {
+                    // Stopping early, close the parent directory too.
+                    self.close_command()?;
+                    self.step = Step::Close;
                 } else {
                     // Go back to the parent directory.
                     self.getdents_command()?;
@@ -267,26 +1071,105 @@ impl StateChannel<FileSystem> for FsTestPort {
             }
             Step::Getdents => {
                 let dent = self.getdents_result()?;
+                if self.exceeds_steps()? {
+                    return Ok(false);
+                }
                 if let Some(dent) = dent {
-                    if dent.name() == "." || dent.name() == ".." {
-                        // Ignore "." and "..".
+                    let dir = self.top_path();
+                    let listed = self.listed.entry(dir.clone()).or_default();
+                    *listed += 1;
+                    if *listed > self.limits.entries {
+                        // Stop reading the directory.
+                        self.corrupt(dir, Corruption::TooManyEntries(self.limits.entries));
+                        self.partial = true;
+                        self.close_command()?;
+                        self.step = Step::Close;
+                        return Ok(false);
+                    }
+                    if !self.check_name(dent.name()) {
+                        self.getdents_command()?;
+                        self.step = Step::Getdents;
+                    } else if dent.name() == "." || dent.name() == ".." {
+                        // "." and ".." are checked, but not walked.
+                        self.check_dot(&dent);
                         self.getdents_command()?;
                         self.step = Step::Getdents;
                     } else {
-                        self.openat_command(dent.name())?;
-                        // Push to stack, fd will be updated later.
-                        self.stack.push((-1, dent.name().to_owned()));
-                        self.step = Step::Open;
+                        // Stat the entry before deciding whether to open it.
+                        self.fstatat_command(dent.name())?;
+                        self.entry = dent.name().to_owned();
+                        self.entry_kind = dent.kind();
+                        self.entry_ino = dent.ino as usize;
+                        self.step = Step::Fstatat;
                     }
                 } else {
-                    // No more entries, close the directory.
+                    // No more entries, list the directory again.
+                    self.rewind_command()?;
+                    self.step = Step::Rewind;
+                }
+                Ok(false)
+            }
+            Step::Rewind => {
+                self.rewind_result()?;
+                self.relisted_names.clear();
+                self.getdents_command()?;
+                self.step = Step::Relist;
+                Ok(false)
+            }
+            Step::Relist => {
+                let dent = self.getdents_result()?;
+                if self.exceeds_steps()? {
+                    return Ok(false);
+                }
+                if let Some(dent) = dent {
+                    if self.relisted_names.len() >= self.limits.entries {
+                        // Stop reading the directory, the entries past the limit are
+                        // not inspected.
+                        let dir = self.top_path();
+                        self.corrupt(dir, Corruption::TooManyEntries(self.limits.entries));
+                        self.skip_unknown_entry();
+                        self.close_command()?;
+                        self.step = Step::Close;
+                        return Ok(false);
+                    }
+                    self.relist_name(dent.name());
+                    self.getdents_command()?;
+                    self.step = Step::Relist;
+                } else {
+                    self.check_omitted();
                     self.close_command()?;
                     self.step = Step::Close;
                 }
                 Ok(false)
             }
             Step::Getcwd => {
-                self.cwd = self.getcwd_result()?;
+                match self.getcwd_result()? {
+                    Some(cwd) => {
+                        self.cwd = cwd;
+                        self.statfs_command()?;
+                        self.step = Step::Statfs;
+                    }
+                    None => {
+                        // The target restarted after a `Reboot`, and the new
+                        // process starts outside the test root. The model
+                        // starts over in the test root, so enter it again.
+                        self.enter_test_root_command()?;
+                        self.step = Step::Reenter;
+                    }
+                }
+                Ok(false)
+            }
+            Step::Reenter => {
+                if self.receive_retv() < 0 {
+                    return Err(Error::Io);
+                }
+                self.cwd = AbsPath::root();
+                self.statfs_command()?;
+                self.step = Step::Statfs;
+                Ok(false)
+            }
+            Step::Statfs => {
+                self.space = Some(self.statfs_result()?);
                 Ok(true)
             }
         }
@@ -294,7 +1177,13 @@ impl StateChannel<FileSystem> for FsTestPort {
 
     fn finish_state_retrieval(&mut self) -> Result<FileSystem, Error> {
         self.send_command(&Nop(km_command::Nop {}))?;
-        Ok(FileSystem::new(self.fs.clone(), self.cwd.clone(), 0, 0))
+        let fs = self.retrieved_state();
+        // Findings do not depend on the model, so they fail the retrieval instead of
+        // the comparison, which a relaxed check may skip.
+        if !self.findings.is_empty() {
+            return Err(Error::Io);
+        }
+        Ok(fs)
     }
 }
 
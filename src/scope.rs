@@ -0,0 +1,35 @@
+use crate::path::AbsPath;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Part of the target the model runs in, shared by the model and the test port.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    /// Directory of the target the model runs in, e.g. "/tmp/kmtest". It must
+    /// exist, be empty, and have the mode and owner of the model root.
+    pub root: AbsPath,
+    /// Subtrees excluded from retrieval, seen from `root`, e.g. "/lost+found".
+    pub ignored: BTreeSet<AbsPath>,
+}
+
+impl Scope {
+    /// Create a scope of directory `root` of the target, ignoring nothing.
+    pub fn new(root: AbsPath) -> Self {
+        Self {
+            root,
+            ignored: BTreeSet::new(),
+        }
+    }
+
+    /// Exclude the subtree at `path`, seen from the root, from retrieval.
+    pub fn ignore(&mut self, path: AbsPath) {
+        self.ignored.insert(path);
+    }
+
+    /// Check if `path`, seen from the root, is in an ignored subtree.
+    pub fn is_ignored(&self, path: &AbsPath) -> bool {
+        self.ignored
+            .iter()
+            .any(|ignored| path.strip(ignored).is_some())
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    content::FileData,
+    durability::Durability,
+    fs::{Capacity, FileDescriptor, FreeSpace},
+    inode::Inode,
+    lock::LockTable,
+    mmap::MappingTable,
+    path::AbsPath,
+    pipe::PipeBuffer,
+    scope::Scope,
+    FileSystem,
+};
+use km_command::fs::FileMode;
+use multi_key_map::MultiKeyMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Version of the serialized form, bumped on incompatible changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Serialized form of the full model state.
+///
+/// Open file descriptions are listed once, and file descriptors refer to them by
+/// index, so that descriptors sharing a description still share it when loaded.
+/// Locks refer to their open file description by id, which is kept.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SavedState {
+    pub version: u32,
+    pub uid: u32,
+    pub gid: u32,
+    #[serde(with = "file_mode")]
+    pub umask: FileMode,
+    #[serde(with = "inode_map")]
+    pub inodes: MultiKeyMap<AbsPath, Inode>,
+    /// History of the namespace since the last sync point.
+    pub durability: Durability,
+    pub cwd: AbsPath,
+    /// Open file descriptions.
+    pub descriptions: Vec<FileDescriptor>,
+    /// (fd, index of its open file description).
+    pub fds: Vec<(usize, usize)>,
+    pub tmp_inodes: BTreeMap<usize, Inode>,
+    pub tmp_idx: usize,
+    pub ofd_idx: usize,
+    pub linkable: BTreeSet<usize>,
+    pub pipes: BTreeMap<usize, Inode>,
+    pub pipe_bufs: BTreeMap<usize, PipeBuffer>,
+    pub mounts: BTreeMap<AbsPath, u64>,
+    pub found_mounts: BTreeMap<AbsPath, u64>,
+    pub capacity: Option<Capacity>,
+    pub reported_space: Option<FreeSpace>,
+    pub free_blocks_tolerance: Option<usize>,
+    pub blocks_tolerance: Option<usize>,
+    pub locks: LockTable,
+    pub contents: BTreeMap<usize, FileData>,
+    pub unknown_contents: BTreeSet<usize>,
+    pub mappings: MappingTable,
+    pub recovering: bool,
+    pub scope: Scope,
+}
+
+impl Serialize for FileSystem {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.save().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileSystem {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let saved = SavedState::deserialize(deserializer)?;
+        if saved.version != SNAPSHOT_VERSION {
+            return Err(serde::de::Error::custom(format!(
+                "snapshot version {}, expected {}",
+                saved.version, SNAPSHOT_VERSION
+            )));
+        }
+        FileSystem::load(saved).map_err(serde::de::Error::custom)
+    }
+}
+
+/// `FileMode` as its bits.
+pub(crate) mod file_mode {
+    use km_command::fs::FileMode;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mode: &FileMode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(mode.bits() as u32)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FileMode, D::Error> {
+        Ok(FileMode::from_bits_retain(
+            u32::deserialize(deserializer)? as _
+        ))
+    }
+}
+
+/// `OpenFlags` as its bits.
+pub(crate) mod open_flags {
+    use km_command::fs::OpenFlags;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(flags: &OpenFlags, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(flags.bits() as u32)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OpenFlags, D::Error> {
+        Ok(OpenFlags::from_bits_retain(
+            u32::deserialize(deserializer)? as _
+        ))
+    }
+}
+
+/// `FileKind` by name, e.g. "Directory".
+pub(crate) mod file_kind {
+    use km_command::fs::FileKind;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    /// Kinds the model knows.
+    const KINDS: [FileKind; 6] = [
+        FileKind::File,
+        FileKind::Directory,
+        FileKind::Fifo,
+        FileKind::CharDevice,
+        FileKind::BlockDevice,
+        FileKind::Socket,
+    ];
+
+    pub fn serialize<S: Serializer>(kind: &FileKind, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:?}", kind))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FileKind, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        KINDS
+            .into_iter()
+            .find(|kind| format!("{:?}", kind) == name)
+            .ok_or_else(|| D::Error::custom(format!("unknown file kind {}", name)))
+    }
+}
+
+/// `MultiKeyMap<AbsPath, Inode>` as a list of (paths, inode). Paths are hard
+/// links of the same inode if they have the same device and inode number.
+pub(crate) mod inode_map {
+    use crate::{inode::Inode, path::AbsPath};
+    use multi_key_map::MultiKeyMap;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        map: &MultiKeyMap<AbsPath, Inode>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut groups: BTreeMap<(u64, usize), (Vec<AbsPath>, &Inode)> = BTreeMap::new();
+        for path in map.keys() {
+            let inode = map.get(path).unwrap();
+            groups
+                .entry((inode.dev, inode.ino))
+                .or_insert((Vec::new(), inode))
+                .0
+                .push(path.clone());
+        }
+        let mut list: Vec<_> = groups.into_values().collect();
+        for (paths, _) in &mut list {
+            paths.sort();
+        }
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<MultiKeyMap<AbsPath, Inode>, D::Error> {
+        let list = Vec::<(Vec<AbsPath>, Inode)>::deserialize(deserializer)?;
+        let mut map = MultiKeyMap::new();
+        for (paths, inode) in list {
+            let mut paths = paths.into_iter();
+            let Some(first) = paths.next() else {
+                continue;
+            };
+            map.insert(first.clone(), inode);
+            for path in paths {
+                map.insert_alias(&first, path);
+            }
+        }
+        Ok(map)
+    }
+}
@@ -0,0 +1,172 @@
+use crate::acl::{ACL_ACCESS, ACL_DEFAULT, MAY_READ, MAY_WRITE};
+use crate::error::FsError;
+use crate::inode::Inode;
+use km_command::fs::XattrFlags;
+
+/// Maximum length of an extended attribute name.
+pub const XATTR_NAME_MAX: usize = 255;
+
+/// Maximum size of an extended attribute value.
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+/// Maximum size of an extended attribute name list.
+pub const XATTR_LIST_MAX: usize = 65536;
+
+/// Extended attribute namespace, given by the name prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Namespace {
+    /// "user.", regular files and directories only.
+    User,
+    /// "trusted.", visible to root only.
+    Trusted,
+    /// "security.", managed by security modules.
+    Security,
+    /// "system.", only POSIX ACLs are supported.
+    System,
+}
+
+impl Namespace {
+    /// Get the namespace of attribute `name` by its prefix, `None` if unknown.
+    fn prefix(name: &str) -> Option<Self> {
+        match name.split_once('.')?.0 {
+            "user" => Some(Self::User),
+            "trusted" => Some(Self::Trusted),
+            "security" => Some(Self::Security),
+            "system" => Some(Self::System),
+            _ => None,
+        }
+    }
+
+    /// Get the namespace handling attribute `name`.
+    ///
+    /// Unknown prefixes and "system." attributes other than ACLs give `EOPNOTSUPP`,
+    /// names without suffix give `EINVAL`.
+    fn of(name: &str) -> Result<Self, FsError> {
+        let ns = Self::prefix(name).ok_or(FsError::NotSupported)?;
+        if ns == Self::System && name != ACL_ACCESS && name != ACL_DEFAULT {
+            return Err(FsError::NotSupported);
+        }
+        if name.split_once('.').unwrap().1.is_empty() {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(ns)
+    }
+}
+
+/// Check the length of attribute `name`, empty or too long names give `ERANGE`.
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(FsError::OutOfRange);
+    }
+    Ok(())
+}
+
+impl Inode {
+    /// Set extended attribute `name` to `value`.
+    ///
+    /// The name and size are checked before the permission, as Linux copies them
+    /// from the caller first.
+    pub fn set_xattr(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        name: &str,
+        value: &[u8],
+        flags: XattrFlags,
+    ) -> Result<(), FsError> {
+        if !(flags - (XattrFlags::CREATE | XattrFlags::REPLACE)).is_empty() {
+            return Err(FsError::InvalidArgument);
+        }
+        check_name(name)?;
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(FsError::TooBig);
+        }
+        self.xattr_permission(uid, gid, name, true)?;
+        if Namespace::of(name)? == Namespace::System {
+            return self.set_acl(uid, gid, name, value);
+        }
+        let exists = self.xattrs.contains_key(name);
+        if flags.contains(XattrFlags::CREATE) && exists {
+            return Err(FsError::AlreadyExists);
+        }
+        if flags.contains(XattrFlags::REPLACE) && !exists {
+            return Err(FsError::NoData);
+        }
+        self.xattrs.insert(name.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    /// Get the value size of extended attribute `name`.
+    ///
+    /// If `size` is 0, only the value size is returned. Otherwise the value must
+    /// fit in `size` bytes.
+    pub fn get_xattr(&self, uid: u32, gid: u32, name: &str, size: usize) -> Result<usize, FsError> {
+        check_name(name)?;
+        self.xattr_permission(uid, gid, name, false)?;
+        Namespace::of(name)?;
+        let value = self.xattrs.get(name).ok_or(FsError::NoData)?;
+        if size != 0 && size < value.len() {
+            return Err(FsError::OutOfRange);
+        }
+        Ok(value.len())
+    }
+
+    /// Get the size of the extended attribute name list, each name terminated by NUL.
+    ///
+    /// "trusted." attributes are only listed for root. If `size` is 0, only the list
+    /// size is returned. Otherwise the list must fit in `size` bytes.
+    pub fn list_xattr(&self, uid: u32, size: usize) -> Result<usize, FsError> {
+        let len = self
+            .xattrs
+            .keys()
+            .filter(|name| Namespace::prefix(name) != Some(Namespace::Trusted) || uid == 0)
+            .map(|name| name.len() + 1)
+            .sum();
+        if len > XATTR_LIST_MAX {
+            return Err(FsError::TooBig);
+        }
+        if size != 0 && size < len {
+            return Err(FsError::OutOfRange);
+        }
+        Ok(len)
+    }
+
+    /// Remove extended attribute `name`.
+    pub fn remove_xattr(&mut self, uid: u32, gid: u32, name: &str) -> Result<(), FsError> {
+        check_name(name)?;
+        self.xattr_permission(uid, gid, name, true)?;
+        Namespace::of(name)?;
+        self.xattrs.remove(name).map(|_| ()).ok_or(FsError::NoData)
+    }
+
+    /// Check if attribute `name` can be accessed, in the order of Linux's
+    /// `xattr_permission`.
+    ///
+    /// Denied namespaces give `ENODATA` for reads and `EPERM` for writes. "user."
+    /// attributes and unknown prefixes also need read or write permission on the
+    /// inode, the namespace handler is only looked up afterwards.
+    fn xattr_permission(&self, uid: u32, gid: u32, name: &str, write: bool) -> Result<(), FsError> {
+        let denied = if write {
+            FsError::NotPermitted
+        } else {
+            FsError::NoData
+        };
+        match Namespace::prefix(name) {
+            Some(Namespace::Security | Namespace::System) => return Ok(()),
+            Some(Namespace::Trusted) => {
+                return if uid == 0 { Ok(()) } else { Err(denied) };
+            }
+            Some(Namespace::User) => {
+                if !self.is_file() && !self.is_dir() {
+                    return Err(denied);
+                }
+                // Only the owner may change user attributes in a sticky directory.
+                if write && self.is_dir() && self.is_sticky() && uid != 0 && uid != self.uid {
+                    return Err(FsError::NotPermitted);
+                }
+            }
+            None => (),
+        }
+        self.permission(uid, gid, if write { MAY_WRITE } else { MAY_READ })
+    }
+}